@@ -3,6 +3,26 @@ pub mod consts;
 mod types;
 pub mod waves;
 
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU32,
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+    time::Duration,
+};
+
 use cpal::{
     traits::*,
     Device,
@@ -13,77 +33,266 @@ use midir::{
     MidiInput,
     MidiInputConnection,
     MidiInputPort,
+    MidiOutput,
+    MidiOutputConnection,
+    MidiOutputPort,
 };
 use rack::{
     container::StackResponse,
-    graph::compiled::compile,
-    widgets::scope::SampleQueue,
+    devices::block::MAX_BLOCK,
+    engine::{
+        Engine,
+        MidiScheduler,
+    },
+    graph::{
+        compiled::{
+            compile,
+            ByteCode,
+            CHANNELS,
+        },
+        CtlGraph,
+        DeviceId,
+    },
+    input::InputResampler,
+    midi::MidiEvent,
+    record::RecordTap,
+    spsc::{
+        self,
+        Consumer,
+        Producer,
+    },
+    transport::{
+        ClockSync,
+        TransportCommand,
+        TransportSettings,
+    },
+    widgets::scope::SampleSender,
     STQueue,
 };
-use wmidi::{
-    MidiMessage,
-    Note,
-};
+use wmidi::MidiMessage;
+
+/// Messages the UI can queue up for the audio thread before it has to wait
+const MESSAGE_CAPACITY: usize = 1024;
+const GARBAGE_CAPACITY: usize = 64;
+/// MIDI events that can arrive between two audio blocks
+pub const MIDI_CAPACITY: usize = 1024;
+/// MIDI inputs that can be open at once
+pub const MAX_MIDI_INPUTS: usize = 16;
+/// Ports the audio thread gets MIDI from, the inputs followed by the file
+/// player and the computer keyboard
+const MIDI_PORTS: usize = MAX_MIDI_INPUTS + 2;
+/// How long the MIDI output thread sleeps when it has nothing to send
+const MIDI_OUT_POLL: Duration = Duration::from_millis(1);
+/// Seconds of audio input kept buffered, against the input and output
+/// streams running their callbacks at different times
+const INPUT_LATENCY: f32 = 0.02;
+/// How many times that the input buffer can hold
+const INPUT_BUFFERS: usize = 8;
 
-pub struct NoteQueue {
-    inner: Vec<(u64, Note)>,
+/// Where every open MIDI input sends its messages, so that they get merged
+/// into a single stream for the audio thread
+#[derive(Clone)]
+pub struct MidiSink {
+    events: Arc<Mutex<Producer<MidiEvent>>>,
+    /// Controller changes, for the UI
+    controls: Arc<Mutex<Producer<MidiMessage<'static>>>>,
 }
 
-impl NoteQueue {
-    pub fn new() -> Self {
-        Self { inner: Vec::new() }
+impl MidiSink {
+    pub fn new(events: Producer<MidiEvent>, controls: Producer<MidiMessage<'static>>) -> Self {
+        Self {
+            events: Arc::new(Mutex::new(events)),
+            controls: Arc::new(Mutex::new(controls)),
+        }
     }
 
-    pub fn insert(&mut self, note: Note, time: u64) {
-        if let Some((t, _)) = self.inner.iter_mut().find(|(_, n)| n == &note) {
-            *t = time;
-        } else {
-            self.inner.push((time, note));
+    pub fn send(&self, event: MidiEvent) {
+        if let MidiMessage::ControlChange(..) = event.msg {
+            // The UI only looks at these once a frame, so losing some is fine
+            let _ = self.controls.lock().unwrap().push(event.msg.clone());
+        }
+        if self.events.lock().unwrap().push(event).is_err() {
+            log::warn!("MIDI event dropped, audio thread isn't keeping up");
         }
     }
+}
 
-    pub fn remove(&mut self, note: Note) {
-        if let Some(i) = self
-            .inner
-            .iter_mut()
-            .enumerate()
-            .find_map(|(i, (_, n))| (n == &note).then_some(i))
-        {
-            self.inner.remove(i);
-        }
+pub fn enumerate_outputs() -> Vec<Device> {
+    let host = cpal::default_host();
+    host.output_devices().unwrap().collect()
+}
+
+pub fn enumerate_inputs() -> Vec<Device> {
+    let host = cpal::default_host();
+    host.input_devices()
+        .map(|devices| devices.collect())
+        .unwrap_or_default()
+}
+
+/// Messages from the UI thread to the audio callback
+pub enum AudioMessage {
+    Program(Box<ByteCode>),
+    Param((DeviceId, u8), f32),
+    Midi(Consumer<MidiEvent>),
+    MidiOut(Option<MidiOutQueue>),
+    Input(Option<InputResampler>),
+    Record(Option<RecordTap>),
+    Transport(TransportSettings),
+    TransportCommand(TransportCommand),
+    ClockSync(ClockSync),
+}
+
+/// Things the audio callback is done with, dropped on the UI thread instead
+pub enum Garbage {
+    Program(Box<ByteCode>),
+    Midi(Consumer<MidiEvent>),
+    MidiOut(Option<MidiOutQueue>),
+    Input(Option<InputResampler>),
+    Record(Option<RecordTap>),
+}
+
+/// Where the audio thread's transport is, for showing it
+#[derive(Default)]
+pub struct TransportPosition {
+    /// Bits of the position in beats
+    beats: AtomicU64,
+    playing: AtomicBool,
+    /// Bits of the tempo of the MIDI clock followed, zero when there's none
+    followed_tempo: AtomicU32,
+}
+
+impl TransportPosition {
+    fn store(&self, engine: &Engine) {
+        let transport = engine.transport();
+        self.beats
+            .store(transport.position().to_bits(), Ordering::Relaxed);
+        self.playing
+            .store(transport.is_playing(), Ordering::Relaxed);
+        let tempo = engine.followed_tempo().unwrap_or(0.0);
+        self.followed_tempo
+            .store(tempo.to_bits(), Ordering::Relaxed);
     }
 
-    pub fn first(&self) -> Option<&Note> {
-        self.inner.iter().min_by_key(|(t, _)| t).map(|(_, n)| n)
+    /// Beats since the top
+    pub fn beats(&self) -> f64 {
+        f64::from_bits(self.beats.load(Ordering::Relaxed))
     }
 
-    pub fn len(&self) -> usize {
-        self.inner.len()
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
     }
 
-    #[must_use]
-    pub fn is_empty(&self) -> bool {
-        self.inner.is_empty()
+    /// Tempo the transport follows MIDI clock at, once it's heard any
+    pub fn followed_tempo(&self) -> Option<f32> {
+        let tempo = f32::from_bits(self.followed_tempo.load(Ordering::Relaxed));
+        (tempo > 0.0).then_some(tempo)
     }
 }
 
-impl Default for NoteQueue {
-    fn default() -> Self {
-        Self::new()
-    }
+/// UI thread side of the audio callback.
+///
+/// Compiles every rebuild of the rack, so the audio thread only ever swaps
+/// a pointer, and frees whatever the audio thread hands back.
+pub struct AudioControl {
+    sample_rate: f32,
+    graph: CtlGraph,
+    tx: Producer<AudioMessage>,
+    garbage: Consumer<Garbage>,
+    /// Messages that didn't fit in the channel yet
+    pending: VecDeque<AudioMessage>,
+    transport: Arc<TransportPosition>,
+    clock_sync: ClockSync,
+    /// MIDI events the audio thread had no room to schedule
+    midi_dropped: Arc<AtomicU64>,
 }
 
-pub fn enumerate_outputs() -> Vec<Device> {
-    let host = cpal::default_host();
-    host.output_devices().unwrap().collect()
+impl AudioControl {
+    /// Forwards everything `events` got since the last call
+    pub fn update(&mut self, events: &STQueue<StackResponse>) {
+        while let Some(garbage) = self.garbage.pop() {
+            drop(garbage);
+        }
+
+        let dropped = self.midi_dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("{dropped} MIDI events dropped, too many were waiting to be played");
+        }
+
+        while let Some(msg) = events.get() {
+            match msg {
+                StackResponse::Rebuild(graph) => {
+                    let program = compile(&graph, self.sample_rate);
+                    self.graph = graph;
+                    self.pending
+                        .push_back(AudioMessage::Program(Box::new(program)));
+                }
+                StackResponse::ControlChange(nid, value) => {
+                    if let Some(id) = self.graph.dev_map.get(&nid) {
+                        self.pending.push_back(AudioMessage::Param(*id, value));
+                    }
+                }
+                StackResponse::Transport(settings) => {
+                    self.pending.push_back(AudioMessage::Transport(settings))
+                }
+            }
+        }
+
+        while let Some(msg) = self.pending.pop_front() {
+            if let Err(msg) = self.tx.push(msg) {
+                self.pending.push_front(msg);
+                break;
+            }
+        }
+    }
+
+    /// Switches the audio thread over to another MIDI input
+    pub fn set_midi(&mut self, midi_evs: Consumer<MidiEvent>) {
+        self.pending.push_back(AudioMessage::Midi(midi_evs));
+    }
+
+    /// Switches the audio thread over to another MIDI output, or none
+    pub fn set_midi_out(&mut self, midi_out: Option<MidiOutQueue>) {
+        self.pending.push_back(AudioMessage::MidiOut(midi_out));
+    }
+
+    /// Switches the audio thread over to another audio input, or none
+    pub fn set_input(&mut self, input: Option<InputResampler>) {
+        self.pending.push_back(AudioMessage::Input(input));
+    }
+
+    /// Starts feeding everything the rack plays to a recording, or stops
+    pub fn set_recording(&mut self, tap: Option<RecordTap>) {
+        self.pending.push_back(AudioMessage::Record(tap));
+    }
+
+    /// Plays, stops or rewinds the rack's transport
+    pub fn transport_command(&mut self, command: TransportCommand) {
+        self.pending
+            .push_back(AudioMessage::TransportCommand(command));
+    }
+
+    /// Follows MIDI clock from another input port, or none, and sends it or not
+    pub fn set_clock_sync(&mut self, sync: ClockSync) {
+        self.clock_sync = sync;
+        self.pending.push_back(AudioMessage::ClockSync(sync));
+    }
+
+    pub fn clock_sync(&self) -> ClockSync {
+        self.clock_sync
+    }
+
+    /// Where the transport was after the last audio buffer
+    pub fn transport(&self) -> &TransportPosition {
+        &self.transport
+    }
 }
 
 pub fn build_audio(
     device: Device,
-    ui_evs: STQueue<StackResponse>,
-    mut midi_evs: STQueue<(u64, MidiMessage<'static>)>,
-    samples: SampleQueue,
-) -> (f32, Stream) {
+    mut midi_evs: Consumer<MidiEvent>,
+    mut midi_out: Option<MidiOutQueue>,
+    mut samples: SampleSender,
+) -> (f32, Stream, AudioControl) {
     let supported_config = device
         .default_output_config()
         .expect("no output config available");
@@ -91,74 +300,135 @@ pub fn build_audio(
     let sample_format = supported_config.sample_format();
     let config: cpal::StreamConfig = supported_config.into();
 
+    let (tx, mut rx) = spsc::channel(MESSAGE_CAPACITY);
+    let (mut garbage_tx, garbage) = spsc::channel(GARBAGE_CAPACITY);
+    let control = AudioControl {
+        sample_rate,
+        graph: Default::default(),
+        tx,
+        garbage,
+        pending: VecDeque::new(),
+        transport: Arc::new(TransportPosition::default()),
+        clock_sync: ClockSync::default(),
+        midi_dropped: Arc::new(AtomicU64::new(0)),
+    };
+    let transport = control.transport.clone();
+    let midi_dropped = control.midi_dropped.clone();
+
     let stream = match sample_format {
         SampleFormat::F32 => {
             let channels = config.channels as usize;
 
             let err_fn = |err| eprintln!("an error occurred on stream: {err}");
 
-            let mut pipeline = compile(&Default::default(), sample_rate);
-            let mut graph = Default::default();
+            let mut pipeline = Engine::new(sample_rate);
+            let mut scheduler = MidiScheduler::new(sample_rate, MIDI_PORTS, MIDI_CAPACITY);
 
-            let mut notes = NoteQueue::new();
-            let mut next_value = move || {
-                if let Some(msg) = ui_evs.get() {
+            // If the UI stops collecting garbage, it gets dropped here as a last resort
+            let mut handle_events = move |pipeline: &mut Engine,
+                                          scheduler: &mut MidiScheduler,
+                                          midi_out: &mut Option<MidiOutQueue>,
+                                          input: &mut Option<InputResampler>,
+                                          recording: &mut Option<RecordTap>,
+                                          buffer_len: usize| {
+                while let Some(msg) = rx.pop() {
                     match msg {
-                        StackResponse::Rebuild(r) => {
-                            graph = r;
-                            pipeline = compile(&graph, sample_rate);
-                        }
-                        StackResponse::ControlChange(nid, value) => {
-                            if let Some(id) = graph.dev_map.get(&nid) {
-                                pipeline.update_param(*id, value);
+                        AudioMessage::Program(program) => {
+                            if let Some(old) = pipeline.swap(program) {
+                                let _ = garbage_tx.push(Garbage::Program(old));
                             }
                         }
-                        StackResponse::MidiChange(evs) => {
-                            println!("Midi channel changed");
-                            midi_evs = evs
+                        AudioMessage::Param(pid, value) => pipeline.update_param(pid, value),
+                        AudioMessage::Midi(evs) => {
+                            let old = mem::replace(&mut midi_evs, evs);
+                            let _ = garbage_tx.push(Garbage::Midi(old));
+                            // The new ports stamp events on clocks of their own
+                            scheduler.reset_clocks();
                         }
-                    }
-                }
-                if let Some((t, m)) = midi_evs.get() {
-                    println!("Midi event recv'd");
-                    match m {
-                        MidiMessage::NoteOff(_, n, _) => {
-                            notes.remove(n);
-                            let f = if let Some(n) = notes.first() {
-                                n.to_freq_f32()
-                            } else {
-                                0.0
-                            };
-                            for (_nid, pid @ (_, pi)) in &graph.midis {
-                                let f = if *pi == 1 { 0.0 } else { f };
-                                pipeline.update_param(*pid, f)
-                            }
+                        AudioMessage::MidiOut(out) => {
+                            let old = mem::replace(midi_out, out);
+                            let _ = garbage_tx.push(Garbage::MidiOut(old));
                         }
-                        MidiMessage::NoteOn(_, n, _) => {
-                            notes.insert(n, t);
-                            let f = n.to_freq_f32();
-                            for (_nid, pid @ (_, pi)) in &graph.midis {
-                                let f = if *pi == 1 { 1.0 } else { f };
-                                pipeline.update_param(*pid, f)
-                            }
+                        AudioMessage::Input(new) => {
+                            let old = mem::replace(input, new);
+                            let _ = garbage_tx.push(Garbage::Input(old));
+                        }
+                        AudioMessage::Record(tap) => {
+                            let old = mem::replace(recording, tap);
+                            let _ = garbage_tx.push(Garbage::Record(old));
                         }
-                        _ => (),
+                        AudioMessage::Transport(settings) => pipeline.set_transport(settings),
+                        AudioMessage::TransportCommand(command) => {
+                            pipeline.transport_command(command)
+                        }
+                        AudioMessage::ClockSync(sync) => pipeline.set_clock_sync(sync),
+                    }
+                }
+                if let Some(old) = pipeline.take_retired() {
+                    let _ = garbage_tx.push(Garbage::Program(old));
+                }
+
+                while let Some(ev) = midi_evs.pop() {
+                    if scheduler.schedule(ev, buffer_len).is_none() {
+                        midi_dropped.fetch_add(1, Ordering::Relaxed);
                     }
                 }
-                let sample = pipeline.sample();
-                samples.put(sample);
-                sample
             };
+            let mut block = vec![[0.0; CHANNELS]; MAX_BLOCK];
+            let mut input = None;
+            let mut input_block = vec![[0.0; CHANNELS]; MAX_BLOCK];
+            let mut recording = None;
             device
                 .build_output_stream(
                     &config,
                     move |data: &mut [f32], _| {
-                        for frame in data.chunks_mut(channels) {
-                            let value = next_value();
-                            for sample in frame.iter_mut() {
-                                *sample = value;
+                        // Parameters and programs change at buffer boundaries,
+                        // MIDI events on the sample they're due
+                        handle_events(
+                            &mut pipeline,
+                            &mut scheduler,
+                            &mut midi_out,
+                            &mut input,
+                            &mut recording,
+                            data.len() / channels,
+                        );
+                        for frames in data.chunks_mut(channels * MAX_BLOCK) {
+                            let block = &mut block[..frames.len() / channels];
+                            let input_block = &mut input_block[..block.len()];
+                            match &mut input {
+                                Some(input) => input.read(input_block),
+                                None => input_block.fill([0.0; CHANNELS]),
+                            }
+                            pipeline.set_input(input_block);
+                            scheduler.process_block(&mut pipeline, block);
+                            if let Some(recording) = &mut recording {
+                                recording.write(block);
+                            }
+                            // The output thread sends these as soon as it sees them,
+                            // so where in the block they happened is lost, except
+                            // in recordings
+                            pipeline.take_midi_out(|offset, msg| {
+                                if let Some(recording) = &mut recording {
+                                    recording.write_midi(offset, msg.clone());
+                                }
+                                if let Some(out) = midi_out.as_mut() {
+                                    out.push(msg);
+                                }
+                            });
+                            for (frame, &[l, r]) in frames.chunks_mut(channels).zip(block.iter()) {
+                                match frame {
+                                    [mono] => *mono = (l + r) / 2.0,
+                                    [left, right, rest @ ..] => {
+                                        *left = l;
+                                        *right = r;
+                                        rest.fill(0.0);
+                                    }
+                                    [] => (),
+                                }
+                                samples.put((l + r) / 2.0);
                             }
                         }
+                        transport.store(&pipeline);
                     },
                     err_fn,
                     None,
@@ -167,7 +437,49 @@ pub fn build_audio(
         }
         f => panic!("Unsupported format {f:?}"),
     };
-    (sample_rate, stream)
+    (sample_rate, stream, control)
+}
+
+/// Starts recording from `device`, returning the stream along with what
+/// reads it at `output_rate` for the audio thread
+pub fn build_audio_in(device: &Device, output_rate: f32) -> Option<(Stream, InputResampler)> {
+    let supported_config = device.default_input_config().ok()?;
+    let sample_format = supported_config.sample_format();
+    if sample_format != SampleFormat::F32 {
+        log::warn!("Unsupported input format {sample_format:?}");
+        return None;
+    }
+    let input_rate = supported_config.sample_rate().0 as f32;
+    let config: cpal::StreamConfig = supported_config.into();
+    let channels = config.channels as usize;
+
+    let target = (input_rate * INPUT_LATENCY) as usize;
+    let (mut tx, rx) = spsc::channel(target * INPUT_BUFFERS);
+    let stream = device
+        .build_input_stream(
+            &config,
+            move |data: &[f32], _| {
+                for frame in data.chunks(channels) {
+                    let frame = match *frame {
+                        [mono] => [mono, mono],
+                        [left, right, ..] => [left, right],
+                        [] => continue,
+                    };
+                    // The audio thread stopped reading, what's there is plenty
+                    if tx.push(frame).is_err() {
+                        break;
+                    }
+                }
+            },
+            |err| eprintln!("an error occurred on input stream: {err}"),
+            None,
+        )
+        .ok()?;
+    stream.play().ok()?;
+    Some((
+        stream,
+        InputResampler::new(rx, input_rate, output_rate, target),
+    ))
 }
 
 pub fn enumerate_midi_inputs() -> Vec<(String, MidiInputPort)> {
@@ -182,21 +494,122 @@ pub fn enumerate_midi_inputs() -> Vec<(String, MidiInputPort)> {
     }
 }
 
+/// Connects to `port`, sending everything that comes in to `sink` tagged
+/// as coming from port number `index`
 pub fn build_midi_in(
-    midi_evs: STQueue<(u64, MidiMessage<'static>)>,
-    port: MidiInputPort,
+    sink: MidiSink,
+    index: usize,
+    port: &MidiInputPort,
 ) -> Option<MidiInputConnection<()>> {
     let midi_in = MidiInput::new("PCMG Input").ok()?;
 
     midi_in
         .connect(
-            &port,
-            "pcmg-input-port",
-            move |t, msg, _| {
-                let msg = MidiMessage::try_from(msg).map(|m| m.to_owned()).unwrap();
-                midi_evs.put((t, msg));
+            port,
+            &format!("pcmg-input-port-{index}"),
+            move |time, msg, _| {
+                let Ok(msg) = MidiMessage::try_from(msg) else {
+                    log::warn!("Dropping malformed MIDI message {msg:02x?}");
+                    return;
+                };
+                sink.send(MidiEvent {
+                    port: index,
+                    time,
+                    msg: msg.to_owned(),
+                });
             },
             (),
         )
         .ok()
 }
+
+pub fn enumerate_midi_outputs() -> Vec<(String, MidiOutputPort)> {
+    if let Ok(midi_out) = MidiOutput::new("PCMG Output") {
+        midi_out
+            .ports()
+            .into_iter()
+            .map(|p| (midi_out.port_name(&p).unwrap(), p))
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Thread sending what the rack's `MidiOut` devices play to a MIDI output port.
+///
+/// Stops once dropped.
+pub struct MidiOutSender {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for MidiOutSender {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Where the audio thread pushes messages for a [`MidiOutSender`]
+pub struct MidiOutQueue {
+    tx: Producer<MidiMessage<'static>>,
+    /// Messages that didn't fit, for the sender thread to report
+    dropped: Arc<AtomicU64>,
+}
+
+impl MidiOutQueue {
+    pub fn push(&mut self, msg: MidiMessage<'static>) {
+        if self.tx.push(msg).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Connects to `port`, returning the sender thread along with where the
+/// audio thread should push messages for it
+pub fn build_midi_out(port: &MidiOutputPort) -> Option<(MidiOutSender, MidiOutQueue)> {
+    let midi_out = MidiOutput::new("PCMG Output").ok()?;
+    let conn = midi_out.connect(port, "pcmg-output-port").ok()?;
+
+    let (tx, rx) = spsc::channel(MIDI_CAPACITY);
+    let stop = Arc::new(AtomicBool::new(false));
+    let dropped = Arc::new(AtomicU64::new(0));
+    let thread = thread::Builder::new()
+        .name("midi-out".into())
+        .spawn({
+            let stop = stop.clone();
+            let dropped = dropped.clone();
+            move || send_midi_out(conn, rx, &stop, &dropped)
+        })
+        .ok()?;
+
+    let sender = MidiOutSender {
+        stop,
+        thread: Some(thread),
+    };
+    Some((sender, MidiOutQueue { tx, dropped }))
+}
+
+fn send_midi_out(
+    mut conn: MidiOutputConnection,
+    mut rx: Consumer<MidiMessage<'static>>,
+    stop: &AtomicBool,
+    dropped: &AtomicU64,
+) {
+    let mut bytes = [0; 3];
+    while !stop.load(Ordering::Relaxed) {
+        let n = dropped.swap(0, Ordering::Relaxed);
+        if n > 0 {
+            log::warn!("{n} MIDI output messages dropped, sender isn't keeping up");
+        }
+        while let Some(msg) = rx.pop() {
+            let len = msg.bytes_size();
+            if msg.copy_to_slice(&mut bytes[..len]).is_err() || conn.send(&bytes[..len]).is_err() {
+                log::warn!("Could not send MIDI message {msg:?}");
+            }
+        }
+        thread::sleep(MIDI_OUT_POLL);
+    }
+}
@@ -1,4 +1,14 @@
-use std::time::Duration;
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+    },
+    time::Duration,
+};
 
 use cpal::{
     Device,
@@ -13,9 +23,14 @@ use eframe::{
     Frame,
     egui::{
         CentralPanel,
+        ComboBox,
         Context,
+        DragValue,
         SidePanel,
+        Slider,
         TopBottomPanel,
+        Ui,
+        Window,
     },
 };
 use egui_plot::{
@@ -23,46 +38,414 @@ use egui_plot::{
     Plot,
     PlotPoints,
 };
+use futures::channel::mpsc;
 use midir::{
     MidiInputConnection,
     MidiInputPort,
+    MidiOutputPort,
 };
 use pcmg::{
+    AudioControl,
+    MAX_MIDI_INPUTS,
+    MIDI_CAPACITY,
+    MidiOutQueue,
+    MidiOutSender,
+    MidiSink,
     build_audio,
+    build_audio_in,
     build_midi_in,
+    build_midi_out,
+    enumerate_inputs,
     enumerate_midi_inputs,
+    enumerate_midi_outputs,
     enumerate_outputs,
 };
 use rack::{
     STQueue,
     container::Stack,
     graph::modules::Module,
+    input::InputResampler,
+    midi::{
+        MidiEvent,
+        SmfPlayer,
+    },
     module_description::ModuleDescription,
+    patch::Patch,
+    record::Recorder,
+    spsc,
+    transport::{
+        ClockSync,
+        TransportCommand,
+    },
+    tuning::{
+        KeyboardMap,
+        Scale,
+        Tuning,
+    },
     widgets::scope::SampleQueue,
 };
-use rack_loaders::AssetLoader;
+use rack_loaders::{
+    AssetLoader,
+    saveloaders::{
+        self,
+        RawFile,
+        load_from_base64,
+        save_to_base64,
+        saver,
+    },
+};
+use wmidi::{
+    MidiMessage,
+    Note,
+};
 
-use self::module_adder::ModuleAdder;
+use self::{
+    keyboard::Keyboard,
+    module_adder::ModuleAdder,
+};
 
+mod keyboard;
 mod module_adder;
 
-struct Started {
-    _midi_conn: Option<MidiInputConnection<()>>,
+const MAX_VOICES: usize = 32;
+
+/// Open MIDI input ports
+struct MidiInputs {
+    /// Open while these are kept around
+    conns: Vec<MidiInputConnection<()>>,
+    /// Names of the ports, in the order they're numbered in
+    names: Vec<String>,
+    /// Controller changes, for knobs and toggles mapped to them
+    controls: spsc::Consumer<MidiMessage<'static>>,
+    /// Where the ports send their messages, the MIDI file player and the keyboard too
+    sink: MidiSink,
+}
+
+impl MidiInputs {
+    /// Port the MIDI file player comes in through, right after the real ones
+    fn file_port(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Port the computer keyboard and on-screen piano come in through
+    fn keyboard_port(&self) -> usize {
+        self.names.len() + 1
+    }
+
+    /// Sends what the keyboard plays, stamped as `now`
+    fn keyboard_sender(&self, now: u64) -> impl FnMut(MidiMessage<'static>) + '_ {
+        let port = self.keyboard_port();
+        move |msg| {
+            self.sink.send(MidiEvent {
+                port,
+                time: now,
+                msg,
+            })
+        }
+    }
+}
+
+/// Open MIDI output port
+struct MidiOutput {
+    name: String,
+    _sender: MidiOutSender,
+}
+
+/// MIDI file played into the rack as if it came in through one more port
+struct FilePlayback {
+    name: String,
+    player: SmfPlayer,
+    /// When playback last moved on, in microseconds of UI time
+    last: u64,
+}
+
+impl FilePlayback {
+    /// Sends what the player hands out, stamped as `elapsed` past `last`
+    fn sender(midi: &MidiInputs, last: u64) -> impl FnMut(u64, MidiMessage<'static>) + '_ {
+        let port = midi.file_port();
+        move |elapsed, msg| {
+            midi.sink.send(MidiEvent {
+                port,
+                time: last + elapsed,
+                msg,
+            })
+        }
+    }
+}
+
+/// Running audio input
+struct AudioInput {
+    name: String,
+    _stream: Stream,
+    /// Times the audio thread ran out of input
+    underruns: Arc<AtomicU64>,
+}
+
+/// Running audio output
+struct AudioOutput {
+    name: String,
     samples: SampleQueue,
     sample_rate: f32,
-
     _stream: Stream,
+    control: AudioControl,
+}
+
+struct Started {
+    midi: MidiInputs,
+    midi_out: Option<MidiOutput>,
+    audio: AudioOutput,
+    audio_in: Option<AudioInput>,
+    recording: Option<Recorder>,
+    /// Devices to switch to, while the settings window is open
+    settings: Option<PreStart>,
+    file: Option<FilePlayback>,
+    file_loading: Option<mpsc::Receiver<Option<RawFile>>>,
+    keyboard: Keyboard,
+    tuning_loading: Option<mpsc::Receiver<Option<RawFile>>>,
+    /// Reference frequency being edited, applied once the edit is done
+    /// since every change recompiles the rack
+    reference_freq: Option<f32>,
 
     stack: Stack,
     adder: Option<ModuleAdder>,
     load_string: String,
+    patch_string: String,
+    patch_loading: Option<mpsc::Receiver<Option<Patch>>>,
 }
 
 struct PreStart {
     midi_ports: Vec<(String, MidiInputPort)>,
-    selected_port: Option<usize>,
+    /// Ports to open, in the order they get numbered in
+    selected_ports: Vec<usize>,
+    midi_outputs: Vec<(String, MidiOutputPort)>,
+    selected_midi_out: Option<usize>,
     audio_outputs: Vec<Device>,
     selected_output: Option<usize>,
+    audio_inputs: Vec<Device>,
+    selected_input: Option<usize>,
+}
+
+impl PreStart {
+    /// Every device currently available, with `midi`, `midi_out`, `output`
+    /// and `input` selected if they still are
+    fn enumerate(
+        midi: &[String],
+        midi_out: Option<&str>,
+        output: Option<&str>,
+        input: Option<&str>,
+    ) -> Self {
+        let midi_ports = enumerate_midi_inputs();
+        let midi_outputs = enumerate_midi_outputs();
+        let audio_outputs = enumerate_outputs();
+        let audio_inputs = enumerate_inputs();
+        Self {
+            selected_ports: midi
+                .iter()
+                .filter_map(|name| midi_ports.iter().position(|(n, _)| n == name))
+                .collect(),
+            selected_midi_out: midi_out
+                .and_then(|name| midi_outputs.iter().position(|(n, _)| n == name)),
+            selected_output: output.and_then(|output| {
+                audio_outputs
+                    .iter()
+                    .position(|o| o.name().is_ok_and(|n| n == output))
+            }),
+            selected_input: input.and_then(|input| {
+                audio_inputs
+                    .iter()
+                    .position(|i| i.name().is_ok_and(|n| n == input))
+            }),
+            midi_ports,
+            midi_outputs,
+            audio_outputs,
+            audio_inputs,
+        }
+    }
+
+    fn show(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("MIDI inputs");
+            let label = match self.selected_ports.len() {
+                0 => "None".to_string(),
+                1 => self.midi_ports[self.selected_ports[0]].0.clone(),
+                n => format!("{n} ports"),
+            };
+            ui.menu_button(label, |ui| {
+                for (i, (name, _)) in self.midi_ports.iter().enumerate() {
+                    let position = self.selected_ports.iter().position(|p| *p == i);
+                    // MidiControl port filters count ports in the order they were picked
+                    let text = match position {
+                        Some(n) => format!("{}: {name}", n + 1),
+                        None => name.clone(),
+                    };
+                    let mut selected = position.is_some();
+                    if ui.checkbox(&mut selected, text).changed() {
+                        match position {
+                            Some(n) => {
+                                self.selected_ports.remove(n);
+                            }
+                            None => self.selected_ports.push(i),
+                        }
+                    }
+                }
+            });
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("MIDI output");
+            let label = self
+                .selected_midi_out
+                .map_or("None", |s| self.midi_outputs[s].0.as_str());
+            ui.menu_button(label, |ui| {
+                if ui.button("None").clicked() {
+                    self.selected_midi_out = None;
+                }
+                for (i, (name, _)) in self.midi_outputs.iter().enumerate() {
+                    if ui.button(name).clicked() {
+                        self.selected_midi_out = Some(i);
+                    }
+                }
+            });
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Audio output");
+            let output_names: Vec<_> = self
+                .audio_outputs
+                .iter()
+                .map(|o| o.name().unwrap())
+                .collect();
+            let label = self
+                .selected_output
+                .map(|s| output_names[s].clone())
+                .unwrap_or_else(|| "None".into());
+            ui.menu_button(label, |ui| {
+                for (i, name) in output_names.iter().enumerate() {
+                    if ui.button(name).clicked() {
+                        self.selected_output = Some(i);
+                    }
+                }
+            });
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Audio input");
+            let input_names: Vec<_> = self
+                .audio_inputs
+                .iter()
+                .map(|i| i.name().unwrap_or_default())
+                .collect();
+            let label = self
+                .selected_input
+                .map_or("None", |s| input_names[s].as_str());
+            ui.menu_button(label, |ui| {
+                if ui.button("None").clicked() {
+                    self.selected_input = None;
+                }
+                for (i, name) in input_names.iter().enumerate() {
+                    if ui.button(name).clicked() {
+                        self.selected_input = Some(i);
+                    }
+                }
+            });
+        });
+    }
+
+    /// Opens the selected MIDI inputs, along with the stream for the audio thread
+    fn open_midi(&self) -> (MidiInputs, spsc::Consumer<MidiEvent>) {
+        let (midi_tx, midi_rx) = spsc::channel(MIDI_CAPACITY);
+        let (controls_tx, controls) = spsc::channel(MIDI_CAPACITY);
+        let sink = MidiSink::new(midi_tx, controls_tx);
+
+        let mut conns = Vec::new();
+        let mut names = Vec::new();
+        if self.selected_ports.len() > MAX_MIDI_INPUTS {
+            log::warn!("Only the first {MAX_MIDI_INPUTS} MIDI inputs get opened");
+        }
+        for (index, p) in self.selected_ports.iter().take(MAX_MIDI_INPUTS).enumerate() {
+            let (name, port) = &self.midi_ports[*p];
+            match build_midi_in(sink.clone(), index, port) {
+                Some(conn) => conns.push(conn),
+                None => log::warn!("Could not open MIDI input {name}"),
+            }
+            names.push(name.clone());
+        }
+
+        let inputs = MidiInputs {
+            conns,
+            names,
+            controls,
+            sink,
+        };
+        (inputs, midi_rx)
+    }
+
+    /// Opens the selected MIDI output, along with where the audio thread sends to it
+    fn open_midi_out(&self) -> (Option<MidiOutput>, Option<MidiOutQueue>) {
+        let Some((name, port)) = self.selected_midi_out.map(|o| &self.midi_outputs[o]) else {
+            return (None, None);
+        };
+        match build_midi_out(port) {
+            Some((sender, tx)) => {
+                let output = MidiOutput {
+                    name: name.clone(),
+                    _sender: sender,
+                };
+                (Some(output), Some(tx))
+            }
+            None => {
+                log::warn!("Could not open MIDI output {name}");
+                (None, None)
+            }
+        }
+    }
+
+    /// Starts recording from the selected input, along with what reads it
+    /// at `sample_rate` for the audio thread
+    fn open_input(&self, sample_rate: f32) -> (Option<AudioInput>, Option<InputResampler>) {
+        let Some(device) = self.selected_input.map(|i| &self.audio_inputs[i]) else {
+            return (None, None);
+        };
+        let name = device.name().unwrap_or_default();
+        match build_audio_in(device, sample_rate) {
+            Some((stream, resampler)) => {
+                let input = AudioInput {
+                    name,
+                    _stream: stream,
+                    underruns: resampler.underruns(),
+                };
+                (Some(input), Some(resampler))
+            }
+            None => {
+                log::warn!("Could not open audio input {name}");
+                (None, None)
+            }
+        }
+    }
+
+    /// Starts playing on the selected output
+    fn open_output(
+        &mut self,
+        midi: spsc::Consumer<MidiEvent>,
+        midi_out: Option<MidiOutQueue>,
+    ) -> Option<AudioOutput> {
+        let device = self.audio_outputs.remove(self.selected_output.take()?);
+        let name = device.name().unwrap_or_default();
+
+        let (sample_tx, mut samples) = SampleQueue::new(44100 / 10);
+        let (sample_rate, stream, control) = build_audio(device, midi, midi_out, sample_tx);
+        samples.set_period(sample_rate as _);
+
+        stream.play().unwrap();
+
+        Some(AudioOutput {
+            name,
+            samples,
+            sample_rate,
+            _stream: stream,
+            control,
+        })
+    }
 }
 
 #[expect(clippy::large_enum_variant)]
@@ -75,9 +458,13 @@ impl Default for PcmgUiState {
     fn default() -> Self {
         Self::PreStart(PreStart {
             midi_ports: Vec::new(),
-            selected_port: None,
+            selected_ports: Vec::new(),
+            midi_outputs: Vec::new(),
+            selected_midi_out: None,
             audio_outputs: Vec::new(),
             selected_output: None,
+            audio_inputs: Vec::new(),
+            selected_input: None,
         })
     }
 }
@@ -90,12 +477,7 @@ pub struct PcmgUi {
 impl PcmgUi {
     pub fn new(loader: AssetLoader<ModuleDescription>) -> Self {
         Self {
-            state: PcmgUiState::PreStart(PreStart {
-                audio_outputs: enumerate_outputs(),
-                selected_port: None,
-                midi_ports: enumerate_midi_inputs(),
-                selected_output: None,
-            }),
+            state: PcmgUiState::PreStart(PreStart::enumerate(&[], None, None, None)),
 
             loader,
         }
@@ -106,75 +488,36 @@ fn update_pre_start(ctx: &Context, mut state: PreStart) -> PcmgUiState {
     CentralPanel::default()
         .show(ctx, |ui| {
             ui.vertical_centered(|ui| {
-                ui.horizontal(|ui| {
-                    ui.label("MIDI input");
-                    let label = state
-                        .selected_port
-                        .map(|s| &*state.midi_ports[s].0)
-                        .unwrap_or("None");
-                    ui.menu_button(label, |ui| {
-                        for (i, (name, _)) in state.midi_ports.iter().enumerate() {
-                            if ui.button(name).clicked() {
-                                state.selected_port = Some(i);
-                            }
-                        }
-                    });
-                });
-
-                ui.horizontal(|ui| {
-                    ui.label("Audio output");
-                    let output_names: Vec<_> = state
-                        .audio_outputs
-                        .iter()
-                        .map(|o| o.name().unwrap())
-                        .collect();
-                    let label = state
-                        .selected_output
-                        .map(|s| state.audio_outputs[s].name().unwrap())
-                        .unwrap_or_else(|| "None".into());
-                    ui.menu_button(label, |ui| {
-                        for (i, name) in output_names.iter().enumerate() {
-                            if ui.button(name).clicked() {
-                                state.selected_output = Some(i);
-                            }
-                        }
-                    });
-                });
+                state.show(ui);
 
                 let start = ui
                     .add_enabled_ui(state.selected_output.is_some(), |ui| ui.button("Start"))
                     .inner;
 
                 if start.enabled() && start.clicked() {
-                    let ui_evs = STQueue::new();
-                    let midi_evs = STQueue::new();
-
-                    let midi_conn = state.selected_port.and_then(|p| {
-                        //
-                        let (_, p) = state.midi_ports.remove(p);
-
-                        build_midi_in(midi_evs.clone(), p)
-                    });
-
-                    let samples = SampleQueue::new(44100 / 10);
-                    let (sample_rate, stream) = build_audio(
-                        state.audio_outputs.remove(state.selected_output.unwrap()),
-                        ui_evs.clone(),
-                        midi_evs,
-                        samples.clone(),
-                    );
-                    samples.set_period(sample_rate as _);
-
-                    stream.play().unwrap();
+                    let (midi, midi_rx) = state.open_midi();
+                    let (midi_out, midi_out_tx) = state.open_midi_out();
+                    let mut audio = state.open_output(midi_rx, midi_out_tx).unwrap();
+                    let (audio_in, input) = state.open_input(audio.sample_rate);
+                    audio.control.set_input(input);
 
                     PcmgUiState::Started(Started {
-                        _midi_conn: midi_conn,
-                        samples,
-                        sample_rate,
-                        _stream: stream,
-                        stack: Stack::new(ui_evs),
+                        midi,
+                        midi_out,
+                        audio,
+                        audio_in,
+                        recording: None,
+                        settings: None,
+                        file: None,
+                        file_loading: None,
+                        keyboard: Keyboard::default(),
+                        tuning_loading: None,
+                        reference_freq: None,
+                        stack: Stack::new(STQueue::new()),
                         adder: None,
                         load_string: String::new(),
+                        patch_string: String::new(),
+                        patch_loading: None,
                     })
                 } else {
                     PcmgUiState::PreStart(state)
@@ -185,12 +528,87 @@ fn update_pre_start(ctx: &Context, mut state: PreStart) -> PcmgUiState {
         .inner
 }
 
+enum SettingsAction {
+    Apply,
+    Refresh,
+    Cancel,
+}
+
+/// Switches to the devices picked in the settings window, keeping the rack as it is
+fn apply_settings(state: &mut Started, mut settings: PreStart) {
+    // Ports stay taken while open, so the old connections get out of the way
+    // of the new ones
+    state.midi.conns.clear();
+    state.midi_out = None;
+    let (midi, midi_rx) = settings.open_midi();
+    let (midi_out, midi_out_tx) = settings.open_midi_out();
+    let switch_output = settings.selected_output.is_some_and(|o| {
+        settings.audio_outputs[o]
+            .name()
+            .is_ok_and(|n| n != state.audio.name)
+    });
+    // Ports get numbered anew, so there's no telling which one had the clock
+    let sync = ClockSync {
+        follow: None,
+        ..state.audio.control.clock_sync()
+    };
+    if switch_output {
+        // The recording is at the old stream's sample rate, and nothing feeds it anymore
+        state.recording = None;
+        state.audio = settings.open_output(midi_rx, midi_out_tx).unwrap();
+        // The new stream starts out silent, and at its own sample rate
+        state.stack.rebuild();
+    } else {
+        state.audio.control.set_midi(midi_rx);
+        state.audio.control.set_midi_out(midi_out_tx);
+    }
+    state.audio.control.set_clock_sync(sync);
+    let (audio_in, input) = settings.open_input(state.audio.sample_rate);
+    state.audio.control.set_input(input);
+    state.audio_in = audio_in;
+    state.midi = midi;
+    state.midi_out = midi_out;
+}
+
+/// First of `recording-1.wav`, `recording-2.wav`... in the working directory not taken yet
+fn recording_path() -> PathBuf {
+    (1..)
+        .map(|n| PathBuf::from(format!("recording-{n}.wav")))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Swaps in the scale or keyboard mapping in `bytes`, telling them apart by the extension
+fn load_tuning(stack: &mut Stack, name: &str, bytes: &[u8]) {
+    let text = String::from_utf8_lossy(bytes);
+    let mut tuning = stack.tuning().clone();
+    let loaded = if name.to_lowercase().ends_with(".kbm") {
+        KeyboardMap::parse_kbm(&text).map(|keyboard| tuning.keyboard = keyboard)
+    } else {
+        Scale::parse_scl(&text).map(|scale| {
+            tuning.name = name.to_string();
+            tuning.scale = scale;
+        })
+    };
+    match loaded.and_then(|_| tuning.validate()) {
+        Ok(()) => stack.set_tuning(tuning),
+        Err(e) => log::warn!("Could not load tuning {name}: {e}"),
+    }
+}
+
 fn update_started(
     ctx: &Context,
     mut state: Started,
     loader: &mut AssetLoader<ModuleDescription>,
 ) -> PcmgUiState {
     loader.drive();
+    let now = (ctx.input(|i| i.time) * 1e6) as u64;
+    if let Some(file) = &mut state.file {
+        let send = FilePlayback::sender(&state.midi, file.last);
+        file.player.advance(now - file.last, send);
+        file.last = now;
+    }
+
     TopBottomPanel::top("top-bar").show(ctx, |ui| {
         ui.horizontal(|ui| {
             if ui.button("Add module").clicked() && state.adder.is_none() {
@@ -206,9 +624,357 @@ fn update_started(
             if ui.button("Load from file").clicked() {
                 loader.load();
             }
-        })
+
+            ui.separator();
+            if ui.button("Settings").clicked() && state.settings.is_none() {
+                state.settings = Some(PreStart::enumerate(
+                    &state.midi.names,
+                    state.midi_out.as_ref().map(|o| o.name.as_str()),
+                    Some(&state.audio.name),
+                    state.audio_in.as_ref().map(|i| i.name.as_str()),
+                ));
+            }
+
+            ui.separator();
+            match &state.recording {
+                Some(recorder) => {
+                    if ui.button("Stop recording").clicked() {
+                        state.audio.control.set_recording(None);
+                        state.recording = None;
+                    } else {
+                        let seconds = recorder.seconds() as u64;
+                        ui.label(format!(
+                            "Recording to {} {}:{:02}",
+                            recorder.path().display(),
+                            seconds / 60,
+                            seconds % 60
+                        ));
+                        let dropped = recorder.dropped();
+                        if dropped > 0 {
+                            ui.label(format!("{dropped} frames dropped"));
+                        }
+                        let dropped = recorder.midi_dropped();
+                        if dropped > 0 {
+                            ui.label(format!("{dropped} MIDI messages dropped"));
+                        }
+                        // Keeps the time going up while nothing else moves
+                        ctx.request_repaint_after(Duration::from_millis(250));
+                    }
+                }
+                None => {
+                    if ui.button("Record").clicked() {
+                        let path = recording_path();
+                        match Recorder::start(&path, state.audio.sample_rate) {
+                            Ok((recorder, tap)) => {
+                                state.audio.control.set_recording(Some(tap));
+                                state.recording = Some(recorder);
+                            }
+                            Err(e) => log::warn!("Could not record to {}: {e}", path.display()),
+                        }
+                    }
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Save patch").clicked() {
+                saver(state.stack.to_patch());
+            }
+            if ui.button("Load patch").clicked() && state.patch_loading.is_none() {
+                state.patch_loading = Some(saveloaders::loader());
+            }
+
+            ui.label("Patch share string");
+            ui.text_edit_singleline(&mut state.patch_string);
+            if ui.button("Load").clicked() {
+                match load_from_base64(&state.patch_string) {
+                    Some(patch) => state.stack.load_patch(patch),
+                    None => log::warn!("Failed to decode patch share string"),
+                }
+                state.patch_string.clear();
+            }
+            if ui.button("Copy share string").clicked()
+                && let Some(s) = save_to_base64(state.stack.to_patch())
+            {
+                ctx.output_mut(|o| o.copied_text = s);
+            }
+        });
+        ui.horizontal(|ui| {
+            let mut polyphony = state.stack.polyphony();
+            ui.label("Voices");
+            let mut changed = ui
+                .add(DragValue::new(&mut polyphony.voices).clamp_range(1..=MAX_VOICES))
+                .changed();
+            ui.label("Unison");
+            changed |= ui
+                .add(DragValue::new(&mut polyphony.unison).clamp_range(1..=polyphony.voices))
+                .changed();
+            ui.label("Detune");
+            changed |= ui
+                .add(
+                    DragValue::new(&mut polyphony.detune)
+                        .clamp_range(0.0..=100.0)
+                        .suffix(" ct"),
+                )
+                .changed();
+            if changed {
+                state.stack.set_polyphony(polyphony);
+            }
+
+            ui.separator();
+            let mut tuning = state.stack.tuning().clone();
+            ui.label(format!("Tuning {}", tuning.name));
+            if ui.button("Load .scl/.kbm").clicked() && state.tuning_loading.is_none() {
+                state.tuning_loading = Some(saveloaders::bytes_loader());
+            }
+            let reference = Note::try_from(tuning.keyboard.reference_note).map_or_else(
+                |_| tuning.keyboard.reference_note.to_string(),
+                |n| n.to_string(),
+            );
+            ui.label(format!("{reference} at"));
+            let freq = state
+                .reference_freq
+                .get_or_insert(tuning.keyboard.reference_freq);
+            let reference = DragValue::new(freq)
+                .clamp_range(1.0..=20000.0)
+                .speed(0.1)
+                .suffix(" Hz");
+            let response = ui.add(reference);
+            if response.drag_stopped() || response.lost_focus() {
+                tuning.keyboard.reference_freq = *freq;
+                state.stack.set_tuning(tuning);
+            }
+            if !(response.dragged() || response.has_focus()) {
+                state.reference_freq = None;
+            }
+            if ui.button("Reset").clicked() {
+                state.stack.set_tuning(Tuning::default());
+            }
+
+            if state.stack.learning() {
+                ui.separator();
+                ui.label("Move a MIDI controller to map it");
+                if ui.button("Cancel").clicked() {
+                    state.stack.cancel_learn();
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            let position = state.audio.control.transport();
+            let (playing, beats) = (position.is_playing(), position.beats() as u64);
+            let followed_tempo = position.followed_tempo();
+            let mut transport = state.stack.transport();
+            if ui.button(if playing { "Stop" } else { "Play" }).clicked() {
+                let command = if playing {
+                    TransportCommand::Stop
+                } else {
+                    TransportCommand::Play
+                };
+                state.audio.control.transport_command(command);
+            }
+            if ui.button("Rewind").clicked() {
+                state
+                    .audio
+                    .control
+                    .transport_command(TransportCommand::Reset);
+            }
+            let per_bar = u64::from(transport.beats_per_bar.max(1));
+            ui.label(format!("{}.{}", beats / per_bar + 1, beats % per_bar + 1));
+
+            ui.label("Tempo");
+            let mut sync = state.audio.control.clock_sync();
+            let mut changed = false;
+            if sync.follow.is_some() {
+                match followed_tempo {
+                    Some(tempo) => ui.label(format!("{tempo:.1} BPM")),
+                    None => ui.label("waiting for clock"),
+                };
+            } else {
+                changed |= ui
+                    .add(
+                        DragValue::new(&mut transport.tempo)
+                            .clamp_range(20.0..=300.0)
+                            .speed(0.1)
+                            .suffix(" BPM"),
+                    )
+                    .changed();
+            }
+            ui.label("Beats per bar");
+            changed |= ui
+                .add(DragValue::new(&mut transport.beats_per_bar).clamp_range(1..=16))
+                .changed();
+            ui.label("Swing");
+            let mut swing = transport.swing * 100.0;
+            if ui
+                .add(
+                    DragValue::new(&mut swing)
+                        .clamp_range(50.0..=75.0)
+                        .speed(0.1)
+                        .suffix(" %"),
+                )
+                .changed()
+            {
+                transport.swing = swing / 100.0;
+                changed = true;
+            }
+            if changed {
+                state.stack.set_transport(transport);
+            }
+
+            ui.separator();
+            let follow = sync.follow;
+            let name = |port: Option<usize>| match port {
+                Some(port) => state.midi.names[port].as_str(),
+                None => "Internal",
+            };
+            ComboBox::from_label("Sync")
+                .selected_text(name(sync.follow))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut sync.follow, None, name(None));
+                    for port in 0..state.midi.names.len() {
+                        ui.selectable_value(&mut sync.follow, Some(port), name(Some(port)));
+                    }
+                });
+            let send = ui.checkbox(&mut sync.send, "Send clock").changed();
+            if send || sync.follow != follow {
+                state.audio.control.set_clock_sync(sync);
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Open MIDI file").clicked() && state.file_loading.is_none() {
+                state.file_loading = Some(saveloaders::bytes_loader());
+            }
+            let Some(file) = &mut state.file else {
+                return;
+            };
+            let send = FilePlayback::sender(&state.midi, now);
+            ui.label(format!(
+                "{} on port {}",
+                file.name,
+                state.midi.file_port() + 1
+            ));
+            if file.player.is_playing() {
+                if ui.button("Stop").clicked() {
+                    file.player.stop(send);
+                }
+            } else if ui.button("Play").clicked() {
+                file.player.play();
+            }
+            ui.checkbox(&mut file.player.looping, "Loop");
+
+            let mut seconds = file.player.position() as f64 / 1e6;
+            let length = file.player.length() as f64 / 1e6;
+            let seek = ui.add(Slider::new(&mut seconds, 0.0..=length).suffix(" s"));
+            if seek.changed() {
+                file.player.seek(
+                    (seconds * 1e6) as u64,
+                    FilePlayback::sender(&state.midi, now),
+                );
+            }
+        });
     });
 
+    if let Some(rx) = &mut state.file_loading {
+        match rx.try_recv() {
+            Ok(Some((name, bytes))) => {
+                match SmfPlayer::new(&bytes) {
+                    Ok(player) => {
+                        if let Some(mut old) = state.file.take() {
+                            old.player.stop(FilePlayback::sender(&state.midi, now));
+                        }
+                        state.file = Some(FilePlayback {
+                            name,
+                            player,
+                            last: now,
+                        });
+                    }
+                    Err(e) => log::warn!("Could not load MIDI file {name}: {e}"),
+                }
+                state.file_loading = None;
+            }
+            Ok(None) => state.file_loading = None,
+            Err(_) => {}
+        }
+    }
+
+    if let Some(rx) = &mut state.tuning_loading {
+        match rx.try_recv() {
+            Ok(Some((name, bytes))) => {
+                load_tuning(&mut state.stack, &name, &bytes);
+                state.tuning_loading = None;
+            }
+            Ok(None) => state.tuning_loading = None,
+            Err(_) => {}
+        }
+    }
+
+    if let Some(settings) = &mut state.settings {
+        let mut action = None;
+        Window::new("Settings").show(ctx, |ui| {
+            settings.show(ui);
+            ui.horizontal(|ui| {
+                if ui.button("Apply").clicked() {
+                    action = Some(SettingsAction::Apply);
+                }
+                if ui.button("Refresh").clicked() {
+                    action = Some(SettingsAction::Refresh);
+                }
+                if ui.button("Cancel").clicked() {
+                    action = Some(SettingsAction::Cancel);
+                }
+            });
+        });
+        match action {
+            Some(SettingsAction::Apply) => {
+                let settings = state.settings.take().unwrap();
+                apply_settings(&mut state, settings);
+            }
+            Some(SettingsAction::Refresh) => {
+                // Keep whatever was picked, as far as it's still there
+                let midi: Vec<_> = settings
+                    .selected_ports
+                    .iter()
+                    .map(|p| settings.midi_ports[*p].0.clone())
+                    .collect();
+                let midi_out = settings
+                    .selected_midi_out
+                    .map(|o| settings.midi_outputs[o].0.clone());
+                let output = settings
+                    .selected_output
+                    .and_then(|o| settings.audio_outputs[o].name().ok());
+                let input = settings
+                    .selected_input
+                    .and_then(|i| settings.audio_inputs[i].name().ok());
+                *settings = PreStart::enumerate(
+                    &midi,
+                    midi_out.as_deref(),
+                    output.as_deref(),
+                    input.as_deref(),
+                );
+            }
+            Some(SettingsAction::Cancel) => state.settings = None,
+            None => {}
+        }
+    }
+
+    state
+        .keyboard
+        .handle_keys(ctx, state.midi.keyboard_sender(now));
+
+    while let Some(msg) = state.midi.controls.pop() {
+        state.stack.handle_midi(&msg);
+    }
+
+    if let Some(rx) = &mut state.patch_loading {
+        match rx.try_recv() {
+            Ok(Some(patch)) => {
+                state.stack.load_patch(patch);
+                state.patch_loading = None;
+            }
+            Ok(None) => state.patch_loading = None,
+            Err(_) => {}
+        }
+    }
+
     if let Some(a) = &mut state.adder
         && a.show(ctx)
     {
@@ -223,8 +989,16 @@ fn update_started(
         let added = state.stack.with_module(m).is_none();
         assert!(added);
     }
+    TopBottomPanel::bottom("keyboard").show(ctx, |ui| {
+        ui.label(format!(
+            "Computer keyboard and piano on port {}",
+            state.midi.keyboard_port() + 1
+        ));
+        state.keyboard.show(ui, state.midi.keyboard_sender(now));
+    });
     SidePanel::right("scope").show(ctx, |ui| {
         let sin: PlotPoints = state
+            .audio
             .samples
             .get()
             .iter()
@@ -237,7 +1011,7 @@ fn update_started(
             .include_y(2.0)
             .include_y(-2.0)
             .include_x(0.0)
-            .include_x(state.sample_rate / 10.0)
+            .include_x(state.audio.sample_rate / 10.0)
             // .view_aspect(2.0)
             .show(ui, |plot_ui| plot_ui.line(line));
 
@@ -247,6 +1021,13 @@ fn update_started(
     CentralPanel::default().show(ctx, |ui| {
         state.stack.show(ctx, ui);
     });
+    state.audio.control.update(&state.stack.events);
+    if let Some(input) = &state.audio_in {
+        let underruns = input.underruns.swap(0, Ordering::Relaxed);
+        if underruns > 0 {
+            log::warn!("Audio input ran dry {underruns} times");
+        }
+    }
 
     PcmgUiState::Started(state)
 }
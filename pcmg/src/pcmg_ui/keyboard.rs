@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+
+use eframe::egui::{
+    Color32,
+    Context,
+    Event,
+    Key,
+    Pos2,
+    Rect,
+    Sense,
+    Stroke,
+    Ui,
+    Vec2,
+};
+use wmidi::{
+    Channel,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+/// Computer keys playing notes, by semitone from C. Laid out like a piano,
+/// with the middle row as white keys and the row above as black ones.
+const NOTE_KEYS: [Key; 17] = [
+    Key::A,
+    Key::W,
+    Key::S,
+    Key::E,
+    Key::D,
+    Key::F,
+    Key::T,
+    Key::G,
+    Key::Y,
+    Key::H,
+    Key::U,
+    Key::J,
+    Key::K,
+    Key::O,
+    Key::L,
+    Key::P,
+    Key::Semicolon,
+];
+const OCTAVE_DOWN: Key = Key::Z;
+const OCTAVE_UP: Key = Key::X;
+const VELOCITY_DOWN: Key = Key::C;
+const VELOCITY_UP: Key = Key::V;
+const VELOCITY_STEP: u8 = 20;
+const OCTAVES: std::ops::RangeInclusive<i8> = -1..=8;
+
+/// Octaves the on-screen piano spans
+const PIANO_OCTAVES: u8 = 3;
+const PIANO_HEIGHT: f32 = 60.0;
+/// Semitones from C of the white keys
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// Plays notes from the computer keyboard and an on-screen piano, for
+/// patching without a MIDI controller
+pub struct Keyboard {
+    /// Octave of the lowest key, with C4 being middle C
+    pub octave: i8,
+    pub velocity: u8,
+    /// Computer keys held down, along with the notes they started
+    held: BTreeMap<Key, Note>,
+    /// Piano key held down with the mouse
+    clicked: Option<Note>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self {
+            octave: 4,
+            velocity: 100,
+            held: BTreeMap::new(),
+            clicked: None,
+        }
+    }
+}
+
+impl Keyboard {
+    /// Note of the key `semitones` above the lowest one, if it's in MIDI range
+    fn note(&self, semitones: u8) -> Option<Note> {
+        let note = (i16::from(self.octave) + 1) * 12 + i16::from(semitones);
+        u8::try_from(note).ok().and_then(|n| Note::try_from(n).ok())
+    }
+
+    /// Plays what was typed this frame, unless a text field has focus
+    pub fn handle_keys(&mut self, ctx: &Context, mut send: impl FnMut(MidiMessage<'static>)) {
+        let events = ctx.input(|i| i.events.clone());
+        let typing = ctx.wants_keyboard_input();
+        for event in events {
+            match event {
+                // Keys released while unfocused never show up
+                Event::WindowFocused(false) => self.release_keys(&mut send),
+                Event::Key {
+                    key,
+                    physical_key,
+                    pressed,
+                    repeat: false,
+                    modifiers,
+                } => {
+                    // Follow where keys are, not what they're labelled
+                    let key = physical_key.unwrap_or(key);
+                    if !pressed {
+                        if let Some(note) = self.held.remove(&key) {
+                            send(MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN));
+                        }
+                    } else if !typing && !modifiers.any() {
+                        self.press(key, &mut send);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn press(&mut self, key: Key, send: &mut impl FnMut(MidiMessage<'static>)) {
+        match key {
+            OCTAVE_DOWN => self.octave = (self.octave - 1).max(*OCTAVES.start()),
+            OCTAVE_UP => self.octave = (self.octave + 1).min(*OCTAVES.end()),
+            VELOCITY_DOWN => {
+                self.velocity = (self.velocity / VELOCITY_STEP * VELOCITY_STEP)
+                    .saturating_sub(VELOCITY_STEP)
+                    .max(1)
+            }
+            VELOCITY_UP => {
+                self.velocity = (self.velocity / VELOCITY_STEP * VELOCITY_STEP + VELOCITY_STEP)
+                    .min(U7::MAX.into())
+            }
+            _ => {
+                let Some(semitones) = NOTE_KEYS.iter().position(|k| *k == key) else {
+                    return;
+                };
+                let Some(note) = self.note(semitones as u8) else {
+                    return;
+                };
+                // The same note can't be held twice, so restart it instead
+                if let Some(old) = self.held.insert(key, note) {
+                    send(MidiMessage::NoteOff(Channel::Ch1, old, U7::MIN));
+                }
+                send(MidiMessage::NoteOn(
+                    Channel::Ch1,
+                    note,
+                    U7::from_u8_lossy(self.velocity),
+                ));
+            }
+        }
+    }
+
+    fn release_keys(&mut self, send: &mut impl FnMut(MidiMessage<'static>)) {
+        for (_, note) in std::mem::take(&mut self.held) {
+            send(MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN));
+        }
+    }
+
+    /// Clickable piano, clicking lower on a key plays it louder
+    pub fn show(&mut self, ui: &mut Ui, mut send: impl FnMut(MidiMessage<'static>)) {
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "Octave {} ({}/{})",
+                self.octave,
+                OCTAVE_DOWN.name(),
+                OCTAVE_UP.name()
+            ));
+            ui.label(format!(
+                "Velocity {} ({}/{})",
+                self.velocity,
+                VELOCITY_DOWN.name(),
+                VELOCITY_UP.name()
+            ));
+        });
+
+        let size = Vec2::new(ui.available_width(), PIANO_HEIGHT);
+        let (rect, response) = ui.allocate_exact_size(size, Sense::click_and_drag());
+        let keys = self.piano_keys(rect);
+
+        let pointer = response
+            .is_pointer_button_down_on()
+            .then(|| response.interact_pointer_pos())
+            .flatten();
+        // Black keys sit on top, so they get the first pick
+        let under = pointer.and_then(|pos| {
+            keys.iter()
+                .rev()
+                .find(|(_, _, key)| key.contains(pos))
+                .map(|(note, _, key)| (*note, (pos.y - key.top()) / key.height()))
+        });
+        if under.map(|(note, _)| note) != self.clicked {
+            if let Some(old) = self.clicked.take() {
+                send(MidiMessage::NoteOff(Channel::Ch1, old, U7::MIN));
+            }
+            if let Some((note, depth)) = under {
+                let velocity = 1.0 + depth.clamp(0.0, 1.0) * 126.0;
+                send(MidiMessage::NoteOn(
+                    Channel::Ch1,
+                    note,
+                    U7::from_u8_lossy(velocity as u8),
+                ));
+                self.clicked = Some(note);
+            }
+        }
+
+        let visuals = ui.visuals();
+        let painter = ui.painter_at(rect);
+        let stroke = Stroke::new(1.0, visuals.window_stroke.color);
+        for (note, black, key) in &keys {
+            let playing = self.clicked == Some(*note) || self.held.values().any(|n| n == note);
+            let fill = match (playing, black) {
+                (true, _) => visuals.selection.bg_fill,
+                (false, true) => Color32::BLACK,
+                (false, false) => Color32::WHITE,
+            };
+            painter.rect(*key, 0.0, fill, stroke);
+        }
+    }
+
+    /// Notes of the piano, whether they're black keys, and where they are.
+    ///
+    /// White keys come first, so black ones get painted over them.
+    fn piano_keys(&self, rect: Rect) -> Vec<(Note, bool, Rect)> {
+        // One more C closes off the top octave
+        let whites = usize::from(PIANO_OCTAVES) * WHITE_KEYS.len() + 1;
+        let white_width = rect.width() / whites as f32;
+        let black_size = Vec2::new(white_width * 0.6, rect.height() * 0.6);
+
+        let mut white = Vec::with_capacity(whites);
+        let mut black = Vec::new();
+        for i in 0..whites {
+            let octave = (i / WHITE_KEYS.len()) as u8;
+            let semitone = WHITE_KEYS[i % WHITE_KEYS.len()];
+            let left = rect.left() + i as f32 * white_width;
+            let key = Rect::from_min_size(
+                Pos2::new(left, rect.top()),
+                Vec2::new(white_width, rect.height()),
+            );
+            if let Some(note) = self.note(octave * 12 + semitone) {
+                white.push((note, false, key));
+            }
+
+            // Every white key but E and B has a black one to its right
+            let has_black = !matches!(semitone, 4 | 11) && i + 1 < whites;
+            if let Some(note) = has_black
+                .then(|| self.note(octave * 12 + semitone + 1))
+                .flatten()
+            {
+                let min = Pos2::new(left + white_width - black_size.x / 2.0, rect.top());
+                black.push((note, true, Rect::from_min_size(min, black_size)));
+            }
+        }
+        white.extend(black);
+        white
+    }
+}
@@ -72,16 +72,37 @@ pub fn loader<T: DeserializeOwned + 'static>() -> mpsc::Receiver<Option<T>> {
         _ = match file {
             None => tx.try_send(None),
             Some(file) => {
-                // TODO: error handling
-                let file = file.read().await;
-                let asset: T = serde_yaml::from_slice(&file).unwrap();
-                tx.try_send(Some(asset))
+                let name = file.file_name();
+                let asset = serde_yaml::from_slice(&file.read().await)
+                    .inspect_err(|e| log::warn!("Could not load {name}: {e}"))
+                    .ok();
+                tx.try_send(asset)
             }
         };
     });
     rx
 }
 
+/// Name and contents of a file
+pub type RawFile = (String, Vec<u8>);
+
+/// Picks a file and reads it as is
+pub fn bytes_loader() -> mpsc::Receiver<Option<RawFile>> {
+    let (mut tx, rx) = mpsc::channel(1);
+
+    spawn(async move {
+        let file = rfd::AsyncFileDialog::new()
+            .set_directory(".")
+            .pick_file()
+            .await;
+        _ = match file {
+            None => tx.try_send(None),
+            Some(file) => tx.try_send(Some((file.file_name(), file.read().await))),
+        };
+    });
+    rx
+}
+
 pub fn loader_many<T: DeserializeOwned + 'static>() -> mpsc::Receiver<Option<Vec<T>>> {
     let (mut tx, rx) = mpsc::channel(1);
 
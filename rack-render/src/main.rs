@@ -0,0 +1,199 @@
+//! Renders a patch to a WAV file, without an audio device or a window
+
+use std::{
+    fs,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use anyhow::{
+    bail,
+    Result,
+};
+use clap::Parser;
+use hound::{
+    SampleFormat,
+    WavReader,
+    WavSpec,
+    WavWriter,
+};
+use rack::{
+    container::{
+        Stack,
+        StackResponse,
+    },
+    devices::block::MAX_BLOCK,
+    graph::{
+        compiled::{
+            compile,
+            ByteCode,
+            Frame,
+            CHANNELS,
+        },
+        CtlGraph,
+    },
+    midi::{
+        load_smf,
+        write_smf,
+    },
+    patch::Patch,
+    STQueue,
+};
+
+#[derive(Parser)]
+#[command(about = "Renders a rack patch to a WAV file")]
+struct Args {
+    /// Patch saved from the rack
+    patch: PathBuf,
+    /// Where to write the WAV file
+    #[arg(short, long, default_value = "out.wav")]
+    output: PathBuf,
+    /// Standard MIDI file to play through the patch's MIDI controls
+    #[arg(short, long)]
+    midi: Option<PathBuf>,
+    /// Where to write what the patch's MIDI outputs play, as a standard MIDI file
+    #[arg(long)]
+    midi_out: Option<PathBuf>,
+    /// WAV file to play through the patch's audio inputs, at the rendering sample rate
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+    #[arg(short = 'r', long, default_value_t = 48000)]
+    sample_rate: u32,
+    /// How long to render, defaults to the MIDI file plus a second, or 10 seconds without one
+    #[arg(short, long)]
+    seconds: Option<f32>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let sample_rate = args.sample_rate as f32;
+
+    let patch: Patch = serde_yaml::from_str(&fs::read_to_string(&args.patch)?)?;
+    let events = match &args.midi {
+        Some(path) => load_smf(&fs::read(path)?, sample_rate)?,
+        None => Vec::new(),
+    };
+    let input = match &args.input {
+        Some(path) => load_input(path, args.sample_rate)?,
+        None => Vec::new(),
+    };
+    let length = match args.seconds {
+        Some(seconds) => (seconds * sample_rate) as u64,
+        None => events
+            .last()
+            .map_or(10 * args.sample_rate as u64, |(t, _)| {
+                t + args.sample_rate as u64
+            }),
+    };
+
+    let mut program = build(patch, sample_rate);
+    let spec = WavSpec {
+        channels: CHANNELS as u16,
+        sample_rate: args.sample_rate,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+    let mut writer = WavWriter::create(&args.output, spec)?;
+
+    let mut events = events.into_iter().peekable();
+    let mut block = [[0.0; CHANNELS]; MAX_BLOCK];
+    let mut midi_out = Vec::new();
+    let mut time = 0;
+    while time < length {
+        while let Some((_, msg)) = events.next_if(|(t, _)| *t <= time) {
+            // Files don't have ports, so it all comes in through the first one
+            program.handle_midi(0, &msg);
+        }
+
+        // Stop short of the next event, so it lands on the right sample
+        let next = events.peek().map_or(length, |(t, _)| (*t).min(length));
+        let len = (next - time).min(MAX_BLOCK as u64) as usize;
+        let block = &mut block[..len];
+        let start = (time as usize).min(input.len());
+        program.set_input(&input[start..(start + len).min(input.len())]);
+        program.process_block(block);
+        program.take_midi_out(|offset, msg| midi_out.push((time + offset, msg)));
+        for sample in block.iter().flatten() {
+            writer.write_sample(*sample)?;
+        }
+        time += len as u64;
+    }
+    writer.finalize()?;
+
+    if let Some(path) = &args.midi_out {
+        // Each MidiOut hands its messages in order, but not interleaved with the others
+        midi_out.sort_by_key(|(t, _)| *t);
+        fs::write(path, write_smf(&midi_out, sample_rate))?;
+        eprintln!(
+            "Wrote {} MIDI messages to {}",
+            midi_out.len(),
+            path.display()
+        );
+    }
+
+    eprintln!(
+        "Rendered {:.2}s to {}",
+        length as f32 / sample_rate,
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Reads the frames of a WAV file, taking mono ones to both channels
+fn load_input(path: &Path, sample_rate: u32) -> Result<Vec<Frame>> {
+    let mut reader = WavReader::open(path)?;
+    let spec = reader.spec();
+    if spec.sample_rate != sample_rate {
+        bail!(
+            "{} is at {}Hz, render at that sample rate to play it",
+            path.display(),
+            spec.sample_rate
+        );
+    }
+    let samples: Vec<f32> = match spec.sample_format {
+        SampleFormat::Float => reader.samples().collect::<Result<_, _>>()?,
+        SampleFormat::Int => {
+            let full_scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / full_scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let frames = samples
+        .chunks(spec.channels as usize)
+        .map(|frame| match *frame {
+            [mono] => [mono, mono],
+            [left, right, ..] => [left, right],
+            [] => [0.0; CHANNELS],
+        })
+        .collect();
+    Ok(frames)
+}
+
+/// Loads `patch` the same way the rack does, and compiles it with every knob applied
+fn build(patch: Patch, sample_rate: f32) -> ByteCode {
+    let events = STQueue::new();
+    let mut stack = Stack::new(events.clone());
+    stack.load_patch(patch);
+
+    let mut graph = CtlGraph::default();
+    let mut program = compile(&graph, sample_rate);
+    while let Some(msg) = events.get() {
+        match msg {
+            StackResponse::Rebuild(ctl_graph) => {
+                program = compile(&ctl_graph, sample_rate);
+                graph = ctl_graph;
+            }
+            StackResponse::ControlChange(nid, value) => {
+                if let Some(pid) = graph.dev_map.get(&nid) {
+                    program.update_param(*pid, value);
+                }
+            }
+            StackResponse::Transport(settings) => program.set_transport(settings),
+        }
+    }
+    program
+}
@@ -0,0 +1,117 @@
+//! Compares per sample and block processing on a dense patch:
+//! a bank of oscillators, each through an attenuator, mixed down pairwise.
+//!
+//! Run with `cargo bench -p rack --bench block`
+
+use std::{
+    hint::black_box,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use rack::{
+    devices::{
+        block::MAX_BLOCK,
+        description::DeviceKind,
+    },
+    graph::{
+        compiled::{
+            compile,
+            ByteCode,
+            Frame,
+            CHANNELS,
+        },
+        DeviceId,
+        Graph,
+    },
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+const VOICES: usize = 64;
+const SECONDS: usize = 10;
+
+fn device(graph: &mut Graph, name: &str) -> DeviceId {
+    let kind = DeviceKind::all()
+        .into_iter()
+        .find(|k| k.name() == name)
+        .unwrap();
+    graph.insert_device(kind)
+}
+
+fn connect(graph: &mut Graph, (from, out): (DeviceId, u8), (to, inp): (DeviceId, u8)) {
+    let out = graph.output_of(from, out).unwrap();
+    let inp = graph.input_of(to, inp).unwrap();
+    graph.cables.insert(inp, out);
+}
+
+fn dense_patch() -> ByteCode {
+    let mut graph = Graph::new();
+    let freq = device(&mut graph, "Control");
+    let level = device(&mut graph, "Control");
+
+    let mut layer: Vec<(DeviceId, u8)> = (0..VOICES)
+        .map(|_| {
+            let osc = device(&mut graph, "SawOsc");
+            let att = device(&mut graph, "Attenuator");
+            connect(&mut graph, (freq, 1), (osc, 0));
+            connect(&mut graph, (osc, 2), (att, 0));
+            connect(&mut graph, (level, 1), (att, 1));
+            (att, 2)
+        })
+        .collect();
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mixer = device(&mut graph, "A/B Mixer");
+                connect(&mut graph, pair[0], (mixer, 0));
+                connect(&mut graph, pair[1], (mixer, 1));
+                (mixer, 3)
+            })
+            .collect();
+    }
+
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, layer[0], (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((freq, 0), 110.0);
+    code.update_param((level, 0), 0.5);
+    code
+}
+
+fn run(name: &str, mut render: impl FnMut(&mut [Frame])) -> Duration {
+    let mut buffer = vec![[0.0; CHANNELS]; MAX_BLOCK];
+    let blocks = SECONDS * SAMPLE_RATE as usize / MAX_BLOCK;
+
+    let start = Instant::now();
+    for _ in 0..blocks {
+        render(&mut buffer);
+        black_box(&buffer);
+    }
+    let elapsed = start.elapsed();
+
+    let realtime = (SECONDS as f64) / elapsed.as_secs_f64();
+    println!("{name:>10}: {elapsed:>10.2?} for {SECONDS}s of audio ({realtime:.1}x realtime)");
+    elapsed
+}
+
+fn main() {
+    let mut code = dense_patch();
+    let per_sample = run("per sample", |buffer| {
+        for sample in buffer {
+            *sample = code.sample();
+        }
+    });
+
+    let mut code = dense_patch();
+    let block = run("block", |buffer| code.process_block(buffer));
+
+    println!(
+        "{:>10}: {:.2}x",
+        "speedup",
+        per_sample.as_secs_f64() / block.as_secs_f64()
+    );
+}
@@ -23,17 +23,39 @@ use slotmap::SecondaryMap;
 use wmidi::MidiMessage;
 
 use crate::{
-    devices::description::DeviceKind,
     graph::{
-        modules::ModuleResponse,
+        modules::{
+            Module,
+            ModuleResponse,
+        },
         Connector,
         CtlGraph,
+        DeviceId,
         Graph,
         InputId,
         ModuleId,
         OutputId,
+        VisualId,
+    },
+    midi::{
+        CcMapping,
+        Polyphony,
+    },
+    patch::{
+        Patch,
+        PatchCable,
+        PatchModule,
+        PatchPort,
+    },
+    transport::TransportSettings,
+    tuning::Tuning,
+    widgets::{
+        connector::{
+            draw_catenary,
+            CableStyle,
+        },
+        SlotWidget,
     },
-    widgets::connector::draw_catenary,
     STQueue,
 };
 
@@ -50,20 +72,112 @@ enum ConnAttempt {
 
 pub struct Stack {
     pub graph: Graph,
-    end: Option<InputId>,
     pub events: STQueue<StackResponse>,
     attempting_connection: ConnAttempt,
     qt: Quadtree<u8, ModuleId>,
+    /// Cables the last rebuild had to delay to break feedback loops
+    feedback: Vec<InputId>,
+    /// Cables the last rebuild found to carry more than one channel
+    poly: Vec<InputId>,
+    polyphony: Polyphony,
+    tuning: Tuning,
+    transport: TransportSettings,
+    /// Knob or toggle waiting for a MIDI controller to move
+    learning: Option<(ModuleId, VisualId)>,
 }
 
 impl Stack {
     pub fn new(events: STQueue<StackResponse>) -> Self {
         Self {
             graph: Default::default(),
-            end: None,
             events,
             attempting_connection: ConnAttempt::None,
             qt: Quadtree::new(3),
+            feedback: Vec::new(),
+            poly: Vec::new(),
+            polyphony: Default::default(),
+            tuning: Default::default(),
+            transport: Default::default(),
+            learning: None,
+        }
+    }
+
+    pub fn polyphony(&self) -> Polyphony {
+        self.polyphony
+    }
+
+    pub fn set_polyphony(&mut self, polyphony: Polyphony) {
+        self.polyphony = polyphony;
+        self.rebuild();
+    }
+
+    pub fn tuning(&self) -> &Tuning {
+        &self.tuning
+    }
+
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.tuning = tuning;
+        self.rebuild();
+    }
+
+    pub fn transport(&self) -> TransportSettings {
+        self.transport
+    }
+
+    /// Changes tempo, meter or swing without rebuilding, so the rack keeps playing as it is
+    pub fn set_transport(&mut self, transport: TransportSettings) {
+        self.transport = transport;
+        self.events.put(StackResponse::Transport(transport));
+    }
+
+    /// Whether a knob or toggle is waiting to be mapped to a MIDI controller
+    pub fn learning(&self) -> bool {
+        self.learning.is_some()
+    }
+
+    /// Maps the next MIDI controller that moves to visual `vid` of module `mid`
+    pub fn learn(&mut self, mid: ModuleId, vid: VisualId) {
+        self.learning = Some((mid, vid));
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learning = None;
+    }
+
+    /// Moves knobs and toggles mapped to the controller in `msg`, or maps it
+    /// if one is waiting to learn
+    pub fn handle_midi(&mut self, msg: &MidiMessage) {
+        let MidiMessage::ControlChange(channel, cc, value) = *msg else {
+            return;
+        };
+        let (channel, cc, value) = (channel.index(), u8::from(cc), u8::from(value));
+
+        if let Some((mid, vid)) = self.learning.take() {
+            let Some(module) = self.graph.modules.get_mut(mid) else {
+                return;
+            };
+            let (min, max) = module
+                .visuals
+                .get(vid)
+                .and_then(SlotWidget::range)
+                .unwrap_or((0.0, 1.0));
+            module
+                .mappings
+                .insert(vid, CcMapping::new(channel, cc, min, max));
+        }
+
+        for module in self.graph.modules.values_mut() {
+            for (vid, mapping) in &mut module.mappings {
+                let Some(value) = mapping.handle(channel, cc, value) else {
+                    continue;
+                };
+                let widget = &mut module.visuals[vid];
+                widget.set_value(value);
+                if let Some(&conn) = module.values.get(vid) {
+                    self.events
+                        .put(StackResponse::ControlChange(conn, widget.value()));
+                }
+            }
         }
     }
 
@@ -91,20 +205,6 @@ impl Stack {
             });
 
         if let Some(a) = a {
-            let devs = &self.graph.devices;
-
-            for (did, kind) in devs {
-                if matches!(kind, DeviceKind::Output) {
-                    self.end = self
-                        .graph
-                        .dev_ins
-                        .get(did)
-                        .and_then(|ins| ins.first())
-                        .copied();
-                }
-            }
-            // TODO: Handle insertion of multiple outputs
-
             self.qt.insert(a, id);
 
             None
@@ -113,6 +213,160 @@ impl Stack {
         }
     }
 
+    /// Snapshot of the rack that can be loaded back with [`Stack::load_patch`]
+    pub fn to_patch(&self) -> Patch {
+        let placed: Vec<_> = self
+            .qt
+            .iter()
+            .map(|e| (*e.value_ref(), e.area().anchor()))
+            .collect();
+        let indices: SecondaryMap<ModuleId, usize> = placed
+            .iter()
+            .enumerate()
+            .map(|(i, (mid, _))| (*mid, i))
+            .collect();
+
+        let modules = placed
+            .iter()
+            .map(|(mid, Point { x, y })| {
+                let module = &self.graph[*mid];
+                let values = module
+                    .visual_ids
+                    .iter()
+                    .filter_map(|(vi, vid)| match &module.visuals[*vid] {
+                        SlotWidget::Port(_) => None,
+                        w => Some((*vi, w.value())),
+                    })
+                    .collect();
+                let mappings = module
+                    .visual_ids
+                    .iter()
+                    .filter_map(|(vi, vid)| Some((*vi, *module.mappings.get(*vid)?)))
+                    .collect();
+
+                PatchModule {
+                    description: module.description.clone(),
+                    position: (*x, *y),
+                    values,
+                    mappings,
+                }
+            })
+            .collect();
+
+        let port = |(dev, param): (DeviceId, u8)| {
+            let mid = self.graph.module_of(dev)?;
+            Some(PatchPort {
+                module: *indices.get(mid)?,
+                device: self.graph[mid].device_index(dev)?,
+                param,
+            })
+        };
+        let cables = self
+            .graph
+            .cables
+            .iter()
+            .filter_map(|(inp, &out)| {
+                Some(PatchCable {
+                    input: port(self.graph[inp])?,
+                    output: port(self.graph[out])?,
+                })
+            })
+            .collect();
+
+        Patch {
+            modules,
+            cables,
+            polyphony: self.polyphony,
+            tuning: self.tuning.clone(),
+            transport: self.transport,
+        }
+    }
+
+    /// Replaces everything in the rack with the contents of `patch`
+    pub fn load_patch(&mut self, patch: Patch) {
+        self.graph = Graph::new();
+        self.qt = Quadtree::new(3);
+        self.attempting_connection = ConnAttempt::None;
+        self.learning = None;
+        self.polyphony = patch.polyphony;
+        self.tuning = patch.tuning;
+        self.transport = patch.transport;
+
+        let mut ids = Vec::with_capacity(patch.modules.len());
+        for PatchModule {
+            description,
+            position: (x, y),
+            values,
+            mappings,
+        } in patch.modules
+        {
+            let size = description.size;
+            let id = Module::insert_from_description(&mut self.graph, description);
+
+            let module = &mut self.graph[id];
+            for (vi, value) in values {
+                if let Some(w) = module
+                    .visual_ids
+                    .get(&vi)
+                    .and_then(|vid| module.visuals.get_mut(*vid))
+                {
+                    w.set_value(value);
+                }
+            }
+            for (vi, mapping) in mappings {
+                if let Some(vid) = module.visual_ids.get(&vi) {
+                    module.mappings.insert(*vid, mapping);
+                }
+            }
+
+            let mut ab = AreaBuilder::default();
+            ab.anchor(Point { x, y }).dimensions(size.size_in_units());
+            match ab.build() {
+                Ok(a) => {
+                    self.qt.insert(a, id);
+                }
+                Err(e) => log::warn!("Could not place module at {x}, {y}: {e}"),
+            }
+
+            ids.push(id);
+        }
+
+        let port = |graph: &Graph, port: PatchPort| {
+            ids.get(port.module)
+                .and_then(|mid| graph[*mid].device(port.device))
+                .map(|did| (did, port.param))
+        };
+        for PatchCable { input, output } in patch.cables {
+            let inp = port(&self.graph, input).and_then(|(d, p)| self.graph.input_of(d, p));
+            let out = port(&self.graph, output).and_then(|(d, p)| self.graph.output_of(d, p));
+            if let (Some(inp), Some(out)) = (inp, out) {
+                self.graph.cables.insert(inp, out);
+            } else {
+                log::warn!("Dropping cable {input:?} -> {output:?}, no such ports");
+            }
+        }
+
+        self.rebuild();
+    }
+
+    /// Asks for the rack to be recompiled and resends every control value
+    pub fn rebuild(&mut self) {
+        let mut ctl_graph = self.graph.walk();
+        ctl_graph.polyphony = self.polyphony;
+        ctl_graph.tuning = self.tuning.clone();
+        self.feedback = ctl_graph.feedback.keys().collect();
+        self.poly = ctl_graph.poly_inputs();
+        self.events.put(StackResponse::Rebuild(ctl_graph));
+        self.events.put(StackResponse::Transport(self.transport));
+
+        for module in self.graph.modules.values() {
+            for (knob, &conn) in &module.values {
+                let value = module.visuals[knob].value();
+                self.events.put(StackResponse::ControlChange(conn, value));
+            }
+        }
+    }
+
     pub fn show(&mut self, ctx: &Context, ui: &mut Ui) {
         let rect = ui.available_rect_before_wrap();
         let top = rect.left_top();
@@ -175,6 +429,7 @@ impl Stack {
                     (ConnAttempt::Out(_), ModuleResponse::AttemptConnection(Connector::Out(_))) => {
                     }
                     (_, ModuleResponse::Changed(conn, v)) => control_change = Some((conn, v)),
+                    (_, ModuleResponse::Learn(vid)) => self.learning = Some((mid, vid)),
                     (
                         ConnAttempt::In(_) | ConnAttempt::Out(_),
                         ModuleResponse::AttemptDisconnect(_),
@@ -197,18 +452,7 @@ impl Stack {
         self.draw_wires(&rects, ctx, ui);
 
         if trigger_rebuild {
-            if let Some(end) = &self.end {
-                self.events
-                    .put(StackResponse::Rebuild(self.graph.walk_to(*end)));
-
-                for (mid, _) in rects {
-                    let module = &self.graph[mid];
-                    for (knob, &conn) in &module.values {
-                        let value = module.visuals[knob].value();
-                        self.events.put(StackResponse::ControlChange(conn, value));
-                    }
-                }
-            }
+            self.rebuild();
         } else if let Some((c, v)) = control_change {
             self.events.put(StackResponse::ControlChange(c, v));
         }
@@ -220,13 +464,13 @@ impl Stack {
             ConnAttempt::Out(start) => {
                 let start = self.get_output_pos(start, rects);
                 if let Some(end) = ctx.pointer_latest_pos() {
-                    draw_catenary(start, end, ui.painter());
+                    draw_catenary(start, end, CableStyle::default(), ui.painter());
                 }
             }
             ConnAttempt::In(start) => {
                 let start = self.get_input_pos(start, rects);
                 if let Some(end) = ctx.pointer_latest_pos() {
-                    draw_catenary(start, end, ui.painter());
+                    draw_catenary(start, end, CableStyle::default(), ui.painter());
                 }
             }
         }
@@ -236,7 +480,11 @@ impl Stack {
 
             let end = self.get_output_pos(out, rects);
 
-            draw_catenary(start, end, ui.painter());
+            let style = CableStyle {
+                feedback: self.feedback.contains(&inp),
+                poly: self.poly.contains(&inp),
+            };
+            draw_catenary(start, end, style, ui.painter());
         }
     }
 
@@ -267,10 +515,13 @@ impl Stack {
     }
 }
 
+// Rebuilds are rare, and get compiled right away anyway
+#[expect(clippy::large_enum_variant)]
 pub enum StackResponse {
     Rebuild(CtlGraph),
     ControlChange(Connector, f32),
-    MidiChange(STQueue<(u64, MidiMessage<'static>)>),
+    /// Tempo, meter or swing changed
+    Transport(TransportSettings),
 }
 
 impl std::fmt::Debug for StackResponse {
@@ -282,7 +533,7 @@ impl std::fmt::Debug for StackResponse {
                 .field(arg0)
                 .field(arg1)
                 .finish(),
-            Self::MidiChange(_) => f.debug_tuple("MidiChange").finish(),
+            Self::Transport(arg0) => f.debug_tuple("Transport").field(arg0).finish(),
         }
     }
 }
@@ -1,4 +1,10 @@
+use std::any::Any;
+
 use self::{
+    block::{
+        BlockInputs,
+        BlockOutputs,
+    },
     description::{
         DeviceDescription,
         Param,
@@ -7,23 +13,69 @@ use self::{
         adsr::Adsr,
         filters::MoogFilter,
         generators::{
+            saw,
+            triangle,
             Osc,
             SquarePulse,
         },
         mixers::{
             AbMixer,
             Attenuator,
+            Panner,
         },
         sequencer::Sequencer,
     },
 };
 
+pub mod block;
 pub mod description;
 pub mod impls;
 
-pub trait Device {
+pub trait Device: DeviceState {
     fn get_output_indexed(&mut self, idx: u8) -> f32;
     fn set_param_indexed(&mut self, idx: u8, val: f32);
+
+    /// Processes a whole block at once.
+    ///
+    /// Devices that don't override this are driven one sample at a time
+    /// through [`Device::set_param_indexed`] and [`Device::get_output_indexed`].
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        for i in 0..ins.len() {
+            for (param, buf) in ins.iter() {
+                self.set_param_indexed(param, buf[i]);
+            }
+            for (param, buf) in outs.iter_mut() {
+                buf[i] = self.get_output_indexed(param);
+            }
+        }
+    }
+}
+
+/// Type erased access to a device, implemented for every [`Clone`] type
+pub trait DeviceState {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Overwrites this device's state with `other`'s, if they are the same type
+    fn copy_state(&mut self, other: &dyn Any) -> bool;
+}
+
+impl<T: Clone + 'static> DeviceState for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn copy_state(&mut self, other: &dyn Any) -> bool {
+        if let Some(other) = other.downcast_ref::<T>() {
+            self.clone_from(other);
+            true
+        } else {
+            false
+        }
+    }
 }
 
 macro_rules! dd {
@@ -47,12 +99,12 @@ pub(super) static DEVICES: &[DeviceDescription] = &[
         Osc::<f32>::new(sr, |p| p.sin())
     }),
     dd!("SawOsc", [In("Freq"), In("Detune"), Out("Signal")], |sr| {
-        Osc::<f32>::new(sr, |p| p.sin().asin())
+        Osc::<f32>::new(sr, saw)
     }),
     dd!(
         "TriangleOsc",
         [In("Freq"), In("Detune"), Out("Signal")],
-        |sr| Osc::<f32>::new(sr, |p| p.sin())
+        |sr| Osc::<f32>::new(sr, triangle)
     ),
     dd!(
         "SquareOsc",
@@ -74,6 +126,11 @@ pub(super) static DEVICES: &[DeviceDescription] = &[
         [In("A"), In("B"), In("Ratio"), Out("Signal")],
         |_| AbMixer::new()
     ),
+    dd!(
+        "Panner",
+        [In("Input"), In("Pan"), Out("Left"), Out("Right")],
+        |_| Panner::new()
+    ),
     dd!(
         "ADSR",
         [
@@ -100,12 +157,79 @@ pub(super) static DEVICES: &[DeviceDescription] = &[
             In("6"),
             In("7"),
             In("BPM"),
-            Out("Signal")
+            Out("Signal"),
+            In("Clock"),
+            In("Reset")
         ],
         Sequencer::new
     ),
 ];
 
 const CONTROL_PARAMS: &[Param] = &[Param::In("Control"), Param::Out("Output")];
-const MIDI_PARAMS: &[Param] = &[Param::Out("Note"), Param::Out("Trigger")];
+const MIDI_PARAMS: &[Param] = &[
+    Param::Out("Note"),
+    Param::Out("Trigger"),
+    Param::Out("Velocity"),
+    Param::Out("Bend"),
+    Param::Out("Pressure"),
+    Param::Out("Aftertouch"),
+    Param::Out("Mod Wheel"),
+    Param::In("Bend Range"),
+];
+const MIDI_OUT_PARAMS: &[Param] = &[
+    Param::In("Pitch"),
+    Param::In("Gate"),
+    Param::In("Velocity"),
+    Param::In("CC"),
+    Param::In("CC Number"),
+    Param::In("Channel"),
+];
+const CLOCK_PARAMS: &[Param] = &[
+    Param::Out("Clock"),
+    Param::Out("Beat"),
+    Param::Out("Bar"),
+    Param::Out("Reset"),
+    Param::Out("Running"),
+    Param::Out("Phase"),
+];
+const AUDIO_IN_PARAMS: &[Param] = &[Param::Out("Left"), Param::Out("Right")];
 const OUTPUT_PARAMS: &[Param] = &[Param::In("Signal")];
+const STEREO_OUTPUT_PARAMS: &[Param] = &[Param::In("Left"), Param::In("Right")];
+const MERGE_PARAMS: &[Param] = &[
+    Param::In("1"),
+    Param::In("2"),
+    Param::In("3"),
+    Param::In("4"),
+    Param::In("5"),
+    Param::In("6"),
+    Param::In("7"),
+    Param::In("8"),
+    Param::In("9"),
+    Param::In("10"),
+    Param::In("11"),
+    Param::In("12"),
+    Param::In("13"),
+    Param::In("14"),
+    Param::In("15"),
+    Param::In("16"),
+    Param::Out("Poly"),
+];
+const SPLIT_PARAMS: &[Param] = &[
+    Param::In("Poly"),
+    Param::Out("1"),
+    Param::Out("2"),
+    Param::Out("3"),
+    Param::Out("4"),
+    Param::Out("5"),
+    Param::Out("6"),
+    Param::Out("7"),
+    Param::Out("8"),
+    Param::Out("9"),
+    Param::Out("10"),
+    Param::Out("11"),
+    Param::Out("12"),
+    Param::Out("13"),
+    Param::Out("14"),
+    Param::Out("15"),
+    Param::Out("16"),
+];
@@ -0,0 +1,69 @@
+/// Largest number of samples processed in one go
+pub const MAX_BLOCK: usize = 256;
+
+/// Buffers feeding the connected inputs of a device
+pub struct BlockInputs<'a> {
+    pub(crate) buffers: &'a [Vec<f32>],
+    /// Parameter index and the slot feeding it
+    pub(crate) params: &'a [(u8, u16)],
+    pub(crate) len: usize,
+}
+
+impl<'a> BlockInputs<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Samples for input `param`, `None` if nothing is connected to it
+    pub fn get(&self, param: u8) -> Option<&'a [f32]> {
+        let buffers = self.buffers;
+        self.params
+            .iter()
+            .find(|(p, _)| *p == param)
+            .map(|(_, slot)| &buffers[*slot as usize][..self.len])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &'a [f32])> + '_ {
+        self.params
+            .iter()
+            .map(|(p, slot)| (*p, &self.buffers[*slot as usize][..self.len]))
+    }
+}
+
+/// Buffers for the outputs of a device that are connected to something
+pub struct BlockOutputs<'a> {
+    /// One buffer per entry in `params`
+    pub(crate) buffers: &'a mut [Vec<f32>],
+    pub(crate) params: &'a [(u8, u16)],
+    pub(crate) len: usize,
+}
+
+impl BlockOutputs<'_> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Buffer for output `param`, `None` if it isn't connected
+    pub fn get_mut(&mut self, param: u8) -> Option<&mut [f32]> {
+        let i = self.params.iter().position(|(p, _)| *p == param)?;
+        Some(&mut self.buffers[i][..self.len])
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (u8, &mut [f32])> {
+        let len = self.len;
+        self.params
+            .iter()
+            .map(|(p, _)| *p)
+            .zip(self.buffers.iter_mut().map(move |b| &mut b[..len]))
+    }
+}
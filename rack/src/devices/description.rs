@@ -3,19 +3,34 @@ use serde::{
     Serialize,
 };
 
+use crate::graph::compiled::CHANNELS;
+
 use super::{
     impls::{
+        AudioIn,
+        Clock,
         Control,
         MidiControl,
+        MidiOut,
         Output,
+        StereoOutput,
     },
     Device,
+    AUDIO_IN_PARAMS,
+    CLOCK_PARAMS,
     CONTROL_PARAMS,
     DEVICES,
+    MERGE_PARAMS,
+    MIDI_OUT_PARAMS,
     MIDI_PARAMS,
     OUTPUT_PARAMS,
+    SPLIT_PARAMS,
+    STEREO_OUTPUT_PARAMS,
 };
 
+/// Most channels a poly cable can carry
+pub const POLY_CHANNELS: usize = 16;
+
 #[derive(Debug, Clone, Copy)]
 pub struct DeviceDescription {
     pub name: &'static str,
@@ -37,6 +52,17 @@ pub enum DeviceKind {
     #[serde(serialize_with = "crate::ser_device_description")]
     Audio(usize),
     Output,
+    StereoOutput,
+    /// Sends notes and controllers to a MIDI output port
+    MidiOut,
+    /// Pulses following the rack's transport
+    Clock,
+    /// Channels coming in from the audio interface
+    AudioIn,
+    /// Combines mono signals into the channels of a poly cable
+    Merge,
+    /// Takes the channels of a poly cable apart
+    Split,
 }
 
 impl std::fmt::Debug for DeviceKind {
@@ -51,6 +77,12 @@ impl DeviceKind {
             DeviceKind::Control,
             DeviceKind::MidiControl,
             DeviceKind::Output,
+            DeviceKind::StereoOutput,
+            DeviceKind::MidiOut,
+            DeviceKind::Clock,
+            DeviceKind::AudioIn,
+            DeviceKind::Merge,
+            DeviceKind::Split,
         ];
 
         res.extend(
@@ -73,6 +105,12 @@ impl DeviceKind {
             DeviceKind::MidiControl => "MidiControl",
             DeviceKind::Audio(dd) => DEVICES[*dd].name,
             DeviceKind::Output => "Output",
+            DeviceKind::StereoOutput => "StereoOutput",
+            DeviceKind::MidiOut => "MidiOut",
+            DeviceKind::Clock => "Clock",
+            DeviceKind::AudioIn => "AudioIn",
+            DeviceKind::Merge => "Merge",
+            DeviceKind::Split => "Split",
         }
     }
 
@@ -82,6 +120,12 @@ impl DeviceKind {
             DeviceKind::MidiControl => MIDI_PARAMS,
             DeviceKind::Audio(dd) => DEVICES[*dd].params,
             DeviceKind::Output => OUTPUT_PARAMS,
+            DeviceKind::StereoOutput => STEREO_OUTPUT_PARAMS,
+            DeviceKind::MidiOut => MIDI_OUT_PARAMS,
+            DeviceKind::Clock => CLOCK_PARAMS,
+            DeviceKind::AudioIn => AUDIO_IN_PARAMS,
+            DeviceKind::Merge => MERGE_PARAMS,
+            DeviceKind::Split => SPLIT_PARAMS,
         }
     }
 
@@ -94,7 +138,7 @@ impl DeviceKind {
             },
             DeviceKind::MidiControl => |d, _| {
                 let i = d.len();
-                d.push(Box::new(MidiControl(0.0, 0.0)));
+                d.push(Box::new(MidiControl::new()));
                 i
             },
 
@@ -104,6 +148,62 @@ impl DeviceKind {
                 d.push(Box::new(Output(0.0)));
                 i
             },
+            DeviceKind::StereoOutput => |d, _| {
+                let i = d.len();
+                d.push(Box::new(StereoOutput(0.0, 0.0)));
+                i
+            },
+            DeviceKind::MidiOut => |d, _| {
+                let i = d.len();
+                d.push(Box::new(MidiOut::new()));
+                i
+            },
+            DeviceKind::Clock => |d, sample_rate| {
+                let i = d.len();
+                d.push(Box::new(Clock::new(sample_rate)));
+                i
+            },
+            DeviceKind::AudioIn => |d, _| {
+                let i = d.len();
+                d.push(Box::new(AudioIn::new()));
+                i
+            },
+            DeviceKind::Merge | DeviceKind::Split => {
+                |_, _| unreachable!("Merges and splits only reroute cables, they're never run")
+            }
+        }
+    }
+
+    /// Whether input `param` takes every channel of a poly cable.
+    ///
+    /// Other inputs get the sum of all the channels.
+    pub fn accepts_poly(&self, param: u8) -> bool {
+        match self {
+            // Run once per channel
+            DeviceKind::Audio(_) => true,
+            DeviceKind::Split => param == 0,
+            _ => false,
+        }
+    }
+
+    /// Whether this device is where the rack's sound ends up
+    pub fn is_output(&self) -> bool {
+        matches!(self, DeviceKind::Output | DeviceKind::StereoOutput)
+    }
+
+    /// Whether the rack gets walked back from this device, which is the case
+    /// for everything that leads out of it
+    pub fn is_end(&self) -> bool {
+        self.is_output() || matches!(self, DeviceKind::MidiOut)
+    }
+
+    /// Which input feeds each channel of a [`Frame`](crate::graph::compiled::Frame),
+    /// for output devices
+    pub fn channel_params(&self) -> Option<[u8; CHANNELS]> {
+        match self {
+            DeviceKind::Output => Some([0, 0]),
+            DeviceKind::StereoOutput => Some([0, 1]),
+            _ => None,
         }
     }
 }
@@ -8,11 +8,39 @@ use self::{
     mixers::{
         AbMixer,
         Attenuator,
+        Panner,
     },
     sequencer::Sequencer,
 };
 
-use super::Device;
+use wmidi::{
+    Channel,
+    ControlFunction,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+use crate::{
+    graph::compiled::Frame,
+    transport::{
+        Transport,
+        PPQN,
+    },
+    tuning::{
+        NoteFreqs,
+        Tuning,
+    },
+};
+
+use super::{
+    block::{
+        BlockInputs,
+        BlockOutputs,
+        MAX_BLOCK,
+    },
+    Device,
+};
 
 pub mod adsr;
 pub mod filters;
@@ -20,26 +48,360 @@ pub mod generators;
 pub mod mixers;
 pub mod sequencer;
 
-pub struct MidiControl(pub f32, pub f32);
+/// Voice of a MIDI keyboard, set by [`VoiceAllocator`](crate::midi::VoiceAllocator)
+#[derive(Clone)]
+pub struct MidiControl {
+    /// Frequency of the note before pitch bend
+    pub freq: f32,
+    pub trigger: f32,
+    pub velocity: f32,
+    /// From -1 to 1
+    pub bend: f32,
+    pub pressure: f32,
+    pub aftertouch: f32,
+    pub mod_wheel: f32,
+    /// Semitones a full bend moves the note by
+    pub bend_range: f32,
+}
+
+impl MidiControl {
+    pub fn new() -> Self {
+        Self {
+            freq: 0.0,
+            trigger: 0.0,
+            velocity: 0.0,
+            bend: 0.0,
+            pressure: 0.0,
+            aftertouch: 0.0,
+            mod_wheel: 0.0,
+            bend_range: 2.0,
+        }
+    }
+
+    /// Pitch bend in semitones
+    fn semitones(&self) -> f32 {
+        self.bend * self.bend_range
+    }
+}
+
+impl Default for MidiControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Device for MidiControl {
     fn get_output_indexed(&mut self, idx: u8) -> f32 {
         match idx {
-            0 => self.0,
-            1 => self.1,
+            0 => self.freq * (self.semitones() / 12.0).exp2(),
+            1 => self.trigger,
+            2 => self.velocity,
+            3 => self.semitones(),
+            4 => self.pressure,
+            5 => self.aftertouch,
+            6 => self.mod_wheel,
             _ => 0.0,
         }
     }
 
     fn set_param_indexed(&mut self, idx: u8, val: f32) {
         match idx {
-            0 => self.0 = val,
-            1 => self.1 = val,
+            0 => self.freq = val,
+            1 => self.trigger = val,
+            2 => self.velocity = val,
+            3 => self.bend = val,
+            4 => self.pressure = val,
+            5 => self.aftertouch = val,
+            6 => self.mod_wheel = val,
+            7 => self.bend_range = val,
+            _ => (),
+        }
+    }
+}
+
+/// Most messages a [`MidiOut`] holds on to between two [`MidiOut::take_messages`]
+const MIDI_OUT_CAPACITY: usize = 256;
+
+/// Plays a note on an external MIDI device while its gate is high, and
+/// sends a controller whenever its value changes
+#[derive(Clone)]
+pub struct MidiOut {
+    /// In Hz, rounded to the nearest note of the tuning
+    pub pitch: f32,
+    pub gate: f32,
+    /// From 0 to 1
+    pub velocity: f32,
+    /// From 0 to 1, only sent once something is connected
+    pub cc: Option<f32>,
+    pub cc_number: f32,
+    /// Counted from 1
+    pub channel: f32,
+    /// Note sounding, along with the channel it was started on
+    playing: Option<(Channel, Note)>,
+    /// Controller last sent, along with its channel and number
+    sent_cc: Option<(Channel, u8, u8)>,
+    /// Samples since the messages were last taken
+    elapsed: u64,
+    /// Messages along with the sample they were sent on
+    messages: Vec<(u64, MidiMessage<'static>)>,
+    /// Frequency of every note, to pick the one nearest to `pitch`
+    freqs: NoteFreqs,
+    /// Pitch last looked up, along with the note nearest to it
+    tuned: (f32, Option<Note>),
+}
+
+impl MidiOut {
+    pub fn new() -> Self {
+        Self {
+            pitch: 0.0,
+            gate: 0.0,
+            velocity: 1.0,
+            cc: None,
+            cc_number: 1.0,
+            channel: 1.0,
+            playing: None,
+            sent_cc: None,
+            elapsed: 0,
+            messages: Vec::with_capacity(MIDI_OUT_CAPACITY),
+            freqs: Tuning::default().freqs(),
+            tuned: (0.0, None),
+        }
+    }
+
+    /// Picks notes from `freqs`, the frequencies of the rack's tuning
+    pub fn set_freqs(&mut self, freqs: &NoteFreqs) {
+        self.freqs = *freqs;
+        self.tuned = (0.0, None);
+    }
+
+    /// Mapped note nearest to `pitch`, looked up again only once it changes
+    fn note(&mut self) -> Option<Note> {
+        let pitch = self.pitch;
+        if pitch != self.tuned.0 {
+            let distance = |freq: f32| if freq > pitch { freq / pitch } else { pitch / freq };
+            let note = (pitch > 0.0)
+                .then(|| {
+                    self.freqs
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(note, freq)| Some((note, distance((*freq)?))))
+                        .min_by(|a, b| a.1.total_cmp(&b.1))
+                })
+                .flatten()
+                .map(|(note, _)| Note::from_u8_lossy(note as u8));
+            self.tuned = (pitch, note);
+        }
+        self.tuned.1
+    }
+
+    /// Hands out every message sent since the last call, along with how many
+    /// samples after that call it was sent
+    pub fn take_messages(&mut self, mut send: impl FnMut(u64, MidiMessage<'static>)) {
+        for (at, msg) in self.messages.drain(..) {
+            send(at, msg);
+        }
+        self.elapsed = 0;
+    }
+
+    /// Sends whatever the inputs changed, once every sample
+    pub fn tick(&mut self) {
+        let channel = Channel::from_index((self.channel.round() as u8).clamp(1, 16) - 1).unwrap();
+        let note = self.note();
+
+        match (self.gate >= 0.5, note, self.playing) {
+            (true, Some(note), None) => self.note_on(channel, note),
+            // Legato, the new note starts before the old one stops
+            (true, Some(note), Some((c, n))) if (c, n) != (channel, note) => {
+                self.note_on(channel, note);
+                self.send(MidiMessage::NoteOff(c, n, U7::MIN));
+            }
+            (false, _, Some((c, n))) => {
+                self.send(MidiMessage::NoteOff(c, n, U7::MIN));
+                self.playing = None;
+            }
             _ => (),
         }
+
+        if let Some(cc) = self.cc {
+            let number = (self.cc_number.round() as u8).min(119);
+            let value = (cc.clamp(0.0, 1.0) * 127.0).round() as u8;
+            if self.sent_cc != Some((channel, number, value)) {
+                self.sent_cc = Some((channel, number, value));
+                self.send(MidiMessage::ControlChange(
+                    channel,
+                    ControlFunction(U7::from_u8_lossy(number)),
+                    U7::from_u8_lossy(value),
+                ));
+            }
+        }
+        self.elapsed += 1;
+    }
+
+    fn note_on(&mut self, channel: Channel, note: Note) {
+        let velocity = (self.velocity.clamp(0.0, 1.0) * 127.0).round() as u8;
+        // Velocity 0 would be taken for a note off
+        self.send(MidiMessage::NoteOn(
+            channel,
+            note,
+            U7::from_u8_lossy(velocity.max(1)),
+        ));
+        self.playing = Some((channel, note));
+    }
+
+    fn send(&mut self, msg: MidiMessage<'static>) {
+        // Growing would allocate on the audio thread
+        if self.messages.len() < self.messages.capacity() {
+            self.messages.push((self.elapsed, msg));
+        }
+    }
+}
+
+impl Default for MidiOut {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for MidiOut {
+    fn get_output_indexed(&mut self, _idx: u8) -> f32 {
+        0.0
+    }
+
+    fn set_param_indexed(&mut self, idx: u8, val: f32) {
+        match idx {
+            0 => self.pitch = val,
+            1 => self.gate = val,
+            2 => self.velocity = val,
+            3 => self.cc = Some(val),
+            4 => self.cc_number = val,
+            5 => self.channel = val,
+            _ => (),
+        }
+    }
+
+    fn process_block(&mut self, ins: &BlockInputs, _outs: &mut BlockOutputs) {
+        for i in 0..ins.len() {
+            for (param, buf) in ins.iter() {
+                self.set_param_indexed(param, buf[i]);
+            }
+            self.tick();
+        }
+    }
+}
+
+/// Pulses and ramps following the rack's [`Transport`], which the program
+/// running it keeps up to date
+#[derive(Clone)]
+pub struct Clock {
+    pub transport: Transport,
+}
+
+impl Clock {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            transport: Transport::new(sample_rate),
+        }
+    }
+
+    fn output(&self, idx: u8) -> f32 {
+        let running = self.transport.is_playing();
+        let beats = self.transport.swung();
+        let ticks = beats * f64::from(PPQN);
+        // Pulses last half a tick
+        let pulse = |every: u32| running && ticks % f64::from(every) < 0.5;
+        let high = match idx {
+            0 => pulse(1),
+            1 => pulse(PPQN),
+            2 => pulse(PPQN * self.transport.settings().beats_per_bar.max(1)),
+            // Only ever this close to the top after starting over
+            3 => running && ticks < 0.5,
+            4 => running,
+            5 => return beats.fract() as f32,
+            _ => false,
+        };
+        if high {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Device for Clock {
+    fn get_output_indexed(&mut self, idx: u8) -> f32 {
+        self.output(idx)
+    }
+
+    fn set_param_indexed(&mut self, _idx: u8, _val: f32) {}
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        for i in 0..ins.len() {
+            for (param, buf) in outs.iter_mut() {
+                buf[i] = self.output(param);
+            }
+            self.transport.advance(1);
+        }
+    }
+}
+
+/// Frames coming in from the audio interface, which the program running it
+/// hands over a block at a time
+#[derive(Clone)]
+pub struct AudioIn {
+    frames: Vec<Frame>,
+    /// Frame played when running a sample at a time
+    next: usize,
+}
+
+impl AudioIn {
+    pub fn new() -> Self {
+        Self {
+            frames: Vec::with_capacity(MAX_BLOCK),
+            next: 0,
+        }
+    }
+
+    /// Plays `frames` from the first one on, past them there's silence
+    pub fn feed(&mut self, frames: &[Frame]) {
+        self.frames.clear();
+        self.frames
+            .extend_from_slice(&frames[..frames.len().min(MAX_BLOCK)]);
+        self.next = 0;
+    }
+
+    /// Moves on to the next frame, after a sample was run
+    pub fn tick(&mut self) {
+        self.next += 1;
+    }
+
+    fn frame(&self, i: usize) -> Frame {
+        self.frames.get(i).copied().unwrap_or_default()
+    }
+}
+
+impl Default for AudioIn {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for AudioIn {
+    fn get_output_indexed(&mut self, idx: u8) -> f32 {
+        self.frame(self.next)[idx as usize]
+    }
+
+    fn set_param_indexed(&mut self, _idx: u8, _val: f32) {}
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        for (param, buf) in outs.iter_mut() {
+            for (i, sample) in buf.iter_mut().enumerate().take(ins.len()) {
+                *sample = self.frame(i)[param as usize];
+            }
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct Control(pub f32);
 
 impl Device for Control {
@@ -50,8 +412,22 @@ impl Device for Control {
     fn set_param_indexed(&mut self, _idx: u8, val: f32) {
         self.0 = val;
     }
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        let input = ins.get(0);
+        for (_, out) in outs.iter_mut() {
+            match input {
+                Some(input) => out.copy_from_slice(input),
+                None => out.fill(self.0),
+            }
+        }
+        if let Some(&last) = input.and_then(<[f32]>::last) {
+            self.0 = last;
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Output(pub f32);
 
 impl Device for Output {
@@ -62,6 +438,43 @@ impl Device for Output {
     fn set_param_indexed(&mut self, _idx: u8, val: f32) {
         self.0 = val
     }
+
+    fn process_block(&mut self, ins: &BlockInputs, _outs: &mut BlockOutputs) {
+        if let Some(&last) = ins.get(0).and_then(<[f32]>::last) {
+            self.0 = last;
+        }
+    }
+}
+
+/// Output taking the left and right channel separately
+#[derive(Clone)]
+pub struct StereoOutput(pub f32, pub f32);
+
+impl Device for StereoOutput {
+    fn get_output_indexed(&mut self, idx: u8) -> f32 {
+        match idx {
+            0 => self.0,
+            1 => self.1,
+            _ => 0.0,
+        }
+    }
+
+    fn set_param_indexed(&mut self, idx: u8, val: f32) {
+        match idx {
+            0 => self.0 = val,
+            1 => self.1 = val,
+            _ => (),
+        }
+    }
+
+    fn process_block(&mut self, ins: &BlockInputs, _outs: &mut BlockOutputs) {
+        if let Some(&last) = ins.get(0).and_then(<[f32]>::last) {
+            self.0 = last;
+        }
+        if let Some(&last) = ins.get(1).and_then(<[f32]>::last) {
+            self.1 = last;
+        }
+    }
 }
 
 impl Device for SquarePulse<f32> {
@@ -77,6 +490,22 @@ impl Device for SquarePulse<f32> {
             _ => (),
         }
     }
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        let (freq, width) = (ins.get(0), ins.get(1));
+        let Some(out) = outs.get_mut(2) else {
+            return;
+        };
+        for (i, o) in out.iter_mut().enumerate() {
+            if let Some(freq) = freq {
+                self.set_freq(freq[i]);
+            }
+            if let Some(width) = width {
+                self.set_width(width[i]);
+            }
+            *o = self.sample();
+        }
+    }
 }
 
 impl Device for Osc<f32> {
@@ -91,6 +520,22 @@ impl Device for Osc<f32> {
             _ => (),
         }
     }
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        let (freq, detune) = (ins.get(0), ins.get(1));
+        let Some(out) = outs.get_mut(2) else {
+            return;
+        };
+        for (i, o) in out.iter_mut().enumerate() {
+            if let Some(freq) = freq {
+                self.set_freq(freq[i]);
+            }
+            if let Some(detune) = detune {
+                self.set_detune(detune[i]);
+            }
+            *o = self.sample();
+        }
+    }
 }
 
 impl Device for MoogFilter {
@@ -106,6 +551,25 @@ impl Device for MoogFilter {
             _ => (),
         }
     }
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        let (input, cutoff, resonance) = (ins.get(0), ins.get(1), ins.get(2));
+        let Some(out) = outs.get_mut(3) else {
+            return;
+        };
+        for (i, o) in out.iter_mut().enumerate() {
+            if let Some(input) = input {
+                self.set_input(input[i]);
+            }
+            if let Some(cutoff) = cutoff {
+                self.set_cutoff(cutoff[i]);
+            }
+            if let Some(resonance) = resonance {
+                self.set_resonance(resonance[i]);
+            }
+            *o = self.filter();
+        }
+    }
 }
 
 impl Device for Attenuator {
@@ -120,6 +584,22 @@ impl Device for Attenuator {
             _ => (),
         }
     }
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        let (input, factor) = (ins.get(0), ins.get(1));
+        let Some(out) = outs.get_mut(2) else {
+            return;
+        };
+        for (i, o) in out.iter_mut().enumerate() {
+            if let Some(input) = input {
+                self.set_input(input[i]);
+            }
+            if let Some(factor) = factor {
+                self.set_factor(factor[i]);
+            }
+            *o = self.get_output();
+        }
+    }
 }
 
 impl Device for AbMixer {
@@ -135,6 +615,25 @@ impl Device for AbMixer {
             _ => (),
         }
     }
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        let (a, b, ratio) = (ins.get(0), ins.get(1), ins.get(2));
+        let Some(out) = outs.get_mut(3) else {
+            return;
+        };
+        for (i, o) in out.iter_mut().enumerate() {
+            if let Some(a) = a {
+                self.set_a(a[i]);
+            }
+            if let Some(b) = b {
+                self.set_b(b[i]);
+            }
+            if let Some(ratio) = ratio {
+                self.set_ratio(ratio[i]);
+            }
+            *o = self.get_output();
+        }
+    }
 }
 
 impl Device for Sequencer {
@@ -146,6 +645,8 @@ impl Device for Sequencer {
         match idx {
             0..=7 => self.sequence[idx as usize] = val,
             8 => self.set_bpm(val),
+            sequencer::CLOCK => self.set_clock(val),
+            11 => self.reset = val,
             _ => (),
         }
     }
@@ -169,3 +670,41 @@ impl Device for Adsr<f32> {
         }
     }
 }
+
+impl Device for Panner {
+    fn get_output_indexed(&mut self, idx: u8) -> f32 {
+        match idx {
+            2 => self.get_left(),
+            3 => self.get_right(),
+            _ => 0.0,
+        }
+    }
+
+    fn set_param_indexed(&mut self, idx: u8, val: f32) {
+        match idx {
+            0 => self.set_input(val),
+            1 => self.set_pan(val),
+            _ => (),
+        }
+    }
+
+    fn process_block(&mut self, ins: &BlockInputs, outs: &mut BlockOutputs) {
+        let (input, pan) = (ins.get(0), ins.get(1));
+        // Stateless apart from its inputs, so each channel can be done in its own pass
+        for (param, out) in outs.iter_mut() {
+            for (i, o) in out.iter_mut().enumerate() {
+                if let Some(input) = input {
+                    self.set_input(input[i]);
+                }
+                if let Some(pan) = pan {
+                    self.set_pan(pan[i]);
+                }
+                *o = if param == 2 {
+                    self.get_left()
+                } else {
+                    self.get_right()
+                };
+            }
+        }
+    }
+}
@@ -6,7 +6,7 @@ use num::{
 // translated from
 // http://www.earlevel.com/main/2013/06/03/envelope-generators-adsr-code/
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Stage {
     Off,
     Attack,
@@ -15,7 +15,7 @@ pub enum Stage {
     Release,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Adsr<T: num::Float> {
     last_input: T,
     stage: Stage,
@@ -166,6 +166,8 @@ impl<T: Float + FloatConst> Adsr<T> {
 
     pub fn set_sustain_level(&mut self, level: T) {
         self.sustain_level = level;
+        self.decay_base =
+            (self.sustain_level - self.target_ratio_dr) * (T::one() - self.decay_coef);
     }
 
     pub fn set_target_ratio_a(&mut self, mut ratio: T) {
@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct MoogFilter {
     input: f32,
 
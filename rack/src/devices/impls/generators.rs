@@ -10,7 +10,17 @@ use num::traits::{
     Zero,
 };
 
-#[derive(Debug)]
+/// Rising ramp from -1 to 1 over one period, `phase` in radians
+pub fn saw<T: Float + FloatConst>(phase: T) -> T {
+    phase / T::PI() - T::one()
+}
+
+/// Triangle between -1 and 1 in phase with a sine, `phase` in radians
+pub fn triangle<T: Float + FloatConst>(phase: T) -> T {
+    phase.sin().asin() * T::FRAC_2_PI()
+}
+
+#[derive(Debug, Clone)]
 pub struct SquarePulse<T>
 where
     T: Float,
@@ -129,6 +139,7 @@ impl<T: Float + FloatConst + FromPrimitive + Div + FloatConst + AddAssign> FmOsc
     }
 }
 
+#[derive(Clone)]
 pub struct Osc<T> {
     sample_rate: T,
     freq: T,
@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct Attenuator {
     input: f32,
     factor: f32,
@@ -30,6 +31,7 @@ impl Default for Attenuator {
     }
 }
 
+#[derive(Clone)]
 pub struct AbMixer {
     a: f32,
     b: f32,
@@ -67,3 +69,50 @@ impl Default for AbMixer {
         Self::new()
     }
 }
+
+/// Equal power panner, `pan` goes from -1 (left) to 1 (right)
+#[derive(Clone)]
+pub struct Panner {
+    input: f32,
+    pan: f32,
+    left: f32,
+    right: f32,
+}
+
+impl Panner {
+    pub fn new() -> Self {
+        let mut res = Self {
+            input: 0.0,
+            pan: 0.0,
+            left: 0.0,
+            right: 0.0,
+        };
+        res.set_pan(0.0);
+        res
+    }
+
+    pub fn set_input(&mut self, input: f32) {
+        self.input = input;
+    }
+
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1.0, 1.0);
+        let angle = (self.pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        self.left = angle.cos();
+        self.right = angle.sin();
+    }
+
+    pub fn get_left(&self) -> f32 {
+        self.input * self.left
+    }
+
+    pub fn get_right(&self) -> f32 {
+        self.input * self.right
+    }
+}
+
+impl Default for Panner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
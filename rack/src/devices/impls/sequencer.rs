@@ -1,3 +1,7 @@
+/// Input stepping the sequencer, instead of its tempo
+pub const CLOCK: u8 = 10;
+
+#[derive(Clone)]
 pub struct Sequencer {
     samplerate: f32,
     samples_to_next: f32,
@@ -6,6 +10,13 @@ pub struct Sequencer {
     next: usize,
     pub bpm: f32,
     pub sequence: [f32; 8],
+    /// Steps on every rising edge, once connected it replaces the tempo
+    pub clock: f32,
+    /// Goes back to the first step on a rising edge
+    pub reset: f32,
+    clocked: bool,
+    last_clock: f32,
+    last_reset: f32,
 }
 
 impl Sequencer {
@@ -18,6 +29,11 @@ impl Sequencer {
             next: 0,
             bpm: 0.,
             sequence: [0.; 8],
+            clock: 0.,
+            reset: 0.,
+            clocked: false,
+            last_clock: 0.,
+            last_reset: 0.,
         }
     }
 
@@ -26,8 +42,37 @@ impl Sequencer {
         self.samples_to_next = (1. / (bpm / 60.)) * self.samplerate;
     }
 
+    /// Follows [`Sequencer::clock`] while `clocked`, or steps at
+    /// [`Sequencer::bpm`] otherwise
+    pub fn set_clocked(&mut self, clocked: bool) {
+        if clocked && !self.clocked {
+            // Stay quiet until the first edge, which plays the first step
+            self.next = self.sequence.len() - 1;
+            self.samples_since_last = self.duration + 1.;
+        }
+        self.clocked = clocked;
+    }
+
+    pub fn set_clock(&mut self, clock: f32) {
+        self.clock = clock;
+    }
+
+    fn play_step(&mut self, step: usize) {
+        self.next = step % 8;
+        self.samples_since_last = 0.;
+    }
+
     pub fn get_output(&mut self) -> f32 {
-        if self.samples_to_next < 1. {
+        let reset = self.reset >= 0.5 && self.last_reset < 0.5;
+        let clock = self.clock >= 0.5 && self.last_clock < 0.5;
+        self.last_reset = self.reset;
+        self.last_clock = self.clock;
+        // Resetting on a clock edge plays the first step, not the second
+        if reset {
+            self.play_step(0);
+        } else if clock {
+            self.play_step(self.next + 1);
+        } else if !self.clocked && self.samples_to_next < 1. {
             self.next += 1;
             self.next %= 8;
             self.samples_to_next = (1. / (self.bpm / 60.)) * self.samplerate;
@@ -0,0 +1,414 @@
+use std::{
+    collections::VecDeque,
+    mem,
+};
+
+use wmidi::{
+    ControlFunction,
+    MidiMessage,
+};
+
+use crate::{
+    devices::block::MAX_BLOCK,
+    graph::{
+        compiled::{
+            compile,
+            ByteCode,
+            Frame,
+            CHANNELS,
+        },
+        DeviceId,
+    },
+    midi::MidiEvent,
+    transport::{
+        ClockFollower,
+        ClockSender,
+        ClockSync,
+        Transport,
+        TransportCommand,
+        TransportSettings,
+    },
+};
+
+/// How long the previous program keeps playing after a rebuild
+const CROSSFADE_SECONDS: f32 = 0.005;
+/// MIDI clock messages that can be sent between two calls to `take_midi_out`
+const CLOCK_OUT_CAPACITY: usize = 256;
+
+/// Runs compiled programs, crossfading between the old and the new one
+/// whenever the rack gets rebuilt.
+///
+/// Programs are compiled elsewhere and handed over boxed, so that swapping
+/// them in is just moving a pointer. Replaced programs are handed back the
+/// same way, so they can be dropped off the audio thread.
+pub struct Engine {
+    current: Box<ByteCode>,
+    /// Program that was replaced and is still fading out
+    fading: Option<Box<ByteCode>>,
+    /// Program that finished fading out, waiting for [`Engine::take_retired`]
+    retired: Option<Box<ByteCode>>,
+    fade_len: usize,
+    fade_left: usize,
+    /// Block rendered by the fading program
+    scratch: Vec<Frame>,
+    /// Samples rendered so far
+    now: u64,
+    sync: ClockSync,
+    follower: ClockFollower,
+    sender: ClockSender,
+    /// MIDI clock to send, along with the sample it's due on
+    clock_out: Vec<(u64, MidiMessage<'static>)>,
+    /// Sample `take_midi_out` was last called on
+    taken: u64,
+    /// Frames from the audio interface for the next samples
+    input: Vec<Frame>,
+    /// How many of them were played already
+    input_used: usize,
+}
+
+impl Engine {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            current: Box::new(compile(&Default::default(), sample_rate)),
+            fading: None,
+            retired: None,
+            fade_len: ((sample_rate * CROSSFADE_SECONDS) as usize).max(1),
+            fade_left: 0,
+            scratch: vec![[0.0; CHANNELS]; MAX_BLOCK],
+            now: 0,
+            sync: ClockSync::default(),
+            follower: ClockFollower::new(sample_rate),
+            sender: ClockSender::default(),
+            clock_out: Vec::with_capacity(CLOCK_OUT_CAPACITY),
+            taken: 0,
+            input: Vec::with_capacity(MAX_BLOCK),
+            input_used: 0,
+        }
+    }
+
+    /// Replaces the running program, carrying over the state of every device that survived.
+    ///
+    /// Returns the program that was still fading out from the previous swap, if any.
+    pub fn swap(&mut self, mut next: Box<ByteCode>) -> Option<Box<ByteCode>> {
+        next.carry_state_from(&self.current);
+        let old = mem::replace(&mut self.current, next);
+        self.fade_left = self.fade_len;
+        self.fading.replace(old)
+    }
+
+    /// Program that finished fading out since the last call
+    pub fn take_retired(&mut self) -> Option<Box<ByteCode>> {
+        self.retired.take()
+    }
+
+    /// Frames from the audio interface, played by `AudioIn` devices over the
+    /// next samples. Only the first [`MAX_BLOCK`] are kept, past those or
+    /// without any it's silent.
+    pub fn set_input(&mut self, frames: &[Frame]) {
+        self.input.clear();
+        self.input
+            .extend_from_slice(&frames[..frames.len().min(MAX_BLOCK)]);
+        self.input_used = 0;
+    }
+
+    /// Hands the next `len` input frames over to the programs
+    fn feed_input(&mut self, len: usize) {
+        let start = self.input_used.min(self.input.len());
+        let end = (start + len).min(self.input.len());
+        self.input_used += len;
+        let frames = &self.input[start..end];
+        self.current.set_input(frames);
+        if let Some(fading) = &mut self.fading {
+            fading.set_input(frames);
+        }
+    }
+
+    pub fn update_param(&mut self, pid: (DeviceId, u8), value: f32) {
+        self.current.update_param(pid, value);
+        if let Some(fading) = &mut self.fading {
+            fading.update_param(pid, value);
+        }
+    }
+
+    pub fn handle_midi(&mut self, port: usize, msg: &MidiMessage) {
+        if self.sync.follow == Some(port) {
+            let Self {
+                current,
+                follower,
+                now,
+                ..
+            } = self;
+            if follower.handle(*now, msg, current.transport_mut()) {
+                if self.sync.send && *msg != MidiMessage::TimingClock {
+                    // Clocks get sent as the transport passes them, the rest is passed on
+                    queue(&mut self.clock_out, self.now, msg.to_owned());
+                }
+                self.sync_fading_transport();
+                return;
+            }
+        }
+        self.current.handle_midi(port, msg);
+        if let Some(fading) = &mut self.fading {
+            fading.handle_midi(port, msg);
+        }
+    }
+
+    /// Hands out what the running program sent through `MidiOut` devices since
+    /// the last call, along with how many samples after that call it was sent.
+    ///
+    /// Whatever a fading program sends is dropped, its notes carry over anyway.
+    /// MIDI clock comes first, if it's being sent.
+    pub fn take_midi_out(&mut self, mut send: impl FnMut(u64, MidiMessage<'static>)) {
+        for (time, msg) in self.clock_out.drain(..) {
+            send(time - self.taken, msg);
+        }
+        self.taken = self.now;
+        self.current.take_midi_out(send);
+        if let Some(fading) = &mut self.fading {
+            fading.take_midi_out(|_, _| ());
+        }
+    }
+
+    /// Queues the clocks the transport gets past over the next `len` samples
+    fn send_clocks(&mut self, len: usize) {
+        if !self.sync.send {
+            return;
+        }
+        let Self {
+            current,
+            sender,
+            clock_out,
+            now,
+            ..
+        } = self;
+        sender.clocks(current.transport(), len, |at, msg| {
+            queue(clock_out, *now + at, msg)
+        });
+    }
+
+    /// Where the transport takes its time from and whether it's passed on
+    pub fn clock_sync(&self) -> ClockSync {
+        self.sync
+    }
+
+    pub fn set_clock_sync(&mut self, sync: ClockSync) {
+        if sync.follow != self.sync.follow {
+            self.follower.reset();
+        }
+        if sync.send && !self.sync.send {
+            self.sender = ClockSender::default();
+        }
+        self.sync = sync;
+    }
+
+    /// Tempo of the MIDI clock followed, once it's been heard
+    pub fn followed_tempo(&self) -> Option<f32> {
+        self.sync.follow.and(self.follower.tempo())
+    }
+
+    /// Fading programs play along on the same transport
+    fn sync_fading_transport(&mut self) {
+        if let Some(fading) = &mut self.fading {
+            *fading.transport_mut() = *self.current.transport();
+        }
+    }
+
+    pub fn transport(&self) -> &Transport {
+        self.current.transport()
+    }
+
+    /// The tempo is left alone while following MIDI clock
+    pub fn set_transport(&mut self, mut settings: TransportSettings) {
+        if let Some(tempo) = self.followed_tempo() {
+            settings.tempo = tempo;
+        }
+        self.current.set_transport(settings);
+        if let Some(fading) = &mut self.fading {
+            fading.set_transport(settings);
+        }
+    }
+
+    pub fn transport_command(&mut self, command: TransportCommand) {
+        if self.sync.send {
+            let Self {
+                current,
+                sender,
+                clock_out,
+                now,
+                ..
+            } = self;
+            sender.command(command, current.transport(), |msg| {
+                queue(clock_out, *now, msg)
+            });
+        }
+        self.current.transport_command(command);
+        if let Some(fading) = &mut self.fading {
+            fading.transport_command(command);
+        }
+    }
+
+    pub fn sample(&mut self) -> Frame {
+        self.send_clocks(1);
+        self.now += 1;
+        self.feed_input(1);
+        let sample = self.current.sample();
+        let Some(fading) = &mut self.fading else {
+            return sample;
+        };
+
+        let old = fading.sample();
+        let t = 1.0 - self.fade_left as f32 / self.fade_len as f32;
+        self.fade_left -= 1;
+        if self.fade_left == 0 {
+            self.retired = self.fading.take();
+        }
+        crossfade(old, sample, t)
+    }
+
+    /// Fills `out` with the next `out.len()` samples
+    pub fn process_block(&mut self, out: &mut [Frame]) {
+        self.send_clocks(out.len());
+        self.now += out.len() as u64;
+        for chunk in out.chunks_mut(MAX_BLOCK) {
+            self.feed_input(chunk.len());
+            self.current.process_block(chunk);
+            let Some(fading) = &mut self.fading else {
+                continue;
+            };
+
+            let old = &mut self.scratch[..chunk.len()];
+            fading.process_block(old);
+            for (sample, old) in chunk.iter_mut().zip(old.iter()) {
+                if self.fade_left == 0 {
+                    break;
+                }
+                let t = 1.0 - self.fade_left as f32 / self.fade_len as f32;
+                self.fade_left -= 1;
+                *sample = crossfade(*old, *sample, t);
+            }
+            if self.fade_left == 0 {
+                self.retired = self.fading.take();
+            }
+        }
+    }
+}
+
+/// Plays timestamped MIDI events on the sample they belong to.
+///
+/// Timestamps come from the MIDI driver's clock rather than the audio one,
+/// so the first event from each port anchors the two. Events arrive while
+/// the previous buffer is playing, so they're played up to a buffer late,
+/// which keeps the distance between them whatever the buffer size. Events
+/// arriving later than that push the anchor back, and ones scheduled too
+/// far ahead, from the clocks drifting apart, pull it forward.
+pub struct MidiScheduler {
+    sample_rate: f64,
+    /// Samples rendered so far
+    now: u64,
+    /// Per port, the sample an event stamped 0 lands on
+    offsets: Vec<Option<i64>>,
+    /// Events waiting for their sample, in order
+    pending: VecDeque<(u64, MidiEvent)>,
+}
+
+impl MidiScheduler {
+    /// Scheduler for events from `ports` input ports, holding at most
+    /// `capacity` events at once
+    pub fn new(sample_rate: f32, ports: usize, capacity: usize) -> Self {
+        Self {
+            sample_rate: sample_rate as f64,
+            now: 0,
+            offsets: vec![None; ports],
+            pending: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Forgets how port clocks line up, for when the ports get reopened
+    pub fn reset_clocks(&mut self) {
+        self.offsets.iter_mut().for_each(|o| *o = None);
+    }
+
+    /// Sample `event` will be played at, if the next buffer is `buffer_len` long.
+    ///
+    /// Returns `None` if the event got dropped, either because it came from
+    /// a port past the ones the scheduler was made for or because too many
+    /// are waiting already. Releases make room by dropping the latest event
+    /// that isn't one, so notes don't get stuck.
+    pub fn schedule(&mut self, event: MidiEvent, buffer_len: usize) -> Option<u64> {
+        let now = self.now as i64;
+        let len = buffer_len as i64;
+        let stamp = (event.time as f64 * self.sample_rate / 1e6).round() as i64;
+
+        let offset = self.offsets.get_mut(event.port)?.get_or_insert(now - stamp);
+        let mut at = stamp + *offset;
+        if at < now {
+            *offset += now - at;
+            at = now;
+        } else if at >= now + 2 * len {
+            *offset -= at - (now + len);
+            at = now + len;
+        }
+        let at = at as u64;
+
+        if self.pending.len() == self.pending.capacity() {
+            // Growing would allocate on the audio thread
+            if !is_release(&event.msg) {
+                return None;
+            }
+            let evicted = self
+                .pending
+                .iter()
+                .rposition(|(_, ev)| !is_release(&ev.msg))?;
+            self.pending.remove(evicted);
+        }
+        let i = self.pending.partition_point(|(t, _)| *t <= at);
+        self.pending.insert(i, (at, event));
+        Some(at)
+    }
+
+    /// Fills `out` like [`Engine::process_block`], handing every event due
+    /// to `engine` right before its sample
+    pub fn process_block(&mut self, engine: &mut Engine, out: &mut [Frame]) {
+        let mut done = 0;
+        while done < out.len() {
+            let time = self.now + done as u64;
+            while let Some((_, ev)) = self.pending.pop_front_if(|(t, _)| *t <= time) {
+                engine.handle_midi(ev.port, &ev.msg);
+            }
+            let next = self
+                .pending
+                .front()
+                .map_or(out.len(), |(t, _)| ((t - self.now) as usize).min(out.len()));
+            engine.process_block(&mut out[done..next]);
+            done = next;
+        }
+        self.now += out.len() as u64;
+    }
+}
+
+/// Whether dropping `msg` could leave a note or the sustain pedal stuck
+fn is_release(msg: &MidiMessage) -> bool {
+    match msg {
+        MidiMessage::NoteOff(..) => true,
+        MidiMessage::NoteOn(_, _, v) => u8::from(*v) == 0,
+        MidiMessage::ControlChange(_, ControlFunction::DAMPER_PEDAL, v) => u8::from(*v) < 64,
+        MidiMessage::ControlChange(
+            _,
+            ControlFunction::ALL_SOUND_OFF | ControlFunction::ALL_NOTES_OFF,
+            _,
+        ) => true,
+        _ => false,
+    }
+}
+
+/// Queues `msg` up to be sent on sample `time`
+fn queue(out: &mut Vec<(u64, MidiMessage<'static>)>, time: u64, msg: MidiMessage<'static>) {
+    // Growing would allocate on the audio thread
+    if out.len() < out.capacity() {
+        out.push((time, msg));
+    }
+}
+
+fn crossfade(old: Frame, new: Frame, t: f32) -> Frame {
+    std::array::from_fn(|c| old[c] * (1.0 - t) + new[c] * t)
+}
@@ -1,5 +1,8 @@
 use std::{
-    collections::BTreeMap,
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
     ops::{
         Index,
         IndexMut,
@@ -12,7 +15,14 @@ use slotmap::{
     SlotMap,
 };
 
-use crate::devices::description::DeviceKind;
+use crate::{
+    devices::description::{
+        DeviceKind,
+        Param,
+    },
+    midi::Polyphony,
+    tuning::Tuning,
+};
 
 use self::modules::Module;
 
@@ -53,100 +63,171 @@ pub struct Graph {
     pub cables: SecondaryMap<InputId, OutputId>,
 }
 
-type CtlGraphGraph = BTreeMap<DeviceId, (DeviceKind, [Option<(DeviceId, u8)>; 16])>;
+/// Devices reachable from the outputs, along with what feeds each of their inputs
+type CtlGraphGraph = BTreeMap<DeviceId, (DeviceKind, BTreeMap<u8, (DeviceId, u8)>)>;
 
 #[derive(Debug, Default)]
 pub struct CtlGraph {
-    pub end: DeviceId,
+    /// Every output device, their frames get summed, and every `MidiOut`
+    pub ends: Vec<DeviceId>,
     pub dev_map: BTreeMap<Connector, (DeviceId, u8)>,
     pub midis: SecondaryMap<OutputId, (DeviceId, u8)>,
+    /// Cables that close a feedback loop, and so are read with a one sample delay
+    pub feedback: SecondaryMap<InputId, (DeviceId, u8)>,
+    pub polyphony: Polyphony,
+    pub tuning: Tuning,
     graph: CtlGraphGraph,
 }
 
+impl CtlGraph {
+    /// Number of channels every output of every device carries.
+    ///
+    /// `MidiControl` outputs carry a channel per voice, and merges one per input
+    /// up to the last one connected. Other devices run once per channel of their
+    /// widest poly input, so that's how wide their outputs are.
+    pub fn channels(&self) -> BTreeMap<(DeviceId, u8), usize> {
+        let mut channels = BTreeMap::new();
+        loop {
+            let mut changed = false;
+            for (did, (kind, params)) in &self.graph {
+                let width = match kind {
+                    DeviceKind::MidiControl => self.polyphony.voices.max(1),
+                    DeviceKind::Merge => params.keys().max().map_or(1, |p| *p as usize + 1),
+                    DeviceKind::Split => 1,
+                    _ => params
+                        .iter()
+                        .filter(|(param, _)| kind.accepts_poly(**param))
+                        .map(|(_, source)| channels.get(source).copied().unwrap_or(1))
+                        .max()
+                        .unwrap_or(1),
+                };
+                for (pi, param) in kind.params().iter().enumerate() {
+                    if let Param::Out(_) = param {
+                        let old = channels.insert((*did, pi as u8), width);
+                        changed |= old != Some(width);
+                    }
+                }
+            }
+            if !changed {
+                return channels;
+            }
+        }
+    }
+
+    /// Inputs connected to outputs carrying more than one channel
+    pub fn poly_inputs(&self) -> Vec<InputId> {
+        let channels = self.channels();
+        self.dev_map
+            .iter()
+            .filter_map(|(conn, (dev, param))| {
+                let Connector::In(input) = conn else {
+                    return None;
+                };
+                let (_, params) = self.graph.get(dev)?;
+                let source = params.get(param)?;
+                (channels.get(source).copied().unwrap_or(1) > 1).then_some(*input)
+            })
+            .collect()
+    }
+}
+
 struct Walker {
     dev_map: BTreeMap<Connector, (DeviceId, u8)>,
     midis: SecondaryMap<OutputId, (DeviceId, u8)>,
+    feedback: SecondaryMap<InputId, (DeviceId, u8)>,
+    /// Devices whose inputs are being walked right now
+    walking: BTreeSet<DeviceId>,
     /// from node closer to output backwards
     graph: CtlGraphGraph,
 }
 
 impl Walker {
-    fn walk(to: InputId, graph: &Graph) -> CtlGraph {
-        let end = graph.ins[to].0;
+    fn walk(ends: Vec<DeviceId>, graph: &Graph) -> CtlGraph {
         let mut this = Self {
             dev_map: Default::default(),
             midis: Default::default(),
+            feedback: Default::default(),
+            walking: Default::default(),
             graph: Default::default(),
         };
 
-        this.walk_input(to, graph);
+        for end in &ends {
+            this.walk_device(*end, graph);
+        }
 
         let Walker {
             dev_map,
             midis,
+            feedback,
+            walking: _,
             graph,
         } = this;
 
         CtlGraph {
-            end,
+            ends,
             dev_map,
             midis,
+            feedback,
+            polyphony: Default::default(),
+            tuning: Default::default(),
             graph,
         }
     }
 
+    fn walk_device(&mut self, dev: DeviceId, graph: &Graph) {
+        if self.graph.contains_key(&dev) {
+            return;
+        }
+        let dev_desc = graph.devices[dev];
+        log::trace!("Walking {dev_desc:?}");
+        self.graph.insert(dev, (dev_desc, BTreeMap::new()));
+        self.walking.insert(dev);
+
+        // for each of this device's inputs
+        let prevs = graph
+            .dev_ins
+            .get(dev)
+            .map(|ins| &**ins)
+            .unwrap_or(&[])
+            .iter()
+            .copied();
+
+        for input in prevs {
+            self.walk_input(input, graph);
+        }
+        self.walking.remove(&dev);
+    }
+
     fn walk_input(&mut self, input: InputId, graph: &Graph) {
         let (dev, param) = graph[input];
-        let dev_desc = graph.devices[dev];
-        println!("Walking input {param} for {dev_desc:?}");
 
         self.dev_map.insert(Connector::In(input), (dev, param));
 
-        let prev = graph.cables.get(input).copied().map(|out| graph[out]);
-        if let Some((prev_dev, _)) = prev {
-            // for each of this device's previous devices' outputs
-            let prevs = graph
-                .dev_outs
-                .get(prev_dev)
-                .map(|outs| &**outs)
-                .unwrap_or(&[])
-                .iter()
-                .copied()
-                .map(|out| (out, graph.outs.get(out).copied()));
-
-            for (output, _) in prevs {
-                let r = self.walk_output(output, graph);
-
-                let (_, params) = self.graph.entry(dev).or_insert((dev_desc, [None; 16]));
-                params[param as usize] = Some(r);
+        if let Some(&output) = graph.cables.get(input) {
+            // Whatever feeds this input already depends on this device,
+            // so the loop has to be broken here
+            if self.walking.contains(&graph[output].0) {
+                log::debug!("Delaying feedback into {:?}", graph.devices[dev]);
+                self.feedback.insert(input, (dev, param));
             }
+            let source = self.walk_output(output, graph);
+
+            let (_, params) = self.graph.get_mut(&dev).expect("Device was not walked");
+            params.insert(param, source);
         }
     }
 
     fn walk_output(&mut self, output: OutputId, graph: &Graph) -> (DeviceId, u8) {
         let (dev, param) = graph[output];
         let dev_desc = graph.devices[dev];
-        println!("Walking output {param} for {dev_desc:?}");
 
         if matches!(dev_desc, DeviceKind::MidiControl) {
             self.midis.insert(output, (dev, param));
         }
-        let (_, _) = self.graph.entry(dev).or_insert((dev_desc, [None; 16]));
 
         self.dev_map.insert(Connector::Out(output), (dev, param));
 
-        // for each of this device's inputs
-        let prevs = graph
-            .dev_ins
-            .get(dev)
-            .map(|ins| &**ins)
-            .unwrap_or(&[])
-            .iter()
-            .copied();
-
-        for input in prevs {
-            self.walk_input(input, graph);
-        }
+        self.walk_device(dev, graph);
         (dev, param)
     }
 }
@@ -189,8 +270,57 @@ impl Graph {
         }
     }
 
-    pub fn walk_to(&self, end: InputId) -> CtlGraph {
-        Walker::walk(end, self)
+    /// Adds a device along with its ins and outs, without attaching it to any module
+    pub fn insert_device(&mut self, kind: DeviceKind) -> DeviceId {
+        let did = self.devices.insert(kind);
+        for (pi, param) in kind.params().iter().enumerate() {
+            match param {
+                Param::In(_) => {
+                    let input = self.ins.insert((did, pi as u8));
+                    self.dev_ins.entry(did).unwrap().or_default().push(input);
+                }
+                Param::Out(_) => {
+                    let output = self.outs.insert((did, pi as u8));
+                    self.dev_outs.entry(did).unwrap().or_default().push(output);
+                }
+            }
+        }
+        did
+    }
+
+    /// Input `param` of device `dev`
+    pub fn input_of(&self, dev: DeviceId, param: u8) -> Option<InputId> {
+        self.dev_ins
+            .get(dev)?
+            .iter()
+            .copied()
+            .find(|i| self.ins[*i].1 == param)
+    }
+
+    /// Output `param` of device `dev`
+    pub fn output_of(&self, dev: DeviceId, param: u8) -> Option<OutputId> {
+        self.dev_outs
+            .get(dev)?
+            .iter()
+            .copied()
+            .find(|o| self.outs[*o].1 == param)
+    }
+
+    /// Module a given device belongs to
+    pub fn module_of(&self, dev: DeviceId) -> Option<ModuleId> {
+        self.modules
+            .iter()
+            .find_map(|(mid, m)| m.devices.contains(&dev).then_some(mid))
+    }
+
+    /// Walks back from every device the rack ends in
+    pub fn walk(&self) -> CtlGraph {
+        let ends = self
+            .devices
+            .iter()
+            .filter_map(|(did, kind)| kind.is_end().then_some(did))
+            .collect();
+        Walker::walk(ends, self)
     }
 }
 
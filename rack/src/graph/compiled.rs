@@ -1,23 +1,112 @@
-use crate::devices::Device;
-use std::collections::{
-    BTreeMap,
-    BTreeSet,
-    VecDeque,
+use crate::{
+    devices::{
+        block::{
+            BlockInputs,
+            BlockOutputs,
+            MAX_BLOCK,
+        },
+        description::{
+            DeviceKind,
+            POLY_CHANNELS,
+        },
+        impls::{
+            sequencer::{
+                self,
+                Sequencer,
+            },
+            AudioIn,
+            Clock,
+            MidiOut,
+        },
+        Device,
+    },
+    midi::{
+        MidiFilter,
+        VoiceAllocator,
+    },
+    transport::{
+        Transport,
+        TransportCommand,
+        TransportSettings,
+    },
+    tuning::NoteFreqs,
 };
+use std::{
+    collections::{
+        BTreeMap,
+        BTreeSet,
+    },
+    mem,
+};
+use wmidi::MidiMessage;
 
 use super::{
     CtlGraph,
+    CtlGraphGraph,
     DeviceId,
 };
 
-type NodeToDevice = BTreeMap<DeviceId, usize>;
-type OutputMap = BTreeMap<usize, ((DeviceId, u8), Vec<(DeviceId, u8)>)>;
+/// Output of a merge, after an input for each channel
+const MERGE_OUTPUT: u8 = POLY_CHANNELS as u8;
+
+/// Number of channels a program renders
+pub const CHANNELS: usize = 2;
+
+/// One sample for each channel, left then right
+pub type Frame = [f32; CHANNELS];
+
+/// Every copy of a device, one per channel it processes.
+/// Merges and splits have none, they're compiled into cables.
+type NodeToDevice = BTreeMap<DeviceId, Vec<usize>>;
+type OutputSlots = BTreeMap<(DeviceId, u8), Slots>;
+
+/// Where the values of a device output are stored
+#[derive(Debug)]
+struct Slots {
+    /// One slot per channel
+    channels: Vec<u16>,
+    /// Sum of all channels, for inputs that don't take poly cables
+    mix: Option<u16>,
+}
 
 pub struct ByteCode {
     devices: Vec<Box<dyn Device + Send + Sync>>,
     node_to_device: NodeToDevice,
     code: Vec<Op>,
-    sample: f32,
+    /// Which value slot each connected device output is stored in
+    slots: OutputSlots,
+    values: Vec<f32>,
+    sample: Frame,
+    block_code: Vec<BlockOp>,
+    /// One block of samples per value slot
+    buffers: Vec<Vec<f32>>,
+    /// Output buffers of the device currently being processed
+    scratch: Vec<Vec<f32>>,
+    /// Output devices, summed into the final frame
+    ends: Vec<End>,
+    /// Feedback needs the previous sample, so such programs can't run in blocks
+    has_feedback: bool,
+    /// Every `MidiControl` in use
+    midis: Vec<MidiRoute>,
+    /// Every `MidiOut`
+    midi_outs: Vec<u16>,
+    /// Frequencies of the rack's tuning, which every `MidiOut` picks notes from
+    freqs: NoteFreqs,
+    transport: Transport,
+    /// Every `Clock`, kept in step with `transport`
+    clocks: Vec<u16>,
+    /// Every `AudioIn`
+    audio_ins: Vec<u16>,
+    /// Every `Sequencer`, and whether a cable drives its clock input
+    sequencers: Vec<(u16, bool)>,
+}
+
+/// A `MidiControl`, the messages it plays and the voices it plays them on
+#[derive(Clone, Debug)]
+struct MidiRoute {
+    device: DeviceId,
+    filter: MidiFilter,
+    voices: VoiceAllocator,
 }
 
 impl std::fmt::Debug for ByteCode {
@@ -26,6 +115,8 @@ impl std::fmt::Debug for ByteCode {
             .field("devices_count", &self.devices.len())
             .field("node_to_device", &self.node_to_device)
             .field("code", &self.code)
+            .field("block_code", &self.block_code)
+            .field("values", &self.values)
             .field("sample", &self.sample)
             .finish()
     }
@@ -33,85 +124,620 @@ impl std::fmt::Debug for ByteCode {
 
 impl ByteCode {
     pub fn update_param(&mut self, _pid @ (dev, param): (DeviceId, u8), value: f32) {
-        let d = if let Some(d) = self.node_to_device.get(&dev) {
-            *d
-        } else {
+        let Some(ds) = self.node_to_device.get(&dev) else {
             return;
         };
-        let d = self.devices.get_mut(d).expect("No such device");
-        d.set_param_indexed(param, value)
+        for d in ds {
+            let d = self.devices.get_mut(*d).expect("No such device");
+            d.set_param_indexed(param, value)
+        }
+        if let Some(route) = self.midis.iter_mut().find(|r| r.device == dev) {
+            route.filter.set_param(param, value);
+        }
+    }
+
+    /// Takes over the state of every device that is also present in `old`,
+    /// so that recompiling doesn't reset oscillators, envelopes and filters
+    pub fn carry_state_from(&mut self, old: &ByteCode) {
+        for (did, ds) in &self.node_to_device {
+            if let Some(old_ds) = old.node_to_device.get(did) {
+                for (&d, &old_d) in ds.iter().zip(old_ds) {
+                    self.devices[d].copy_state(old.devices[old_d].as_any());
+                }
+            }
+        }
+        for (output, slots) in &self.slots {
+            // Merges and splits only alias other slots, or the silent one
+            if self.node_to_device[&output.0].is_empty() {
+                continue;
+            }
+            if let Some(old_slots) = old.slots.get(output) {
+                for (&slot, &old_slot) in slots.channels.iter().zip(&old_slots.channels) {
+                    self.values[slot as usize] = old.values[old_slot as usize];
+                }
+                if let (Some(mix), Some(old_mix)) = (slots.mix, old_slots.mix) {
+                    self.values[mix as usize] = old.values[old_mix as usize];
+                }
+            }
+        }
+        for route in &mut self.midis {
+            if let Some(old) = old.midis.iter().find(|r| r.device == route.device) {
+                route.filter = old.filter;
+                route.voices.carry_state_from(&old.voices);
+            }
+        }
+        self.sample = old.sample;
+        self.transport = old.transport;
+        // The old state may have been clocked through a cable that is gone
+        // now, or tuned differently
+        self.sync_sequencers();
+        self.sync_midi_outs();
+    }
+
+    fn sync_midi_outs(&mut self) {
+        for d in &self.midi_outs {
+            midi_out(&mut self.devices[*d as usize]).set_freqs(&self.freqs);
+        }
+    }
+
+    fn sync_sequencers(&mut self) {
+        for (d, clocked) in &self.sequencers {
+            sequencer(&mut self.devices[*d as usize]).set_clocked(*clocked);
+        }
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut Transport {
+        &mut self.transport
+    }
+
+    pub fn set_transport(&mut self, settings: TransportSettings) {
+        self.transport.set_settings(settings);
     }
 
-    pub fn sample(&mut self) -> f32 {
+    pub fn transport_command(&mut self, command: TransportCommand) {
+        self.transport.command(command);
+    }
+
+    /// Hands every `Clock` the position of the transport, from where they
+    /// can follow it through a block on their own
+    fn sync_clocks(&mut self) {
+        for d in &self.clocks {
+            clock(&mut self.devices[*d as usize]).transport = self.transport;
+        }
+    }
+
+    /// Hands `frames` from the audio interface to every `AudioIn`, to play
+    /// over the next samples
+    pub fn set_input(&mut self, frames: &[Frame]) {
+        for d in &self.audio_ins {
+            audio_in(&mut self.devices[*d as usize]).feed(frames);
+        }
+    }
+
+    /// Plays `msg`, which came in through input port `port`, on the voices
+    /// of every `MidiControl` listening to it
+    pub fn handle_midi(&mut self, port: usize, msg: &MidiMessage) {
+        let Self {
+            devices,
+            node_to_device,
+            midis,
+            ..
+        } = self;
+        for route in midis {
+            if !route.filter.accepts(port, msg) {
+                continue;
+            }
+            let ds = node_to_device.get(&route.device);
+            route.voices.handle(msg, |voice, param, value| {
+                if let Some(&d) = ds.and_then(|ds| ds.get(voice)) {
+                    devices[d].set_param_indexed(param, value);
+                }
+            });
+        }
+    }
+
+    pub fn sample(&mut self) -> Frame {
+        self.sync_clocks();
+        let mut frame = [0.0; CHANNELS];
         for op in &self.code {
-            match op {
-                Op::Sample(d, oid) => {
-                    self.sample = self.devices[*d as usize].get_output_indexed(*oid);
+            match *op {
+                Op::Sample(d, oid, slot) => {
+                    self.values[slot as usize] = self.devices[d as usize].get_output_indexed(oid);
                 }
-                Op::Output => break,
-                Op::Parametrise(d, pid) => {
-                    self.devices[*d as usize].set_param_indexed(*pid, self.sample)
+                Op::Parametrise(d, pid, slot) => {
+                    self.devices[d as usize].set_param_indexed(pid, self.values[slot as usize])
+                }
+                Op::Mix(ref channels, slot) => {
+                    self.values[slot as usize] =
+                        channels.iter().map(|c| self.values[*c as usize]).sum();
+                }
+                Op::Output(d, params) => {
+                    let end = &mut self.devices[d as usize];
+                    for (sample, param) in frame.iter_mut().zip(params) {
+                        *sample += end.get_output_indexed(param);
+                    }
                 }
             }
         }
+        for d in &self.midi_outs {
+            midi_out(&mut self.devices[*d as usize]).tick();
+        }
+        for d in &self.audio_ins {
+            audio_in(&mut self.devices[*d as usize]).tick();
+        }
+        self.transport.advance(1);
+        self.sample = frame;
         self.sample
     }
+
+    /// Hands out what every `MidiOut` sent since the last call, along with
+    /// how many samples after that call it was sent
+    pub fn take_midi_out(&mut self, mut send: impl FnMut(u64, MidiMessage<'static>)) {
+        for d in &self.midi_outs {
+            midi_out(&mut self.devices[*d as usize]).take_messages(&mut send);
+        }
+    }
+
+    /// Fills `out` with the next `out.len()` samples.
+    ///
+    /// Parameter updates only take effect between calls.
+    pub fn process_block(&mut self, out: &mut [Frame]) {
+        if self.has_feedback {
+            for sample in out {
+                *sample = self.sample();
+            }
+            return;
+        }
+        for chunk in out.chunks_mut(MAX_BLOCK) {
+            self.process_chunk(chunk);
+        }
+    }
+
+    fn process_chunk(&mut self, out: &mut [Frame]) {
+        let len = out.len();
+        if len == 0 {
+            return;
+        }
+        self.sync_clocks();
+
+        for op in &self.block_code {
+            let (device, op_ins, op_outs) = match op {
+                BlockOp::Process { device, ins, outs } => (*device, ins, outs),
+                BlockOp::Mix(channels, slot) => {
+                    let mut mix = mem::take(&mut self.buffers[*slot as usize]);
+                    mix[..len].fill(0.0);
+                    for channel in channels {
+                        let channel = &self.buffers[*channel as usize];
+                        for (sum, value) in mix[..len].iter_mut().zip(channel) {
+                            *sum += *value;
+                        }
+                    }
+                    self.buffers[*slot as usize] = mix;
+                    continue;
+                }
+            };
+
+            // Outputs are moved out so the device can borrow every other buffer
+            let mut taken = mem::take(&mut self.scratch);
+            taken.extend(
+                op_outs
+                    .iter()
+                    .map(|(_, slot)| mem::take(&mut self.buffers[*slot as usize])),
+            );
+            let ins = BlockInputs {
+                buffers: &self.buffers,
+                params: op_ins,
+                len,
+            };
+            let mut outs = BlockOutputs {
+                buffers: &mut taken,
+                params: op_outs,
+                len,
+            };
+            self.devices[device as usize].process_block(&ins, &mut outs);
+            for ((_, slot), buffer) in op_outs.iter().zip(taken.drain(..)) {
+                self.buffers[*slot as usize] = buffer;
+            }
+            self.scratch = taken;
+        }
+
+        out.fill([0.0; CHANNELS]);
+        for end in &self.ends {
+            for (channel, (slot, param)) in end.slots.iter().zip(end.params).enumerate() {
+                match slot {
+                    Some(slot) => {
+                        let buffer = &self.buffers[*slot as usize];
+                        for (frame, value) in out.iter_mut().zip(buffer) {
+                            frame[channel] += *value;
+                        }
+                    }
+                    None => {
+                        let value = self.devices[end.device as usize].get_output_indexed(param);
+                        for frame in out.iter_mut() {
+                            frame[channel] += value;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Keep the per sample state in sync, so programs can be switched over freely
+        for (value, buffer) in self.values.iter_mut().zip(&self.buffers) {
+            *value = buffer[len - 1];
+        }
+        self.transport.advance(len);
+        self.sample = out[len - 1];
+    }
+}
+
+fn midi_out(device: &mut Box<dyn Device + Send + Sync>) -> &mut MidiOut {
+    device
+        .as_any_mut()
+        .downcast_mut()
+        .expect("Only MidiOut devices are listed as such")
+}
+
+fn clock(device: &mut Box<dyn Device + Send + Sync>) -> &mut Clock {
+    device
+        .as_any_mut()
+        .downcast_mut()
+        .expect("Only Clock devices are listed as such")
+}
+
+fn audio_in(device: &mut Box<dyn Device + Send + Sync>) -> &mut AudioIn {
+    device
+        .as_any_mut()
+        .downcast_mut()
+        .expect("Only AudioIn devices are listed as such")
+}
+
+fn sequencer(device: &mut Box<dyn Device + Send + Sync>) -> &mut Sequencer {
+    device
+        .as_any_mut()
+        .downcast_mut()
+        .expect("Only Sequencer devices are listed as such")
+}
+
+/// Output device, along with what feeds each of the channels it contributes to
+#[derive(Debug)]
+struct End {
+    device: u16,
+    /// Input of the device feeding each channel
+    params: [u8; CHANNELS],
+    /// Slot feeding each channel, if anything is connected to it
+    slots: [Option<u16>; CHANNELS],
+}
+
+#[derive(Debug)]
+enum BlockOp {
+    /// Runs device `device` over a block, reading `ins` and writing `outs`,
+    /// both given as parameter index and value slot
+    Process {
+        device: u16,
+        ins: Vec<(u8, u16)>,
+        outs: Vec<(u8, u16)>,
+    },
+    /// Sum slots `0` into slot `1`
+    Mix(Vec<u16>, u16),
 }
 
 #[derive(Debug)]
 enum Op {
-    Sample(u16, u8),
-    Output,
-    Parametrise(u16, u8),
+    /// Store output `1` of device `0` in slot `2`
+    Sample(u16, u8, u16),
+    /// Set parameter `1` of device `0` to the value in slot `2`
+    Parametrise(u16, u8, u16),
+    /// Add inputs `1` of output device `0` to the final frame
+    Output(u16, [u8; CHANNELS]),
+    /// Sum slots `0` into slot `1`
+    Mix(Vec<u16>, u16),
 }
-pub fn compile(ctl_graph: &CtlGraph, sample_rate: f32) -> ByteCode {
-    let mut graph = ctl_graph.graph.clone();
-    let mut code = VecDeque::new();
-    let mut node_to_device = BTreeMap::new();
-    let mut devices = Vec::new();
-    let mut output_params: OutputMap = BTreeMap::new();
 
-    let mut last = ctl_graph.end;
-    let mut counter = 0;
+/// Orders devices so that each one comes after every device feeding it,
+/// except through `delayed` parameters
+fn schedule(graph: &CtlGraphGraph, delayed: &BTreeSet<(DeviceId, u8)>) -> Vec<DeviceId> {
+    let mut pending: BTreeMap<DeviceId, BTreeSet<DeviceId>> = graph
+        .iter()
+        .map(|(did, (_, params))| {
+            let deps = params
+                .iter()
+                .filter(|(pid, _)| !delayed.contains(&(*did, **pid)))
+                .map(|(_, (source, _))| *source)
+                .filter(|source| source != did)
+                .collect();
+            (*did, deps)
+        })
+        .collect();
 
-    let mut prevs = BTreeSet::new();
-    while let Some((dev, params)) = graph.remove(&last) {
-        let device_idx = dev.make()(&mut devices, sample_rate);
-        node_to_device.insert(last, device_idx);
+    let mut order = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let mut ready: Vec<_> = pending
+            .iter()
+            .filter_map(|(did, deps)| deps.is_empty().then_some(*did))
+            .collect();
+        if ready.is_empty() {
+            // Can't happen as long as the walker breaks every loop,
+            // but an arbitrary order is better than hanging
+            ready.extend(pending.keys().next().copied());
+        }
 
-        for (pid, params) in params.into_iter().enumerate() {
-            if let Some((source_did, source_pid)) = params {
-                prevs.insert(source_did);
+        for did in &ready {
+            pending.remove(did);
+        }
+        for deps in pending.values_mut() {
+            for did in &ready {
+                deps.remove(did);
+            }
+        }
+        order.extend(ready);
+    }
+    order
+}
+
+/// Hands out value slots while compiling
+#[derive(Default)]
+struct Layout {
+    slots: OutputSlots,
+    len: u16,
+    /// Slot that's never written, for channels a cable doesn't carry
+    silent: Option<u16>,
+}
 
-                let (_, to_parametrise) = output_params
-                    .entry(counter)
-                    .or_insert(((source_did, source_pid), Vec::new()));
-                counter += 1;
+impl Layout {
+    fn alloc(&mut self) -> u16 {
+        self.len += 1;
+        self.len - 1
+    }
 
-                to_parametrise.push((last, pid as u8));
+    fn silent(&mut self) -> u16 {
+        match self.silent {
+            Some(slot) => slot,
+            None => {
+                let slot = self.alloc();
+                self.silent = Some(slot);
+                slot
             }
         }
-        if let Some(new) = prevs.pop_first() {
-            last = new;
-        } else {
-            break;
+    }
+
+    /// Slot with every channel of `source` summed
+    fn mix(&mut self, source: (DeviceId, u8)) -> u16 {
+        let Some(slots) = self.slots.get(&source) else {
+            return self.silent();
+        };
+        if let [slot] = *slots.channels {
+            return slot;
+        }
+        if let Some(mix) = slots.mix {
+            return mix;
+        }
+        let mix = self.alloc();
+        self.slots.get_mut(&source).unwrap().mix = Some(mix);
+        mix
+    }
+
+    /// Slot channel `channel` of input `param` of a `kind` device reads from `source`.
+    ///
+    /// Mono signals go to every channel.
+    fn read(&mut self, source: (DeviceId, u8), kind: DeviceKind, param: u8, channel: usize) -> u16 {
+        if !kind.accepts_poly(param) {
+            return self.mix(source);
+        }
+        let channels = self.slots.get(&source).map(|s| &*s.channels);
+        match channels {
+            Some([slot]) => *slot,
+            Some(channels) if channel < channels.len() => channels[channel],
+            _ => self.silent(),
+        }
+    }
+}
+
+/// Compiles `ctl_graph` into a program evaluating it one sample at a time.
+///
+/// Cables in [`CtlGraph::feedback`] are read before their source device
+/// runs, so they carry the previous sample's value. Devices run once for every
+/// channel of their poly inputs, which includes every voice of a `MidiControl`.
+pub fn compile(ctl_graph: &CtlGraph, sample_rate: f32) -> ByteCode {
+    let graph = &ctl_graph.graph;
+    let delayed = ctl_graph.feedback.values().copied().collect();
+    let order = schedule(graph, &delayed);
+    let channels = ctl_graph.channels();
+    let width = |did: &DeviceId| {
+        channels
+            .range((*did, 0)..=(*did, u8::MAX))
+            .map(|(_, c)| *c)
+            .max()
+            .unwrap_or(1)
+    };
+
+    let mut devices = Vec::with_capacity(order.len());
+    let mut node_to_device = BTreeMap::new();
+    let mut clocks = Vec::new();
+    let mut audio_ins = Vec::new();
+    let mut sequencers = Vec::new();
+    for did in &order {
+        let (kind, ref params) = graph[did];
+        let ds: Vec<_> = match kind {
+            DeviceKind::Merge | DeviceKind::Split => Vec::new(),
+            _ => (0..width(did))
+                .map(|_| kind.make()(&mut devices, sample_rate))
+                .collect(),
+        };
+        match kind {
+            DeviceKind::Clock => clocks.extend(ds.iter().map(|d| *d as u16)),
+            DeviceKind::AudioIn => audio_ins.extend(ds.iter().map(|d| *d as u16)),
+            _ => (),
+        }
+        let clocked = params.contains_key(&sequencer::CLOCK);
+        sequencers.extend(
+            ds.iter()
+                .filter(|d| devices[**d].as_any().is::<Sequencer>())
+                .map(|d| (*d as u16, clocked)),
+        );
+        node_to_device.insert(*did, ds);
+    }
+
+    let mut layout = Layout::default();
+    for (_, params) in graph.values() {
+        for source in params.values() {
+            let (kind, _) = graph[&source.0];
+            if matches!(kind, DeviceKind::Merge | DeviceKind::Split)
+                || layout.slots.contains_key(source)
+            {
+                continue;
+            }
+            let slots = Slots {
+                channels: (0..channels[source]).map(|_| layout.alloc()).collect(),
+                mix: None,
+            };
+            layout.slots.insert(*source, slots);
+        }
+    }
+    // Merges and splits just point their outputs at the slots feeding them
+    for did in &order {
+        match &graph[did] {
+            (DeviceKind::Merge, params) => {
+                let inputs = MERGE_OUTPUT as usize;
+                let channels = (0..channels[&(*did, MERGE_OUTPUT)].min(inputs))
+                    .map(|c| match params.get(&(c as u8)) {
+                        Some(source) => layout.mix(*source),
+                        None => layout.silent(),
+                    })
+                    .collect();
+                let slots = Slots {
+                    channels,
+                    mix: None,
+                };
+                layout.slots.insert((*did, MERGE_OUTPUT), slots);
+            }
+            (DeviceKind::Split, params) => {
+                for c in 0..POLY_CHANNELS {
+                    let slot = params
+                        .get(&0)
+                        .and_then(|source| layout.slots.get(source))
+                        .and_then(|slots| slots.channels.get(c).copied());
+                    let slot = slot.unwrap_or_else(|| layout.silent());
+                    let slots = Slots {
+                        channels: vec![slot],
+                        mix: None,
+                    };
+                    layout.slots.insert((*did, c as u8 + 1), slots);
+                }
+            }
+            _ => (),
+        }
+    }
+    // Sums have to exist before the code computing them is generated
+    for (kind, params) in graph.values() {
+        for (pid, source) in params {
+            if !kind.accepts_poly(*pid) {
+                layout.mix(*source);
+            }
         }
     }
 
-    for ((nid, oid), params) in output_params.into_values().rev() {
-        code.push_back(Op::Sample(node_to_device[&nid] as u16, oid));
-        for (puid, pid) in params {
-            code.push_back(Op::Parametrise(node_to_device[&puid] as u16, pid));
+    let mut code = Vec::new();
+    let mut block_code = Vec::new();
+    for did in &order {
+        let (kind, params) = &graph[did];
+        for (channel, &d) in node_to_device[did].iter().enumerate() {
+            let d = d as u16;
+            let mut ins = Vec::new();
+            let mut outs = Vec::new();
+            for (pid, source) in params {
+                let slot = layout.read(*source, *kind, *pid, channel);
+                code.push(Op::Parametrise(d, *pid, slot));
+                ins.push((*pid, slot));
+            }
+            for ((_, oid), slots) in layout.slots.range((*did, 0)..=(*did, u8::MAX)) {
+                code.push(Op::Sample(d, *oid, slots.channels[channel]));
+                outs.push((*oid, slots.channels[channel]));
+            }
+            block_code.push(BlockOp::Process {
+                device: d,
+                ins,
+                outs,
+            });
+        }
+        for (_, slots) in layout.slots.range((*did, 0)..=(*did, u8::MAX)) {
+            if let Some(mix) = slots.mix {
+                code.push(Op::Mix(slots.channels.clone(), mix));
+                block_code.push(BlockOp::Mix(slots.channels.clone(), mix));
+            }
         }
     }
 
-    code.push_back(Op::Output);
+    let mut ends = Vec::with_capacity(ctl_graph.ends.len());
+    let mut midi_outs = Vec::new();
+    for did in &ctl_graph.ends {
+        let (Some(ds), Some((kind, params))) = (node_to_device.get(did), graph.get(did)) else {
+            continue;
+        };
+        if let DeviceKind::MidiOut = kind {
+            midi_outs.push(ds[0] as u16);
+            continue;
+        }
+        let Some(channel_params) = kind.channel_params() else {
+            continue;
+        };
+        let d = ds[0] as u16;
+        code.push(Op::Output(d, channel_params));
+        ends.push(End {
+            device: d,
+            params: channel_params,
+            slots: channel_params.map(|param| {
+                params
+                    .get(&param)
+                    .map(|source| layout.read(*source, *kind, param, 0))
+            }),
+        });
+    }
+    let max_outs = block_code
+        .iter()
+        .map(|op| match op {
+            BlockOp::Process { outs, .. } => outs.len(),
+            BlockOp::Mix(..) => 0,
+        })
+        .max()
+        .unwrap_or(0);
+    let slot_count = layout.len as usize;
 
-    let code = code.into();
-    ByteCode {
+    let mut program = ByteCode {
         devices,
         node_to_device,
         code,
-        sample: 0.0,
-    }
+        values: vec![0.0; slot_count],
+        buffers: vec![vec![0.0; MAX_BLOCK]; slot_count],
+        scratch: Vec::with_capacity(max_outs),
+        slots: layout.slots,
+        sample: [0.0; CHANNELS],
+        block_code,
+        ends,
+        has_feedback: !ctl_graph.feedback.is_empty(),
+        midis: ctl_graph
+            .midis
+            .values()
+            .map(|(did, _)| *did)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|device| MidiRoute {
+                device,
+                filter: MidiFilter::default(),
+                voices: VoiceAllocator::new(ctl_graph.polyphony).with_tuning(&ctl_graph.tuning),
+            })
+            .collect(),
+        midi_outs,
+        freqs: ctl_graph.tuning.freqs(),
+        transport: Transport::new(sample_rate),
+        clocks,
+        audio_ins,
+        sequencers,
+    };
+    program.sync_sequencers();
+    program.sync_midi_outs();
+    program
 }
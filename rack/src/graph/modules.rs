@@ -8,6 +8,7 @@ use eframe::{
     epaint::Rect,
 };
 use egui::{
+    DragValue,
     InnerResponse,
     Pos2,
 };
@@ -24,6 +25,7 @@ use crate::{
         Param,
     },
     graph::Graph,
+    midi::CcMapping,
     module_description::ModuleDescription,
     visuals::{
         templates::WidgetTemplate,
@@ -46,33 +48,43 @@ use super::{
 };
 
 pub struct Module {
+    /// Description this module was instantiated from
+    pub description: ModuleDescription,
     pub size: ModuleSize,
     pub devices: Vec<DeviceId>,
     pub visuals: SlotMap<VisualId, SlotWidget>,
+    /// Maps visual indices of the description to visuals
+    pub visual_ids: BTreeMap<usize, VisualId>,
     pub theme: VisualTheme,
     pub values: SecondaryMap<VisualId, Connector>,
     /// Maps inputs to their visuals
     pub ins: SecondaryMap<InputId, VisualId>,
     /// Maps outputs to their visuals
     pub outs: SecondaryMap<OutputId, VisualId>,
+    /// MIDI controllers driving knobs and toggles
+    pub mappings: SecondaryMap<VisualId, CcMapping>,
 }
 
 impl std::fmt::Debug for Module {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Module")
+            .field("name", &self.description.name)
             .field("size", &self.size)
             .field("devices", &self.devices)
             .field("visuals_count", &self.visuals.len())
             .field("values", &self.values)
             .field("ins", &self.ins)
             .field("outs", &self.outs)
+            .field("mappings", &self.mappings)
             .finish()
     }
 }
 
 impl Module {
+    #[allow(clippy::too_many_arguments)]
     fn insert_new(
         graph: &mut Graph,
+        description: ModuleDescription,
         size: ModuleSize,
         visual_descs: BTreeMap<usize, WidgetTemplate>,
         theme: VisualTheme,
@@ -91,48 +103,44 @@ impl Module {
         let devices = devices
             .into_iter()
             .map(|(di, device)| {
-                graph.devices.insert_with_key(|did| {
-                    let params = device.params();
-                    for (pi, param) in params.iter().enumerate() {
-                        match param {
-                            Param::In(_) => {
-                                let param = graph.ins.insert((did, pi as u8));
-                                graph.dev_ins.entry(did).unwrap().or_default().push(param);
-                                if let Some(vi) = connections.iter().find_map(|(vi, (cdi, cpi))| {
-                                    (*cdi == di && *cpi == pi).then_some(*vi)
-                                }) {
-                                    let vid = visual_ids[&vi];
-                                    values.insert(vid, Connector::In(param));
-                                    ins.insert(param, vid);
-                                }
-                            }
-                            Param::Out(_) => {
-                                let param = graph.outs.insert((did, pi as u8));
-                                graph.dev_outs.entry(did).unwrap().or_default().push(param);
-                                if let Some(vi) = connections.iter().find_map(|(vi, (cdi, cpi))| {
-                                    (*cdi == di && *cpi == pi).then_some(*vi)
-                                }) {
-                                    let vid = visual_ids[&vi];
-                                    values.insert(vid, Connector::Out(param));
-                                    outs.insert(param, vid);
-                                }
-                            }
+                let did = graph.insert_device(device);
+                for (pi, param) in device.params().iter().enumerate() {
+                    let Some(vi) = connections
+                        .iter()
+                        .find_map(|(vi, (cdi, cpi))| (*cdi == di && *cpi == pi).then_some(*vi))
+                    else {
+                        continue;
+                    };
+                    let vid = visual_ids[&vi];
+                    match param {
+                        Param::In(_) => {
+                            let param = graph.input_of(did, pi as u8).unwrap();
+                            values.insert(vid, Connector::In(param));
+                            ins.insert(param, vid);
+                        }
+                        Param::Out(_) => {
+                            let param = graph.output_of(did, pi as u8).unwrap();
+                            values.insert(vid, Connector::Out(param));
+                            outs.insert(param, vid);
                         }
                     }
+                }
 
-                    device
-                })
+                did
             })
             .collect();
 
         graph.modules.insert(Self {
+            description,
             size,
             devices,
             visuals,
+            visual_ids,
             theme,
             values,
             ins,
             outs,
+            mappings: SecondaryMap::default(),
         })
     }
 
@@ -145,8 +153,35 @@ impl Module {
             visuals,
             devices,
             connections,
-        } = description;
-        Self::insert_new(graph, size, visuals, theme, devices, connections)
+        } = description.clone();
+        Self::insert_new(
+            graph,
+            description,
+            size,
+            visuals,
+            theme,
+            devices,
+            connections,
+        )
+    }
+
+    /// Device of this module with index `di` in its description
+    pub fn device(&self, di: usize) -> Option<DeviceId> {
+        self.description
+            .devices
+            .keys()
+            .position(|k| *k == di)
+            .and_then(|i| self.devices.get(i))
+            .copied()
+    }
+
+    /// Index of `did` in this module's description
+    pub fn device_index(&self, did: DeviceId) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| *d == did)
+            .and_then(|i| self.description.devices.keys().nth(i))
+            .copied()
     }
 
     fn ui_for(&mut self, position: Pos2, ui: &mut Ui) -> ModuleResponse {
@@ -170,6 +205,13 @@ impl Module {
                         }
                     }
                 }
+                if !matches!(w, SlotWidget::Port(_)) {
+                    response.context_menu(|ui| {
+                        if self.mapping_menu(vid, ui) {
+                            module_res = ModuleResponse::Learn(vid);
+                        }
+                    });
+                }
                 response.on_hover_text(w.tooltip())
             });
         }
@@ -177,6 +219,33 @@ impl Module {
         module_res
     }
 
+    /// Context menu of a knob or toggle, returns whether it should learn a controller
+    fn mapping_menu(&mut self, vid: VisualId, ui: &mut Ui) -> bool {
+        if let Some(mapping) = self.mappings.get_mut(vid) {
+            let bits = if mapping.fine { 14 } else { 7 };
+            ui.label(format!(
+                "CC {} on channel {}, {bits} bit",
+                mapping.cc,
+                mapping.channel + 1
+            ));
+            ui.horizontal(|ui| {
+                ui.label("Min");
+                ui.add(DragValue::new(&mut mapping.min).speed(0.01));
+                ui.label("Max");
+                ui.add(DragValue::new(&mut mapping.max).speed(0.01));
+            });
+            if ui.button("Forget MIDI mapping").clicked() {
+                self.mappings.remove(vid);
+                ui.close_menu();
+            }
+        }
+        let learn = ui.button("MIDI learn").clicked();
+        if learn {
+            ui.close_menu();
+        }
+        learn
+    }
+
     pub fn show(&mut self, ui: &mut Ui) -> InnerResponse<ModuleResponse> {
         let response = ui.allocate_response(self.size.size(), Sense::click_and_drag());
 
@@ -191,4 +260,6 @@ pub enum ModuleResponse {
     Changed(Connector, f32),
     AttemptConnection(Connector),
     AttemptDisconnect(Connector),
+    /// Map the next MIDI controller that moves to this knob or toggle
+    Learn(VisualId),
 }
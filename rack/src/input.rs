@@ -0,0 +1,145 @@
+//! Audio coming in from an interface running on a clock of its own
+
+use std::{
+    mem,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+};
+
+use crate::{
+    graph::compiled::{
+        Frame,
+        CHANNELS,
+    },
+    spsc::Consumer,
+};
+
+/// Most the reading rate gets bent to catch up with the writing side
+const MAX_DRIFT: f64 = 0.005;
+/// How much the reading rate gets bent for every target's worth of frames
+/// buffered above or below the target
+const DRIFT_GAIN: f64 = 0.002;
+/// How much of the gap to the latest fill level the smoothed one closes on
+/// every block
+const FILL_SMOOTHING: f64 = 0.01;
+/// How many targets' worth of frames can pile up, say while the output
+/// stream stalled, before the oldest ones get dropped
+const MAX_FILL: usize = 4;
+
+/// Frames from an input stream, read at the pace of the output stream.
+///
+/// The two streams run on clocks of their own, which drift apart however
+/// close their sample rates are. Frames wait in a ring buffer and are read
+/// a little faster when it fills past `target` and a little slower when it
+/// runs low, interpolating between them.
+pub struct InputResampler {
+    frames: Consumer<Frame>,
+    /// Input frames per output frame, if the clocks agreed
+    ratio: f64,
+    /// Input frames per output frame, bent by the drift
+    step: f64,
+    /// Frames kept buffered, as latency against the input arriving in bursts
+    target: f64,
+    /// Frames buffered, smoothed over many blocks
+    fill: f64,
+    /// Waiting for the buffer to fill up to `target` before reading from it
+    priming: bool,
+    /// Frames before and after the read position, and how far between them it is
+    prev: Frame,
+    next: Frame,
+    phase: f64,
+    /// Times it ran dry, counted here since the audio thread can't log
+    underruns: Arc<AtomicU64>,
+}
+
+impl InputResampler {
+    /// Reads `frames` written at `input_rate` at `output_rate`, keeping
+    /// `target` frames buffered
+    pub fn new(frames: Consumer<Frame>, input_rate: f32, output_rate: f32, target: usize) -> Self {
+        let ratio = f64::from(input_rate) / f64::from(output_rate);
+        Self {
+            frames,
+            ratio,
+            step: ratio,
+            target: target.max(1) as f64,
+            fill: target as f64,
+            priming: true,
+            prev: [0.0; CHANNELS],
+            next: [0.0; CHANNELS],
+            phase: 0.0,
+            underruns: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// How many times it ran dry so far, to be read from another thread
+    pub fn underruns(&self) -> Arc<AtomicU64> {
+        self.underruns.clone()
+    }
+
+    /// Input frames read per output frame right now
+    pub fn step(&self) -> f64 {
+        self.step
+    }
+
+    /// Whether it's waiting for the buffer to fill up, playing silence
+    pub fn is_priming(&self) -> bool {
+        self.priming
+    }
+
+    /// Fills `out` with the next frames
+    pub fn read(&mut self, out: &mut [Frame]) {
+        let mut buffered = self.frames.len();
+        let target = self.target as usize;
+        if buffered > target * MAX_FILL {
+            while buffered > target && self.frames.pop().is_some() {
+                buffered -= 1;
+            }
+            self.fill = buffered as f64;
+        }
+        if self.priming {
+            if buffered < target {
+                out.fill([0.0; CHANNELS]);
+                return;
+            }
+            self.priming = false;
+            self.fill = buffered as f64;
+            self.phase = 0.0;
+            self.next = self.frames.pop().unwrap_or_default();
+            self.advance();
+        }
+
+        self.fill += (buffered as f64 - self.fill) * FILL_SMOOTHING;
+        let drift = (self.fill - self.target) / self.target * DRIFT_GAIN;
+        self.step = self.ratio * (1.0 + drift.clamp(-MAX_DRIFT, MAX_DRIFT));
+
+        for i in 0..out.len() {
+            let t = self.phase as f32;
+            out[i] = std::array::from_fn(|c| self.prev[c] + (self.next[c] - self.prev[c]) * t);
+            self.phase += self.step;
+            while self.phase >= 1.0 {
+                self.phase -= 1.0;
+                if !self.advance() {
+                    // Ran dry, so wait for the buffer to fill up again
+                    self.underruns.fetch_add(1, Ordering::Relaxed);
+                    self.priming = true;
+                    out[i + 1..].fill([0.0; CHANNELS]);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Moves on to the next frame, unless there's none yet
+    fn advance(&mut self) -> bool {
+        let Some(next) = self.frames.pop() else {
+            return false;
+        };
+        self.prev = mem::replace(&mut self.next, next);
+        true
+    }
+}
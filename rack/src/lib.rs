@@ -28,8 +28,16 @@ use visuals::templates::WidgetTemplate;
 
 pub mod container;
 pub mod devices;
+pub mod engine;
 pub mod graph;
+pub mod input;
+pub mod midi;
 pub mod module_description;
+pub mod patch;
+pub mod record;
+pub mod spsc;
+pub mod transport;
+pub mod tuning;
 pub mod visuals;
 pub mod widgets;
 
@@ -0,0 +1,556 @@
+use midly::{
+    live::LiveEvent,
+    num::{
+        u15,
+        u28,
+    },
+    Format,
+    Header,
+    MetaMessage,
+    Smf,
+    Timing,
+    TrackEvent,
+    TrackEventKind,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use wmidi::{
+    Channel,
+    ControlFunction,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+use crate::tuning::{
+    NoteFreqs,
+    Tuning,
+};
+
+/// Tempo of a MIDI file until it says otherwise, in microseconds per beat
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// How many copies of a patch's voice section get played at once.
+///
+/// The voice section is every device fed by a `MidiControl`, directly or not,
+/// up to but excluding the output devices, which get the sum of all voices.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Polyphony {
+    pub voices: usize,
+    /// Voices each note is played by
+    pub unison: usize,
+    /// Spread between the lowest and highest unison voice, in cents
+    pub detune: f32,
+}
+
+impl Default for Polyphony {
+    fn default() -> Self {
+        Self {
+            voices: 1,
+            unison: 1,
+            detune: 10.0,
+        }
+    }
+}
+
+/// `MidiControl` parameters set by [`VoiceAllocator`]
+pub const NOTE: u8 = 0;
+pub const TRIGGER: u8 = 1;
+pub const VELOCITY: u8 = 2;
+/// From -1 to 1, scaled by the device's bend range
+pub const BEND: u8 = 3;
+pub const PRESSURE: u8 = 4;
+pub const AFTERTOUCH: u8 = 5;
+pub const MOD_WHEEL: u8 = 6;
+
+/// `MidiControl` inputs choosing which messages it plays, see [`MidiFilter`]
+pub const CHANNEL: u8 = 8;
+pub const PORT: u8 = 9;
+
+/// Which messages a `MidiControl` listens to
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MidiFilter {
+    /// Zero based MIDI channel, or every channel
+    pub channel: Option<u8>,
+    /// Index of the input port, or every port
+    pub port: Option<usize>,
+}
+
+impl MidiFilter {
+    /// Updates the filter from the `MidiControl` input `param`, counting
+    /// channels and ports from 1 with 0 meaning all of them
+    pub fn set_param(&mut self, param: u8, value: f32) {
+        let n = value.round().max(0.0) as usize;
+        match param {
+            CHANNEL => self.channel = n.checked_sub(1).map(|c| c.min(15) as u8),
+            PORT => self.port = n.checked_sub(1),
+            _ => (),
+        }
+    }
+
+    /// Whether `msg` from input port `port` should be played.
+    ///
+    /// Messages without a channel get through to everyone.
+    pub fn accepts(&self, port: usize, msg: &MidiMessage) -> bool {
+        let channel = msg.channel().map(|c| c.index());
+        self.port.is_none_or(|p| p == port)
+            && self.channel.is_none_or(|c| channel.is_none_or(|m| m == c))
+    }
+}
+
+/// 7 bit MIDI value scaled to 0 to 1
+fn unit(value: U7) -> f32 {
+    f32::from(u8::from(value)) / 127.0
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Voice {
+    note: Option<Note>,
+    freq: f32,
+    held: bool,
+    /// When the voice was last started or released
+    since: u64,
+}
+
+/// Hands notes out to voices.
+///
+/// Free voices are taken first, then the ones released the longest ago, so
+/// release tails get as long as possible to ring out. Once every voice is
+/// held, the oldest note is stolen.
+#[derive(Clone, Debug)]
+pub struct VoiceAllocator {
+    voices: Vec<Voice>,
+    unison: usize,
+    detune: f32,
+    freqs: NoteFreqs,
+    /// Counts events, to tell which voice changed last
+    clock: u64,
+}
+
+impl VoiceAllocator {
+    pub fn new(polyphony: Polyphony) -> Self {
+        let voices = polyphony.voices.max(1);
+        Self {
+            voices: vec![Voice::default(); voices],
+            unison: polyphony.unison.clamp(1, voices),
+            detune: polyphony.detune,
+            freqs: Tuning::default().freqs(),
+            clock: 0,
+        }
+    }
+
+    /// Plays notes at the frequencies `tuning` gives them
+    pub fn with_tuning(mut self, tuning: &Tuning) -> Self {
+        self.freqs = tuning.freqs();
+        self
+    }
+
+    pub fn voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Takes over which notes `old` was playing, as far as the voices go
+    pub fn carry_state_from(&mut self, old: &VoiceAllocator) {
+        for (voice, old) in self.voices.iter_mut().zip(&old.voices) {
+            *voice = *old;
+        }
+        self.clock = old.clock;
+    }
+
+    /// Updates voices for `msg`, calling `set` with the voice, the `MidiControl`
+    /// parameter and its new value for everything that changed
+    pub fn handle(&mut self, msg: &MidiMessage, mut set: impl FnMut(usize, u8, f32)) {
+        self.clock += 1;
+        match *msg {
+            MidiMessage::NoteOn(_, n, v) if u8::from(v) > 0 => self.note_on(n, v, &mut set),
+            MidiMessage::NoteOn(_, n, _) | MidiMessage::NoteOff(_, n, _) => {
+                for (i, voice) in self.voices.iter_mut().enumerate() {
+                    if voice.held && voice.note == Some(n) {
+                        voice.held = false;
+                        voice.since = self.clock;
+                        set(i, TRIGGER, 0.0);
+                    }
+                }
+            }
+            MidiMessage::PolyphonicKeyPressure(_, n, v) => {
+                for (i, voice) in self.voices.iter().enumerate() {
+                    if voice.note == Some(n) {
+                        set(i, AFTERTOUCH, unit(v));
+                    }
+                }
+            }
+            MidiMessage::PitchBendChange(_, v) => {
+                // The centre is 8192, which leaves one step less above it than below
+                let offset = f32::from(u16::from(v)) - 8192.0;
+                let bend = offset / if offset > 0.0 { 8191.0 } else { 8192.0 };
+                self.set_all(BEND, bend, &mut set);
+            }
+            MidiMessage::ChannelPressure(_, v) => self.set_all(PRESSURE, unit(v), &mut set),
+            MidiMessage::ControlChange(_, ControlFunction::MODULATION_WHEEL, v) => {
+                self.set_all(MOD_WHEEL, unit(v), &mut set)
+            }
+            _ => (),
+        }
+    }
+
+    /// Channel wide messages go to every voice
+    fn set_all(&self, param: u8, value: f32, set: &mut impl FnMut(usize, u8, f32)) {
+        for i in 0..self.voices.len() {
+            set(i, param, value);
+        }
+    }
+
+    fn note_on(&mut self, note: Note, velocity: U7, set: &mut impl FnMut(usize, u8, f32)) {
+        // Keys the tuning leaves unmapped don't play
+        let Some(freq) = self.freqs[note as usize] else {
+            return;
+        };
+        for k in 0..self.unison {
+            let Some((i, _)) = self
+                .voices
+                .iter()
+                .enumerate()
+                // Already taken by this note
+                .filter(|(_, v)| v.since != self.clock)
+                .min_by_key(|(_, v)| {
+                    let rank = if v.note == Some(note) {
+                        0
+                    } else if !v.held {
+                        1
+                    } else {
+                        2
+                    };
+                    (rank, v.since)
+                })
+            else {
+                return;
+            };
+
+            let cents = if self.unison > 1 {
+                self.detune * (k as f32 / (self.unison - 1) as f32 - 0.5)
+            } else {
+                0.0
+            };
+            let voice = &mut self.voices[i];
+            *voice = Voice {
+                note: Some(note),
+                freq: freq * (cents / 1200.0).exp2(),
+                held: true,
+                since: self.clock,
+            };
+            set(i, NOTE, voice.freq);
+            set(i, VELOCITY, unit(velocity));
+            set(i, TRIGGER, 1.0);
+        }
+    }
+}
+
+/// MIDI message along with the input port it came in through and when
+#[derive(Clone, Debug)]
+pub struct MidiEvent {
+    /// Index of the port among the ones opened
+    pub port: usize,
+    /// Microseconds, as stamped by the port
+    pub time: u64,
+    pub msg: MidiMessage<'static>,
+}
+
+/// Hardware controller driving a knob or toggle
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CcMapping {
+    /// Zero based MIDI channel
+    pub channel: u8,
+    /// Controller number, of the most significant half for 14 bit controllers
+    pub cc: u8,
+    /// Whether the least significant half arrives on controller `cc + 32`
+    pub fine: bool,
+    /// Widget value with the controller all the way down
+    pub min: f32,
+    /// Widget value with the controller all the way up
+    pub max: f32,
+    #[serde(skip)]
+    msb: u8,
+    #[serde(skip)]
+    lsb: u8,
+}
+
+impl CcMapping {
+    /// Mapping for controller `cc` as a plain 7 bit controller, until its
+    /// 14 bit partner turns up
+    pub fn new(channel: u8, cc: u8, min: f32, max: f32) -> Self {
+        Self {
+            channel,
+            cc,
+            fine: false,
+            min,
+            max,
+            msb: 0,
+            lsb: 0,
+        }
+    }
+
+    /// Widget value after controller `cc` on `channel` moved to `value`,
+    /// if that controller is mapped here
+    pub fn handle(&mut self, channel: u8, cc: u8, value: u8) -> Option<f32> {
+        if channel != self.channel {
+            return None;
+        }
+        if cc == self.cc {
+            self.msb = value;
+            // Per the spec, a new coarse value resets the fine one
+            self.lsb = 0;
+        } else if self.cc < 32 && cc == self.cc + 32 {
+            // Controllers sending both halves turn out to be 14 bit
+            self.fine = true;
+            self.lsb = value;
+        } else if !self.fine && (32..64).contains(&self.cc) && cc == self.cc - 32 {
+            // Learned from the fine half, and the coarse one just showed up
+            self.cc = cc;
+            self.fine = true;
+            self.msb = value;
+            self.lsb = 0;
+        } else {
+            return None;
+        }
+
+        let t = if self.fine {
+            f32::from(u16::from(self.msb) << 7 | u16::from(self.lsb)) / 16383.0
+        } else {
+            f32::from(self.msb) / 127.0
+        };
+        Some(self.min + t * (self.max - self.min))
+    }
+}
+
+/// Channel messages of a standard MIDI file, merged across tracks and timed in samples.
+///
+/// Tempo changes are followed, meta and system exclusive events are skipped.
+pub fn load_smf(
+    bytes: &[u8],
+    sample_rate: f32,
+) -> Result<Vec<(u64, MidiMessage<'static>)>, midly::Error> {
+    let smf = Smf::parse(bytes)?;
+
+    let mut events = Vec::new();
+    for track in &smf.tracks {
+        let mut tick = 0u64;
+        for event in track {
+            tick += u64::from(event.delta.as_int());
+            events.push((tick, event.kind));
+        }
+    }
+    // Stable, so events on the same tick keep their track order
+    events.sort_by_key(|(tick, _)| *tick);
+
+    let mut tempo = DEFAULT_TEMPO;
+    let mut last_tick = 0;
+    let mut seconds = 0.0f64;
+    let mut res = Vec::new();
+    for (tick, kind) in events {
+        let seconds_per_tick = match smf.header.timing {
+            Timing::Metrical(tpb) => f64::from(tempo) / 1e6 / f64::from(tpb.as_int()),
+            Timing::Timecode(fps, subframes) => {
+                1.0 / (f64::from(fps.as_f32()) * f64::from(subframes))
+            }
+        };
+        seconds += (tick - last_tick) as f64 * seconds_per_tick;
+        last_tick = tick;
+
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => tempo = t.as_int(),
+            TrackEventKind::Midi { .. } => {
+                let mut raw = Vec::with_capacity(3);
+                let live = kind
+                    .as_live_event()
+                    .expect("Channel messages are live events");
+                live.write_std(&mut raw)
+                    .expect("Writing to a Vec can't fail");
+                if let Ok(msg) = MidiMessage::try_from(&raw[..]) {
+                    let time = (seconds * f64::from(sample_rate)).round() as u64;
+                    res.push((time, msg.to_owned()));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(res)
+}
+
+/// Ticks per beat of the files written by [`write_smf`]
+const EXPORT_TICKS_PER_BEAT: u16 = 480;
+
+/// Standard MIDI file holding `events`, timed in samples.
+///
+/// The file keeps to the default tempo of 120 BPM, and only channel messages
+/// make it in. Events don't need to be in order, they're sorted by time with
+/// ones at the same time keeping theirs.
+pub fn write_smf(events: &[(u64, MidiMessage)], sample_rate: f32) -> Vec<u8> {
+    let mut raw: Vec<_> = events
+        .iter()
+        .map(|(time, msg)| {
+            let mut bytes = vec![0; msg.bytes_size()];
+            msg.copy_to_slice(&mut bytes)
+                .expect("Sized to fit the message");
+            (*time, bytes)
+        })
+        .collect();
+    // Stable, like in `load_smf`
+    raw.sort_by_key(|(time, _)| *time);
+
+    let ticks_per_second = f64::from(EXPORT_TICKS_PER_BEAT) * 1e6 / f64::from(DEFAULT_TEMPO);
+    let mut track = Vec::with_capacity(raw.len() + 1);
+    let mut last_tick = 0;
+    for (time, bytes) in &raw {
+        let Ok(LiveEvent::Midi { channel, message }) = LiveEvent::parse(bytes) else {
+            continue;
+        };
+        let tick = (*time as f64 / f64::from(sample_rate) * ticks_per_second).round() as u32;
+        track.push(TrackEvent {
+            delta: u28::new(tick - last_tick),
+            kind: TrackEventKind::Midi { channel, message },
+        });
+        last_tick = tick;
+    }
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    let smf = Smf {
+        header: Header::new(
+            Format::SingleTrack,
+            Timing::Metrical(u15::new(EXPORT_TICKS_PER_BEAT)),
+        ),
+        tracks: vec![track],
+    };
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes)
+        .expect("Writing to a Vec can't fail");
+    bytes
+}
+
+/// Plays a standard MIDI file in real time, with a transport to start, stop,
+/// loop and seek.
+///
+/// Playback moves on by however long the caller says passed, handing out
+/// the events due in that stretch along with how far into it they are.
+/// Notes still sounding get released whenever playback stops or jumps.
+#[derive(Clone, Debug)]
+pub struct SmfPlayer {
+    /// Timed in microseconds
+    events: Vec<(u64, MidiMessage<'static>)>,
+    /// Microseconds from the start of the file
+    position: u64,
+    /// First event not played yet
+    next: usize,
+    playing: bool,
+    /// Whether to start over once the end is reached
+    pub looping: bool,
+    held: Vec<(Channel, Note)>,
+}
+
+impl SmfPlayer {
+    pub fn new(bytes: &[u8]) -> Result<Self, midly::Error> {
+        Ok(Self {
+            events: load_smf(bytes, 1e6)?,
+            position: 0,
+            next: 0,
+            playing: false,
+            looping: false,
+            held: Vec::new(),
+        })
+    }
+
+    /// Microseconds until the last event
+    pub fn length(&self) -> u64 {
+        self.events.last().map_or(0, |(t, _)| *t)
+    }
+
+    /// Microseconds from the start of the file
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Starts playing, from the top if the end was reached
+    pub fn play(&mut self) {
+        if self.position >= self.length() {
+            self.position = 0;
+            self.next = 0;
+        }
+        self.playing = true;
+    }
+
+    /// Pauses where playback is, releasing every note
+    pub fn stop(&mut self, mut send: impl FnMut(u64, MidiMessage<'static>)) {
+        self.playing = false;
+        self.release(0, &mut send);
+    }
+
+    /// Jumps to `position` microseconds from the start, releasing every note
+    pub fn seek(&mut self, position: u64, mut send: impl FnMut(u64, MidiMessage<'static>)) {
+        self.release(0, &mut send);
+        self.position = position.min(self.length());
+        self.next = self.events.partition_point(|(t, _)| *t < self.position);
+    }
+
+    /// Moves playback on by `elapsed` microseconds, calling `send` with every
+    /// event due along with how many microseconds into `elapsed` it is
+    pub fn advance(&mut self, elapsed: u64, mut send: impl FnMut(u64, MidiMessage<'static>)) {
+        let length = self.length();
+        let mut done = 0;
+        while self.playing {
+            let end = self.position + (elapsed - done);
+            // Everything left is due once the end is reached
+            let finished = end >= length;
+            while let Some((t, msg)) = self.events.get(self.next) {
+                if *t >= end && !finished {
+                    break;
+                }
+                track_held(&mut self.held, msg);
+                send(done + (t - self.position), msg.clone());
+                self.next += 1;
+            }
+            if !finished {
+                self.position = end;
+                return;
+            }
+
+            done += length - self.position;
+            self.release(done, &mut send);
+            if !self.looping || length == 0 {
+                self.position = length;
+                self.playing = false;
+                return;
+            }
+            self.position = 0;
+            self.next = 0;
+        }
+    }
+
+    fn release(&mut self, at: u64, send: &mut impl FnMut(u64, MidiMessage<'static>)) {
+        for (channel, note) in self.held.drain(..) {
+            send(at, MidiMessage::NoteOff(channel, note, U7::MIN));
+        }
+    }
+}
+
+/// Keeps `held` up to date with the notes sounding after `msg`
+fn track_held(held: &mut Vec<(Channel, Note)>, msg: &MidiMessage) {
+    match *msg {
+        MidiMessage::NoteOn(c, n, v) if u8::from(v) > 0 => {
+            held.retain(|h| *h != (c, n));
+            held.push((c, n));
+        }
+        MidiMessage::NoteOn(c, n, _) | MidiMessage::NoteOff(c, n, _) => {
+            held.retain(|h| *h != (c, n));
+        }
+        _ => (),
+    }
+}
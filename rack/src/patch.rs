@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::{
+    midi::{
+        CcMapping,
+        Polyphony,
+    },
+    module_description::ModuleDescription,
+    transport::TransportSettings,
+    tuning::Tuning,
+};
+
+/// Serializable snapshot of a whole rack.
+///
+/// Slotmap keys don't survive a restart, so everything in here is referred to
+/// by its index: modules by their position in [`Patch::modules`], devices and
+/// visuals by their keys in the module's [`ModuleDescription`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Patch {
+    pub modules: Vec<PatchModule>,
+    pub cables: Vec<PatchCable>,
+    #[serde(default)]
+    pub polyphony: Polyphony,
+    #[serde(default)]
+    pub tuning: Tuning,
+    #[serde(default)]
+    pub transport: TransportSettings,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatchModule {
+    pub description: ModuleDescription,
+    /// Top left corner of the module in the rack, in units
+    pub position: (u8, u8),
+    /// Values of knobs and toggles, keyed by visual index
+    pub values: BTreeMap<usize, f32>,
+    /// MIDI controllers driving knobs and toggles, keyed by visual index
+    #[serde(default)]
+    pub mappings: BTreeMap<usize, CcMapping>,
+}
+
+/// A device parameter of a module in the patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PatchPort {
+    pub module: usize,
+    pub device: usize,
+    pub param: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchCable {
+    pub input: PatchPort,
+    pub output: PatchPort,
+}
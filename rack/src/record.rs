@@ -0,0 +1,246 @@
+//! Writing what the rack plays to disk, away from the audio thread
+
+use std::{
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    thread::{
+        self,
+        JoinHandle,
+    },
+    time::Duration,
+};
+
+use hound::{
+    SampleFormat,
+    WavSpec,
+    WavWriter,
+};
+use wmidi::MidiMessage;
+
+use crate::{
+    graph::compiled::{
+        Frame,
+        CHANNELS,
+    },
+    midi::write_smf,
+    spsc::{
+        self,
+        Consumer,
+        Producer,
+    },
+};
+
+/// Seconds of audio that can pile up while the disk is slow, before frames
+/// get dropped
+const BUFFER_SECONDS: f32 = 2.0;
+/// How long the writer thread sleeps when it has nothing to write
+const WRITE_POLL: Duration = Duration::from_millis(10);
+/// MIDI messages that can pile up between two polls of the writer thread
+const MIDI_CAPACITY: usize = 1024;
+
+/// Audio thread side of a [`Recorder`]
+pub struct RecordTap {
+    frames: Producer<Frame>,
+    dropped: Arc<AtomicU64>,
+    /// MIDI sent, timed in samples since the recording started
+    midi: Producer<(u64, MidiMessage<'static>)>,
+    midi_dropped: Arc<AtomicU64>,
+    /// Samples before the block last written
+    block_start: u64,
+    /// Samples up to the end of the block last written
+    block_end: u64,
+}
+
+impl RecordTap {
+    /// Queues `frames` for writing, dropping whatever doesn't fit
+    pub fn write(&mut self, frames: &[Frame]) {
+        self.block_start = self.block_end;
+        self.block_end += frames.len() as u64;
+        for (i, frame) in frames.iter().enumerate() {
+            if self.frames.push(*frame).is_err() {
+                let dropped = (frames.len() - i) as u64;
+                self.dropped.fetch_add(dropped, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+
+    /// Queues `msg` for the MIDI file, sent `offset` samples into the block
+    /// last written
+    pub fn write_midi(&mut self, offset: u64, msg: MidiMessage<'static>) {
+        if self.midi.push((self.block_start + offset, msg)).is_err() {
+            self.midi_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Thread writing the frames its [`RecordTap`] is fed to a WAV file, and
+/// the MIDI to a MIDI file next to it, if any was sent.
+///
+/// Stops and finishes the files once dropped, or with [`Recorder::finish`]
+/// to find out whether that worked.
+pub struct Recorder {
+    path: PathBuf,
+    sample_rate: f32,
+    stop: Arc<AtomicBool>,
+    written: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+    midi_dropped: Arc<AtomicU64>,
+    thread: Option<JoinHandle<hound::Result<()>>>,
+}
+
+impl Recorder {
+    /// Creates a 32 bit float stereo WAV file at `path`, returning the
+    /// writer thread along with what the audio thread feeds it through
+    pub fn start(path: impl AsRef<Path>, sample_rate: f32) -> hound::Result<(Self, RecordTap)> {
+        let path = path.as_ref().to_path_buf();
+        let spec = WavSpec {
+            channels: CHANNELS as u16,
+            sample_rate: sample_rate as u32,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let writer = WavWriter::create(&path, spec)?;
+
+        let capacity = (sample_rate * BUFFER_SECONDS) as usize;
+        let (tx, rx) = spsc::channel(capacity.max(1));
+        let (midi_tx, midi_rx) = spsc::channel(MIDI_CAPACITY);
+        let stop = Arc::new(AtomicBool::new(false));
+        let written = Arc::new(AtomicU64::new(0));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let midi_dropped = Arc::new(AtomicU64::new(0));
+        let thread = thread::Builder::new().name("recorder".into()).spawn({
+            let stop = stop.clone();
+            let written = written.clone();
+            let midi = MidiFile {
+                path: path.with_extension("mid"),
+                sample_rate,
+                rx: midi_rx,
+                events: Vec::new(),
+            };
+            move || write_frames(writer, rx, midi, &stop, &written)
+        })?;
+
+        let recorder = Self {
+            path,
+            sample_rate,
+            stop,
+            written,
+            dropped: dropped.clone(),
+            midi_dropped: midi_dropped.clone(),
+            thread: Some(thread),
+        };
+        let tap = RecordTap {
+            frames: tx,
+            dropped,
+            midi: midi_tx,
+            midi_dropped,
+            block_start: 0,
+            block_end: 0,
+        };
+        Ok((recorder, tap))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Seconds written so far
+    pub fn seconds(&self) -> f64 {
+        self.written.load(Ordering::Relaxed) as f64 / f64::from(self.sample_rate)
+    }
+
+    /// Frames that didn't fit in the buffer, and are missing from the file
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// MIDI messages that didn't fit in the buffer, and are missing from the
+    /// MIDI file
+    pub fn midi_dropped(&self) -> u64 {
+        self.midi_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes out whatever is still buffered and finishes the file
+    pub fn finish(mut self) -> hound::Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> hound::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(hound::Error::IoError(std::io::Error::other(
+                "recorder thread panicked",
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.join() {
+            log::warn!("Could not finish recording {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// MIDI sent during a recording, written out once it stops
+struct MidiFile {
+    path: PathBuf,
+    sample_rate: f32,
+    rx: Consumer<(u64, MidiMessage<'static>)>,
+    events: Vec<(u64, MidiMessage<'static>)>,
+}
+
+impl MidiFile {
+    fn collect(&mut self) {
+        while let Some(event) = self.rx.pop() {
+            self.events.push(event);
+        }
+    }
+
+    /// Writes the file, unless nothing was sent
+    fn finish(&self) -> hound::Result<()> {
+        if !self.events.is_empty() {
+            std::fs::write(&self.path, write_smf(&self.events, self.sample_rate))?;
+        }
+        Ok(())
+    }
+}
+
+fn write_frames(
+    mut writer: WavWriter<std::io::BufWriter<std::fs::File>>,
+    mut rx: Consumer<Frame>,
+    mut midi: MidiFile,
+    stop: &AtomicBool,
+    written: &AtomicU64,
+) -> hound::Result<()> {
+    loop {
+        // Read before draining, so nothing queued before the stop is left behind
+        let stopping = stop.load(Ordering::Relaxed);
+        while let Some(frame) = rx.pop() {
+            for sample in frame {
+                writer.write_sample(sample)?;
+            }
+            written.fetch_add(1, Ordering::Relaxed);
+        }
+        midi.collect();
+        if stopping {
+            writer.finalize()?;
+            return midi.finish();
+        }
+        thread::sleep(WRITE_POLL);
+    }
+}
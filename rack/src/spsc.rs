@@ -0,0 +1,129 @@
+//! Wait-free single producer, single consumer ring buffer.
+//!
+//! Neither side ever blocks or allocates after [`channel`] returns, which
+//! makes it safe to use from the audio callback.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+};
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Next slot to read, only written by the consumer
+    head: AtomicUsize,
+    /// Next slot to write, only written by the producer
+    tail: AtomicUsize,
+}
+
+// Every slot is accessed by exactly one side at a time, as handed over by `head` and `tail`
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    fn next(&self, i: usize) -> usize {
+        if i + 1 == self.slots.len() {
+            0
+        } else {
+            i + 1
+        }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let mut head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        while head != tail {
+            unsafe { self.slots[head].get_mut().assume_init_drop() };
+            head = self.next(head);
+        }
+    }
+}
+
+/// Sending half of a [`channel`]
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Receiving half of a [`channel`]
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+}
+
+/// Creates a channel holding up to `capacity` messages
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    // One slot always stays empty, to tell a full ring from an empty one
+    let slots = (0..capacity + 1)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let ring = Arc::new(Ring {
+        slots,
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            ring: Arc::clone(&ring),
+        },
+        Consumer { ring },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Queues `value`, handing it back if the channel is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let ring = &*self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let next = ring.next(tail);
+        if next == ring.head.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        unsafe { (*ring.slots[tail].get()).write(value) };
+        ring.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn is_full(&self) -> bool {
+        let ring = &*self.ring;
+        ring.next(ring.tail.load(Ordering::Relaxed)) == ring.head.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Takes the oldest message, if there is one
+    pub fn pop(&mut self) -> Option<T> {
+        let ring = &*self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        if head == ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*ring.slots[head].get()).assume_init_read() };
+        ring.head.store(ring.next(head), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let ring = &*self.ring;
+        ring.head.load(Ordering::Relaxed) == ring.tail.load(Ordering::Acquire)
+    }
+
+    /// Messages waiting, at least
+    pub fn len(&self) -> usize {
+        let ring = &*self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let tail = ring.tail.load(Ordering::Acquire);
+        if tail >= head {
+            tail - head
+        } else {
+            ring.slots.len() - head + tail
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use wmidi::{
+    MidiMessage,
+    U14,
+};
+
+/// Clock pulses per beat, as in MIDI clock
+pub const PPQN: u32 = 24;
+/// MIDI clocks slower than this are taken to have stopped and started again
+const MIN_FOLLOWED_TEMPO: f64 = 20.0;
+/// How much of the gap between the tempo followed and that of the latest
+/// clock gets closed on every clock, smoothing out jitter over about a beat
+const TEMPO_SMOOTHING: f64 = 1.0 / PPQN as f64;
+
+/// Tempo and meter of a rack, saved along with its patch
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TransportSettings {
+    /// Beats per minute
+    pub tempo: f32,
+    pub beats_per_bar: u32,
+    /// Where the second sixteenth of every pair lands within it, with 0.5
+    /// playing them straight and 2/3 as triplets
+    pub swing: f32,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            tempo: 120.0,
+            beats_per_bar: 4,
+            swing: 0.5,
+        }
+    }
+}
+
+/// What can be done to a transport while it runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportCommand {
+    /// Continues from where it stopped
+    Play,
+    Stop,
+    /// Goes back to the top, playing or not
+    Reset,
+}
+
+/// Where a rack takes its time from and whether it passes it on, which is
+/// up to the setup it plays in rather than the patch
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClockSync {
+    /// Input port to follow MIDI clock from, instead of running at the
+    /// transport's own tempo
+    pub follow: Option<usize>,
+    /// Whether to send MIDI clock, start, stop and song position
+    pub send: bool,
+}
+
+/// Position of the rack in time, shared by every `Clock` device in it
+#[derive(Clone, Copy, Debug)]
+pub struct Transport {
+    settings: TransportSettings,
+    sample_rate: f32,
+    playing: bool,
+    /// Beats since the top when the tempo last changed
+    anchor: f64,
+    /// Samples played since then, counted rather than summed up in beats so
+    /// that edges land on the same sample however long the rack has been running
+    elapsed: u64,
+}
+
+impl Transport {
+    /// Playing from the top, so that racks keep time without touching the transport
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            settings: Default::default(),
+            sample_rate,
+            playing: true,
+            anchor: 0.0,
+            elapsed: 0,
+        }
+    }
+
+    pub fn settings(&self) -> TransportSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: TransportSettings) {
+        if settings.tempo != self.settings.tempo {
+            self.anchor = self.position();
+            self.elapsed = 0;
+        }
+        self.settings = settings;
+    }
+
+    pub fn command(&mut self, command: TransportCommand) {
+        match command {
+            TransportCommand::Play => self.playing = true,
+            TransportCommand::Stop => self.playing = false,
+            TransportCommand::Reset => self.locate(0.0),
+        }
+    }
+
+    /// Jumps to `beats` since the top
+    pub fn locate(&mut self, beats: f64) {
+        self.anchor = beats;
+        self.elapsed = 0;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    /// Beats since the top
+    pub fn position(&self) -> f64 {
+        let minutes = self.elapsed as f64 / (60.0 * f64::from(self.sample_rate));
+        self.anchor + minutes * f64::from(self.settings.tempo)
+    }
+
+    /// Zero based bar and beat within it
+    pub fn bar_beat(&self) -> (u64, u32) {
+        let beats = self.position() as u64;
+        let per_bar = u64::from(self.settings.beats_per_bar.max(1));
+        (beats / per_bar, (beats % per_bar) as u32)
+    }
+
+    /// Moves `samples` on, if playing
+    pub fn advance(&mut self, samples: usize) {
+        if self.playing {
+            self.elapsed += samples as u64;
+        }
+    }
+
+    /// Position in beats with swing applied, which delays every other sixteenth
+    pub fn swung(&self) -> f64 {
+        let swing = f64::from(self.settings.swing.clamp(0.01, 0.99));
+        // Pairs of sixteenths, that is eighths
+        let eighths = self.position() * 2.0;
+        let within = eighths.fract();
+        let warped = if within < swing {
+            within / swing * 0.5
+        } else {
+            0.5 + (within - swing) / (1.0 - swing) * 0.5
+        };
+        (eighths.trunc() + warped) / 2.0
+    }
+}
+
+/// Keeps a transport in time with MIDI clock from another device, which
+/// sends [`PPQN`] clocks a beat along with start, stop and song position
+#[derive(Clone, Debug)]
+pub struct ClockFollower {
+    sample_rate: f64,
+    /// Sample the last clock came in on
+    last_clock: Option<u64>,
+    /// Samples between clocks, smoothed
+    interval: Option<f64>,
+    /// Beat the transport was last started or moved to
+    from: f64,
+    /// Clocks since then
+    clocks: u64,
+    /// Started or continued, so the next clock plays `from`
+    armed: bool,
+    /// Whether clocks move the transport on
+    running: bool,
+}
+
+impl ClockFollower {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            sample_rate: f64::from(sample_rate),
+            last_clock: None,
+            interval: None,
+            from: 0.0,
+            clocks: 0,
+            armed: false,
+            running: false,
+        }
+    }
+
+    /// Forgets everything heard so far, for following another port
+    pub fn reset(&mut self) {
+        *self = Self::new(self.sample_rate as f32);
+    }
+
+    /// Tempo of the clocks lately, once there were two of them
+    pub fn tempo(&self) -> Option<f32> {
+        let interval = self.interval?;
+        Some((60.0 * self.sample_rate / (interval * f64::from(PPQN))) as f32)
+    }
+
+    /// Moves `transport` along with `msg`, which came in on sample `now`.
+    ///
+    /// Returns whether `msg` was one of the messages followed.
+    pub fn handle(&mut self, now: u64, msg: &MidiMessage, transport: &mut Transport) -> bool {
+        match *msg {
+            MidiMessage::TimingClock => self.clock(now, transport),
+            MidiMessage::Start => {
+                // Playing starts on the next clock
+                self.from = 0.0;
+                self.clocks = 0;
+                self.armed = true;
+                self.running = false;
+                transport.command(TransportCommand::Stop);
+                transport.locate(0.0);
+            }
+            MidiMessage::Continue => {
+                self.armed = true;
+                self.running = false;
+            }
+            MidiMessage::Stop => {
+                self.from = self.position();
+                self.clocks = 0;
+                self.armed = false;
+                self.running = false;
+                transport.command(TransportCommand::Stop);
+                transport.locate(self.from);
+            }
+            MidiMessage::SongPositionPointer(sixteenths) => {
+                self.from = f64::from(u16::from(sixteenths)) / 4.0;
+                self.clocks = 0;
+                transport.locate(self.from);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Beat the clocks got to
+    fn position(&self) -> f64 {
+        self.from + self.clocks as f64 / f64::from(PPQN)
+    }
+
+    fn clock(&mut self, now: u64, transport: &mut Transport) {
+        let slowest = 60.0 * self.sample_rate / (MIN_FOLLOWED_TEMPO * f64::from(PPQN));
+        if let Some(last) = self.last_clock {
+            let latest = now.saturating_sub(last) as f64;
+            if latest > 0.0 && latest < slowest {
+                let interval = self.interval.get_or_insert(latest);
+                *interval += (latest - *interval) * TEMPO_SMOOTHING;
+            }
+        }
+        self.last_clock = Some(now);
+        if let Some(tempo) = self.tempo() {
+            transport.set_settings(TransportSettings {
+                tempo,
+                ..transport.settings()
+            });
+        }
+
+        if self.armed {
+            self.armed = false;
+            self.running = true;
+            transport.command(TransportCommand::Play);
+        } else if self.running {
+            self.clocks += 1;
+        } else {
+            return;
+        }
+        // Runs on at the tempo between clocks, and lines up again on every one
+        transport.locate(self.position());
+    }
+}
+
+/// Sends MIDI clock following a transport, so other devices can follow the rack
+#[derive(Clone, Debug, Default)]
+pub struct ClockSender {
+    /// Clock last sent, counted from the top
+    last_clock: Option<u64>,
+}
+
+impl ClockSender {
+    /// Messages telling others about `command`, which is about to be given
+    /// to `transport`
+    pub fn command(
+        &mut self,
+        command: TransportCommand,
+        transport: &Transport,
+        mut send: impl FnMut(MidiMessage<'static>),
+    ) {
+        let playing = transport.is_playing();
+        match command {
+            TransportCommand::Play if !playing => {
+                self.last_clock = None;
+                let position = transport.position();
+                if position == 0.0 {
+                    send(MidiMessage::Start);
+                } else {
+                    send(MidiMessage::SongPositionPointer(sixteenths(position)));
+                    send(MidiMessage::Continue);
+                }
+            }
+            TransportCommand::Stop if playing => send(MidiMessage::Stop),
+            TransportCommand::Reset => {
+                self.last_clock = None;
+                if playing {
+                    send(MidiMessage::Start);
+                } else {
+                    send(MidiMessage::SongPositionPointer(U14::MIN));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Clocks `transport` gets past over the next `len` samples, along with
+    /// how many samples in each one comes
+    pub fn clocks(
+        &mut self,
+        transport: &Transport,
+        len: usize,
+        mut send: impl FnMut(u64, MidiMessage<'static>),
+    ) {
+        let mut transport = *transport;
+        for i in 0..len {
+            if transport.is_playing() {
+                let clock = (transport.position() * f64::from(PPQN)) as u64;
+                if self.last_clock != Some(clock) {
+                    self.last_clock = Some(clock);
+                    send(i as u64, MidiMessage::TimingClock);
+                }
+            }
+            transport.advance(1);
+        }
+    }
+}
+
+/// Song position pointer for `beats`, rounded down to a sixteenth
+fn sixteenths(beats: f64) -> U14 {
+    U14::try_from((beats * 4.0) as u16).unwrap_or(U14::MAX)
+}
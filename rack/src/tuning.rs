@@ -0,0 +1,255 @@
+//! Scales and keyboard mappings in the Scala formats, see
+//! <https://www.huygens-fokker.org/scala/scl_format.html> and
+//! <https://www.huygens-fokker.org/scala/help.htm#mappings>
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Frequencies of every MIDI note, or `None` for keys left unmapped
+pub type NoteFreqs = [Option<f32>; 128];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TuningError {
+    /// File ended before everything it promised was there
+    MissingLine(&'static str),
+    /// Line that doesn't hold the value it should
+    Invalid(&'static str, String),
+    /// Scale without any degrees
+    EmptyScale,
+    /// The reference note isn't mapped to any degree, so there's nothing to tune to
+    UnmappedReference,
+}
+
+impl std::fmt::Display for TuningError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TuningError::MissingLine(what) => write!(f, "missing {what}"),
+            TuningError::Invalid(what, line) => write!(f, "invalid {what}: {line:?}"),
+            TuningError::EmptyScale => write!(f, "scale has no degrees"),
+            TuningError::UnmappedReference => write!(f, "reference note isn't mapped"),
+        }
+    }
+}
+
+impl std::error::Error for TuningError {}
+
+/// Lines that aren't comments, trimmed
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('!'))
+}
+
+/// First whitespace separated word of the next line, parsed
+fn next_value<'a, T: std::str::FromStr>(
+    lines: &mut impl Iterator<Item = &'a str>,
+    what: &'static str,
+) -> Result<T, TuningError> {
+    let line = lines.next().ok_or(TuningError::MissingLine(what))?;
+    line.split_whitespace()
+        .next()
+        .and_then(|word| word.parse().ok())
+        .ok_or_else(|| TuningError::Invalid(what, line.into()))
+}
+
+/// Musical scale, as a list of pitches repeating every period
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scale {
+    pub description: String,
+    /// Cents above the root of every degree after it, the last one being the
+    /// period the scale repeats at
+    pub cents: Vec<f64>,
+}
+
+impl Scale {
+    /// Splits the octave into `steps` equal steps
+    pub fn equal(steps: usize) -> Self {
+        Self {
+            description: format!("{steps} tone equal temperament"),
+            cents: (1..=steps)
+                .map(|s| s as f64 * 1200.0 / steps as f64)
+                .collect(),
+        }
+    }
+
+    /// Reads a Scala scale file
+    pub fn parse_scl(text: &str) -> Result<Self, TuningError> {
+        // Blank lines only count as the description
+        let mut lines = lines(text);
+        let description = lines
+            .next()
+            .ok_or(TuningError::MissingLine("description"))?
+            .to_string();
+        let mut lines = lines.filter(|line| !line.is_empty());
+        let count: usize = next_value(&mut lines, "number of notes")?;
+        if count == 0 {
+            return Err(TuningError::EmptyScale);
+        }
+
+        let cents = (0..count)
+            .map(|_| {
+                let line = lines.next().ok_or(TuningError::MissingLine("pitch"))?;
+                let word = line.split_whitespace().next().unwrap_or_default();
+                parse_pitch(word).ok_or_else(|| TuningError::Invalid("pitch", line.into()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { description, cents })
+    }
+
+    /// Cents above the root of `degree`, which may lie beyond the first period
+    fn degree_cents(&self, degree: i64) -> f64 {
+        let len = self.cents.len() as i64;
+        let period = self.cents[self.cents.len() - 1];
+        let (periods, step) = (degree.div_euclid(len), degree.rem_euclid(len));
+        let within = match step {
+            0 => 0.0,
+            step => self.cents[step as usize - 1],
+        };
+        periods as f64 * period + within
+    }
+}
+
+/// Pitch of a scale degree in cents, which have a period in them, or as a ratio
+fn parse_pitch(word: &str) -> Option<f64> {
+    if word.contains('.') {
+        return word.parse().ok();
+    }
+    let (num, den) = word.split_once('/').unwrap_or((word, "1"));
+    let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+    (num > 0.0 && den > 0.0).then(|| 1200.0 * (num / den).log2())
+}
+
+/// Which key plays which degree of a scale, and the pitch it's all tuned to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyboardMap {
+    /// Lowest and highest key played
+    pub first: u8,
+    pub last: u8,
+    /// Key playing the root of the scale
+    pub middle: u8,
+    /// Key tuned to `reference_freq`
+    pub reference_note: u8,
+    pub reference_freq: f64,
+    /// Degree the mapping repeats at, or zero for the scale's period
+    pub octave_degree: usize,
+    /// Degrees played by the keys from `middle` up, repeating every
+    /// `mapping.len()` keys. Empty plays consecutive degrees on consecutive keys.
+    pub mapping: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMap {
+    /// Every key on the next degree, with A4 at 440Hz
+    fn default() -> Self {
+        Self {
+            first: 0,
+            last: 127,
+            middle: 60,
+            reference_note: 69,
+            reference_freq: 440.0,
+            octave_degree: 0,
+            mapping: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMap {
+    /// Reads a Scala keyboard mapping file
+    pub fn parse_kbm(text: &str) -> Result<Self, TuningError> {
+        let mut lines = lines(text).filter(|line| !line.is_empty());
+        let size: usize = next_value(&mut lines, "map size")?;
+        let mut map = Self {
+            first: next_value(&mut lines, "first note")?,
+            last: next_value(&mut lines, "last note")?,
+            middle: next_value(&mut lines, "middle note")?,
+            reference_note: next_value(&mut lines, "reference note")?,
+            reference_freq: next_value(&mut lines, "reference frequency")?,
+            octave_degree: next_value(&mut lines, "octave degree")?,
+            mapping: Vec::with_capacity(size),
+        };
+        for _ in 0..size {
+            let line = lines.next().ok_or(TuningError::MissingLine("mapping"))?;
+            let word = line.split_whitespace().next().unwrap_or_default();
+            let degree = match word {
+                "x" | "X" => None,
+                word => Some(
+                    word.parse()
+                        .map_err(|_| TuningError::Invalid("mapping", line.into()))?,
+                ),
+            };
+            map.mapping.push(degree);
+        }
+        Ok(map)
+    }
+
+    /// Cents above the root of `scale` that `note` plays, even outside of
+    /// the keys that get played
+    fn cents(&self, scale: &Scale, note: u8) -> Option<f64> {
+        let offset = i64::from(note) - i64::from(self.middle);
+        if self.mapping.is_empty() {
+            return Some(scale.degree_cents(offset));
+        }
+
+        let len = self.mapping.len() as i64;
+        let degree = self.mapping[offset.rem_euclid(len) as usize]? as i64;
+        let octave = match self.octave_degree {
+            0 => scale.cents.len(),
+            degree => degree,
+        } as i64;
+        let repeats = offset.div_euclid(len);
+        Some(scale.degree_cents(degree) + repeats as f64 * scale.degree_cents(octave))
+    }
+}
+
+/// How notes turn into frequencies, for the whole rack
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tuning {
+    /// Where the scale came from, for showing it
+    pub name: String,
+    pub scale: Scale,
+    pub keyboard: KeyboardMap,
+}
+
+impl Default for Tuning {
+    /// Twelve tone equal temperament with A4 at 440Hz
+    fn default() -> Self {
+        Self {
+            name: "12-TET".into(),
+            scale: Scale::equal(12),
+            keyboard: KeyboardMap::default(),
+        }
+    }
+}
+
+impl Tuning {
+    /// Frequency of `note`, unless its key is unmapped
+    pub fn freq(&self, note: u8) -> Option<f32> {
+        if self.scale.cents.is_empty()
+            || !(self.keyboard.first..=self.keyboard.last).contains(&note)
+        {
+            return None;
+        }
+        let reference = self
+            .keyboard
+            .cents(&self.scale, self.keyboard.reference_note)?;
+        let cents = self.keyboard.cents(&self.scale, note)?;
+        Some((self.keyboard.reference_freq * ((cents - reference) / 1200.0).exp2()) as f32)
+    }
+
+    /// Frequency of every note
+    pub fn freqs(&self) -> NoteFreqs {
+        std::array::from_fn(|note| self.freq(note as u8))
+    }
+
+    /// Checks the keyboard mapping fits the scale
+    pub fn validate(&self) -> Result<(), TuningError> {
+        if self.scale.cents.is_empty() {
+            return Err(TuningError::EmptyScale);
+        }
+        self.keyboard
+            .cents(&self.scale, self.keyboard.reference_note)
+            .map(|_| ())
+            .ok_or(TuningError::UnmappedReference)
+    }
+}
@@ -97,12 +97,31 @@ impl SlotWidget {
     pub fn value(&self) -> f32 {
         match self {
             SlotWidget::Knob(k) => k.value,
-            SlotWidget::Fader(_f) => todo!(),
+            SlotWidget::Fader(f) => f.value,
             SlotWidget::Toggle(t) => t.value(),
             SlotWidget::Port(_) => 0.0,
         }
     }
 
+    pub fn set_value(&mut self, value: f32) {
+        match self {
+            SlotWidget::Knob(k) => k.set_value(value),
+            SlotWidget::Fader(f) => f.set_value(value),
+            SlotWidget::Toggle(t) => t.set_value(value),
+            SlotWidget::Port(_) => {}
+        }
+    }
+
+    /// Lowest and highest value, for widgets that have one
+    pub fn range(&self) -> Option<(f32, f32)> {
+        match self {
+            SlotWidget::Knob(k) => Some(k.range()),
+            SlotWidget::Fader(f) => Some(f.range()),
+            SlotWidget::Toggle(t) => Some(t.range()),
+            SlotWidget::Port(_) => None,
+        }
+    }
+
     pub fn show(&mut self, ui: &mut Ui, theme: VisualTheme) -> InnerResponse<WidgetResponse> {
         match self {
             SlotWidget::Knob(k) => k.show(ui, theme),
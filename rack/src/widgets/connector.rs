@@ -40,20 +40,51 @@ pub fn catenary(start: Pos2, end: Pos2, h: f32, m: f32, n: usize) -> impl Iterat
     })
 }
 
-pub fn draw_catenary(start: emath::Pos2, end: emath::Pos2, painter: &Painter) {
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CableStyle {
+    /// Cable closes a feedback loop and carries the previous sample
+    pub feedback: bool,
+    /// Cable carries more than one channel
+    pub poly: bool,
+}
+
+impl CableStyle {
+    fn colors(&self) -> (Color32, Color32) {
+        if self.feedback {
+            (
+                Color32::from_rgb(255, 140, 0),
+                Color32::from_rgb(170, 90, 0),
+            )
+        } else {
+            (Color32::RED, Color32::DARK_RED)
+        }
+    }
+
+    fn width(&self) -> f32 {
+        if self.poly {
+            8.0
+        } else {
+            4.0
+        }
+    }
+}
+
+pub fn draw_catenary(start: emath::Pos2, end: emath::Pos2, style: CableStyle, painter: &Painter) {
     let pts: Vec<_> = catenary(start, end, 0.6, 0.10, 16).collect();
+    let (outer, inner) = style.colors();
+    let width = style.width();
     painter.add(PathShape::line(
         pts.clone(),
         Stroke {
-            width: 4.0,
-            color: Color32::RED,
+            width,
+            color: outer,
         },
     ));
     painter.add(PathShape::line(
         pts,
         Stroke {
-            width: 3.0,
-            color: Color32::DARK_RED,
+            width: width - 1.0,
+            color: inner,
         },
     ));
     painter.circle_filled(start, 6.0, Color32::DARK_GRAY);
@@ -48,6 +48,20 @@ impl Fader {
         }
     }
 
+    pub fn range(&self) -> (f32, f32) {
+        (self.value_range.start, self.value_range.end)
+    }
+
+    pub fn set_value(&mut self, value: f32) {
+        let span = self.value_range.end - self.value_range.start;
+        self.pos = if span != 0.0 {
+            ((value - self.value_range.start) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self.value = calculate_value(self.value_range, self.pos);
+    }
+
     fn allocate_space(&self, ui: &mut Ui) -> Response {
         let size = vec2(16.0, 256.0);
         ui.allocate_response(size, Sense::click_and_drag())
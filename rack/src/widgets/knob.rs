@@ -84,6 +84,21 @@ impl Knob {
         }
     }
 
+    pub fn range(&self) -> (f32, f32) {
+        (self.value_range.start, self.value_range.end)
+    }
+
+    pub fn set_value(&mut self, value: f32) {
+        let span = self.value_range.end - self.value_range.start;
+        let normalized = if span != 0.0 {
+            ((value - self.value_range.start) / span).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self.angle = lerp(self.angle_range, normalized);
+        self.value = calculate_value(self.value_range, self.angle, self.angle_range);
+    }
+
     fn allocate_space(&self, ui: &mut Ui) -> Response {
         ui.allocate_response(self.size, Sense::click_and_drag())
     }
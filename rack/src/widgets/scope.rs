@@ -1,47 +1,57 @@
-use eframe::epaint::mutex::RwLockReadGuard;
-use std::{
-    collections::VecDeque,
-    sync::{
-        atomic::{
-            AtomicUsize,
-            Ordering,
-        },
-        Arc,
-    },
+use std::collections::VecDeque;
+
+use crate::spsc::{
+    self,
+    Consumer,
+    Producer,
 };
 
-pub struct SampleQueue {
-    inner: Arc<(AtomicUsize, eframe::epaint::mutex::RwLock<VecDeque<f32>>)>,
+/// How many samples can be in flight between two UI frames
+const IN_FLIGHT: usize = 1 << 16;
+
+/// Audio thread side of a [`SampleQueue`]
+pub struct SampleSender {
+    tx: Producer<f32>,
 }
 
-impl Clone for SampleQueue {
-    fn clone(&self) -> Self {
-        Self {
-            inner: Arc::clone(&self.inner),
-        }
+impl SampleSender {
+    /// Never blocks, samples are dropped while the UI isn't keeping up
+    pub fn put(&mut self, sample: f32) {
+        let _ = self.tx.push(sample);
     }
 }
 
+/// Keeps the last `period` samples sent through its [`SampleSender`]
+pub struct SampleQueue {
+    rx: Consumer<f32>,
+    samples: VecDeque<f32>,
+    period: usize,
+}
+
 impl SampleQueue {
-    pub fn new(period: usize) -> Self {
-        Self {
-            inner: Arc::new((period.into(), Default::default())),
-        }
+    pub fn new(period: usize) -> (SampleSender, Self) {
+        let (tx, rx) = spsc::channel(IN_FLIGHT);
+        (
+            SampleSender { tx },
+            Self {
+                rx,
+                samples: VecDeque::with_capacity(period),
+                period,
+            },
+        )
     }
 
-    pub fn set_period(&self, period: usize) {
-        self.inner.0.store(period, Ordering::Relaxed)
+    pub fn set_period(&mut self, period: usize) {
+        self.period = period;
     }
 
-    pub fn put(&self, sample: f32) {
-        let mut g = self.inner.1.write();
-        g.push_back(sample);
-        if g.len() >= self.inner.0.load(Ordering::Relaxed) {
-            g.pop_front();
+    pub fn get(&mut self) -> &VecDeque<f32> {
+        while let Some(sample) = self.rx.pop() {
+            self.samples.push_back(sample);
+            if self.samples.len() >= self.period {
+                self.samples.pop_front();
+            }
         }
-    }
-
-    pub fn get(&'_ self) -> RwLockReadGuard<'_, VecDeque<f32>> {
-        self.inner.1.read()
+        &self.samples
     }
 }
@@ -62,6 +62,14 @@ impl Toggle {
         }
     }
 
+    pub fn range(&self) -> (f32, f32) {
+        (self.off, self.on)
+    }
+
+    pub fn set_value(&mut self, value: f32) {
+        self.state = (value - self.on).abs() < (value - self.off).abs();
+    }
+
     pub fn show(&mut self, ui: &mut Ui, theme: VisualTheme) -> InnerResponse<WidgetResponse> {
         let old_state = self.state;
         let mut res = ui.allocate_response(self.size, Sense::click());
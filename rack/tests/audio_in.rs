@@ -0,0 +1,200 @@
+use std::sync::atomic::Ordering;
+
+use rack::{
+    devices::description::{
+        DeviceKind,
+        Param,
+    },
+    engine::Engine,
+    graph::{
+        compiled::{
+            compile,
+            Frame,
+            CHANNELS,
+        },
+        DeviceId,
+        Graph,
+    },
+    input::InputResampler,
+    module_description::ModuleDescription,
+    spsc,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+
+fn device(graph: &mut Graph, name: &str) -> DeviceId {
+    let kind = DeviceKind::all()
+        .into_iter()
+        .find(|k| k.name() == name)
+        .unwrap();
+    graph.insert_device(kind)
+}
+
+fn connect(graph: &mut Graph, (from, out): (DeviceId, u8), (to, inp): (DeviceId, u8)) {
+    let out = graph.output_of(from, out).unwrap();
+    let inp = graph.input_of(to, inp).unwrap();
+    graph.cables.insert(inp, out);
+}
+
+/// Input going straight out, with left and right swapped around
+fn swapped(graph: &mut Graph) -> DeviceId {
+    let input = device(graph, "AudioIn");
+    let output = device(graph, "StereoOutput");
+    connect(graph, (input, 0), (output, 1));
+    connect(graph, (input, 1), (output, 0));
+    input
+}
+
+fn ramp(len: usize) -> Vec<Frame> {
+    (0..len).map(|i| [i as f32, -(i as f32)]).collect()
+}
+
+#[test]
+fn input_plays_through() {
+    let mut graph = Graph::new();
+    swapped(&mut graph);
+    let mut engine = Engine::new(SAMPLE_RATE);
+    engine.swap(Box::new(compile(&graph.walk(), SAMPLE_RATE)));
+    // Past the crossfade
+    engine.process_block(&mut [[0.0; CHANNELS]; 1000]);
+
+    let input = ramp(100);
+    engine.set_input(&input);
+    let mut out = vec![[0.0; CHANNELS]; 120];
+    // Split up, as happens around MIDI events
+    let (first, rest) = out.split_at_mut(33);
+    engine.process_block(first);
+    engine.process_block(rest);
+    for (frame, [l, r]) in out.iter().zip(&input) {
+        assert_eq!(*frame, [*r, *l]);
+    }
+    // Silent once the input runs out
+    assert!(out[100..].iter().all(|f| *f == [0.0; CHANNELS]));
+}
+
+#[test]
+fn feedback_programs_read_input_a_sample_at_a_time() {
+    let mut graph = Graph::new();
+    swapped(&mut graph);
+    // A loop somewhere else keeps the program from running in blocks
+    let a = device(&mut graph, "Control");
+    let b = device(&mut graph, "Control");
+    let output = device(&mut graph, "Output");
+    connect(&mut graph, (a, 1), (b, 0));
+    connect(&mut graph, (b, 1), (a, 0));
+    connect(&mut graph, (b, 1), (output, 0));
+    let ctl_graph = graph.walk();
+    assert!(!ctl_graph.feedback.is_empty());
+    let mut code = compile(&ctl_graph, SAMPLE_RATE);
+
+    let frames = ramp(10);
+    code.set_input(&frames);
+    let mut out = [[0.0; CHANNELS]; 10];
+    code.process_block(&mut out);
+    for (frame, [l, r]) in out.iter().zip(&frames) {
+        assert_eq!(*frame, [*r, *l]);
+    }
+}
+
+/// Writes `len` frames of a ramp going on from `at`
+fn write(tx: &mut spsc::Producer<Frame>, at: &mut usize, len: usize) {
+    for _ in 0..len {
+        let _ = tx.push([*at as f32, 0.0]);
+        *at += 1;
+    }
+}
+
+#[test]
+fn resampler_waits_for_target() {
+    let (mut tx, rx) = spsc::channel(1024);
+    let mut resampler = InputResampler::new(rx, SAMPLE_RATE, SAMPLE_RATE, 256);
+    let mut at = 0;
+    let mut out = [[1.0; CHANNELS]; 64];
+
+    write(&mut tx, &mut at, 200);
+    resampler.read(&mut out);
+    assert!(resampler.is_priming());
+    assert!(out.iter().all(|f| *f == [0.0; CHANNELS]));
+
+    write(&mut tx, &mut at, 56);
+    resampler.read(&mut out);
+    assert!(!resampler.is_priming());
+    // At the same rate frames come out as they went in
+    for (i, frame) in out.iter().enumerate() {
+        assert_eq!(frame[0], i as f32);
+    }
+
+    // Runs dry and goes back to waiting
+    let underruns = resampler.underruns();
+    assert_eq!(underruns.load(Ordering::Relaxed), 0);
+    let mut long = [[1.0; CHANNELS]; 512];
+    resampler.read(&mut long);
+    assert!(resampler.is_priming());
+    assert_eq!(underruns.load(Ordering::Relaxed), 1);
+    assert_eq!(long[511], [0.0; CHANNELS]);
+}
+
+#[test]
+fn resampler_follows_drifting_input() {
+    // The input's clock runs 0.1% fast, and arrives in bursts of 480 frames
+    let (mut tx, rx) = spsc::channel(8192);
+    let mut resampler = InputResampler::new(rx, SAMPLE_RATE, SAMPLE_RATE, 960);
+    let mut at = 0;
+    let mut written = 0.0;
+    let mut read = 0;
+    let mut last = None;
+    let mut out = [[0.0; CHANNELS]; 256];
+
+    for _ in 0..6000 {
+        read += out.len();
+        let due = read as f64 * 1.001;
+        while written + 480.0 <= due + 960.0 {
+            write(&mut tx, &mut at, 480);
+            written += 480.0;
+        }
+        resampler.read(&mut out);
+        for [frame, _] in out {
+            // The ramp keeps going up a frame at a time, as far as an f32 that
+            // large can tell, never jumping back or skipping any
+            if let Some(last) = last {
+                let step = frame - last;
+                assert!((0.5..1.5).contains(&step), "{last} to {frame}");
+            }
+            last = Some(frame);
+        }
+    }
+    assert!(!resampler.is_priming());
+    assert!(
+        (resampler.step() - 1.001).abs() < 2e-4,
+        "{}",
+        resampler.step()
+    );
+}
+
+#[test]
+fn resampler_converts_rates() {
+    let (mut tx, rx) = spsc::channel(8192);
+    let mut resampler = InputResampler::new(rx, 44100.0, SAMPLE_RATE, 441);
+    let mut at = 0;
+    write(&mut tx, &mut at, 441);
+    let mut out = [[0.0; CHANNELS]; 160];
+    resampler.read(&mut out);
+    // Input frames are 160/147 output frames apart
+    let expected = 44100.0 / SAMPLE_RATE;
+    for (i, frame) in out.iter().enumerate() {
+        assert!((frame[0] - i as f32 * expected).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn prefab_module_fits_device() {
+    let description: ModuleDescription =
+        serde_yaml::from_str(include_str!("../../prefab_modules/audioin.yml")).unwrap();
+    let params = DeviceKind::AudioIn.params();
+    assert_eq!(description.devices[&0], DeviceKind::AudioIn);
+    for (vi, (di, pi)) in &description.connections {
+        assert_eq!(*di, 0);
+        let (Param::In(name) | Param::Out(name)) = params[*pi];
+        assert_eq!(description.visuals[vi].name, name);
+    }
+}
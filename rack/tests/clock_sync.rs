@@ -0,0 +1,197 @@
+use rack::{
+    engine::Engine,
+    graph::compiled::CHANNELS,
+    transport::{
+        ClockSync,
+        TransportCommand,
+        TransportSettings,
+    },
+};
+use wmidi::{
+    MidiMessage,
+    U14,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+/// Samples between clocks at 120 BPM
+const CLOCK_INTERVAL: usize = 1000;
+
+fn run(engine: &mut Engine, len: usize) {
+    let mut block = vec![[0.0; CHANNELS]; len];
+    engine.process_block(&mut block);
+}
+
+fn following() -> Engine {
+    let mut engine = Engine::new(SAMPLE_RATE);
+    engine.set_clock_sync(ClockSync {
+        follow: Some(0),
+        send: false,
+    });
+    engine
+}
+
+/// Plays `count` clocks `interval` samples apart into `engine`
+fn clocks(engine: &mut Engine, count: usize, interval: usize) {
+    for _ in 0..count {
+        engine.handle_midi(0, &MidiMessage::TimingClock);
+        run(engine, interval);
+    }
+}
+
+fn sent(engine: &mut Engine) -> Vec<(u64, MidiMessage<'static>)> {
+    let mut sent = Vec::new();
+    engine.take_midi_out(|time, msg| sent.push((time, msg)));
+    sent
+}
+
+#[test]
+fn follows_start_and_tempo() {
+    let mut engine = following();
+    engine.handle_midi(0, &MidiMessage::Start);
+    assert!(!engine.transport().is_playing());
+    assert_eq!(engine.transport().position(), 0.0);
+
+    // Playing starts on the first clock after Start, which is the downbeat
+    clocks(&mut engine, 48, CLOCK_INTERVAL / 2);
+    assert!(engine.transport().is_playing());
+    let tempo = engine.followed_tempo().unwrap();
+    assert!((tempo - 240.0).abs() < 0.01, "{tempo}");
+    assert_eq!(engine.transport().settings().tempo, tempo);
+    // One clock's worth past the last clock, at the tempo followed
+    assert!((engine.transport().position() - 48.0 / 24.0).abs() < 1e-3);
+}
+
+#[test]
+fn jitter_is_smoothed_out() {
+    let mut engine = following();
+    engine.handle_midi(0, &MidiMessage::Start);
+    for i in 0..96 {
+        engine.handle_midi(0, &MidiMessage::TimingClock);
+        run(&mut engine, if i % 2 == 0 { 1100 } else { 900 });
+    }
+    let tempo = engine.followed_tempo().unwrap();
+    assert!((tempo - 120.0).abs() < 120.0 * 0.01, "{tempo}");
+}
+
+#[test]
+fn stop_song_position_and_continue() {
+    let mut engine = following();
+    engine.handle_midi(0, &MidiMessage::Start);
+    clocks(&mut engine, 30, CLOCK_INTERVAL);
+    engine.handle_midi(0, &MidiMessage::Stop);
+    assert!(!engine.transport().is_playing());
+    // Stops on the last clock, whatever it ran on to after it
+    assert_eq!(engine.transport().position(), 29.0 / 24.0);
+
+    // Clocks keep coming while stopped, without moving anything
+    clocks(&mut engine, 10, CLOCK_INTERVAL);
+    assert_eq!(engine.transport().position(), 29.0 / 24.0);
+
+    let bar_two = U14::try_from(16).unwrap();
+    engine.handle_midi(0, &MidiMessage::SongPositionPointer(bar_two));
+    assert_eq!(engine.transport().position(), 4.0);
+    engine.handle_midi(0, &MidiMessage::Continue);
+    clocks(&mut engine, 12, CLOCK_INTERVAL);
+    assert!(engine.transport().is_playing());
+    assert!((engine.transport().position() - 4.5).abs() < 1e-3);
+}
+
+#[test]
+fn tempo_set_by_hand_is_ignored_while_following() {
+    let mut engine = following();
+    engine.handle_midi(0, &MidiMessage::Start);
+    clocks(&mut engine, 10, CLOCK_INTERVAL);
+    engine.set_transport(TransportSettings {
+        tempo: 90.0,
+        beats_per_bar: 3,
+        ..Default::default()
+    });
+    let settings = engine.transport().settings();
+    assert!((settings.tempo - 120.0).abs() < 0.01);
+    assert_eq!(settings.beats_per_bar, 3);
+
+    // Other ports are only played, and going back to the internal clock lets go of the tempo
+    engine.set_clock_sync(ClockSync::default());
+    engine.handle_midi(1, &MidiMessage::Stop);
+    assert!(engine.transport().is_playing());
+    engine.set_transport(TransportSettings {
+        tempo: 90.0,
+        ..Default::default()
+    });
+    assert_eq!(engine.transport().settings().tempo, 90.0);
+}
+
+#[test]
+fn sends_clock_start_and_stop() {
+    let mut engine = Engine::new(SAMPLE_RATE);
+    engine.transport_command(TransportCommand::Stop);
+    engine.set_clock_sync(ClockSync {
+        follow: None,
+        send: true,
+    });
+    run(&mut engine, 100);
+    assert!(sent(&mut engine).is_empty());
+
+    engine.transport_command(TransportCommand::Play);
+    run(&mut engine, 2 * CLOCK_INTERVAL + 10);
+    assert_eq!(
+        sent(&mut engine),
+        [
+            (0, MidiMessage::Start),
+            (0, MidiMessage::TimingClock),
+            (1000, MidiMessage::TimingClock),
+            (2000, MidiMessage::TimingClock),
+        ]
+    );
+
+    run(&mut engine, 10);
+    engine.transport_command(TransportCommand::Stop);
+    run(&mut engine, 2 * CLOCK_INTERVAL);
+    assert_eq!(sent(&mut engine), [(10, MidiMessage::Stop)]);
+
+    // Picks up from the last sixteenth it got past, which is still the first one
+    engine.transport_command(TransportCommand::Play);
+    let sixteenths = U14::try_from(0).unwrap();
+    assert_eq!(
+        sent(&mut engine)[..2],
+        [
+            (0, MidiMessage::SongPositionPointer(sixteenths)),
+            (0, MidiMessage::Continue),
+        ]
+    );
+}
+
+#[test]
+fn follower_keeps_up_with_sender() {
+    let mut leader = Engine::new(SAMPLE_RATE);
+    leader.set_transport(TransportSettings {
+        tempo: 133.0,
+        ..Default::default()
+    });
+    leader.transport_command(TransportCommand::Stop);
+    leader.set_clock_sync(ClockSync {
+        follow: None,
+        send: true,
+    });
+    let mut follower = following();
+
+    leader.transport_command(TransportCommand::Play);
+    for _ in 0..200 {
+        run(&mut leader, 256);
+        // Everything from the leader lands on the same sample in the follower
+        let mut done = 0;
+        for (time, msg) in sent(&mut leader) {
+            run(&mut follower, time as usize - done);
+            follower.handle_midi(0, &msg);
+            done = time as usize;
+        }
+        run(&mut follower, 256 - done);
+    }
+    let tempo = follower.followed_tempo().unwrap();
+    assert!((tempo - 133.0).abs() < 0.5, "{tempo}");
+    let (lead, follow) = (
+        leader.transport().position(),
+        follower.transport().position(),
+    );
+    assert!((lead - follow).abs() < 1.0 / 24.0, "{lead} {follow}");
+}
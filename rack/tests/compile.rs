@@ -0,0 +1,420 @@
+use rack::{
+    devices::{
+        description::DeviceKind,
+        impls::generators::Osc,
+    },
+    graph::{
+        compiled::{
+            compile,
+            CHANNELS,
+        },
+        DeviceId,
+        Graph,
+    },
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+
+fn device(graph: &mut Graph, name: &str) -> DeviceId {
+    let kind = DeviceKind::all()
+        .into_iter()
+        .find(|k| k.name() == name)
+        .unwrap();
+    graph.insert_device(kind)
+}
+
+fn connect(graph: &mut Graph, (from, out): (DeviceId, u8), (to, inp): (DeviceId, u8)) {
+    let out = graph.output_of(from, out).unwrap();
+    let inp = graph.input_of(to, inp).unwrap();
+    graph.cables.insert(inp, out);
+}
+
+#[test]
+fn fan_out_feeds_every_input() {
+    let mut graph = Graph::new();
+    let control = device(&mut graph, "Control");
+    let att = device(&mut graph, "Attenuator");
+    let out = device(&mut graph, "Output");
+
+    connect(&mut graph, (control, 1), (att, 0));
+    connect(&mut graph, (control, 1), (att, 1));
+    connect(&mut graph, (att, 2), (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((control, 0), 0.5);
+    code.sample();
+    assert_eq!(code.sample()[0], 0.25);
+}
+
+#[test]
+fn diamond() {
+    let mut graph = Graph::new();
+    // Inserted back to front, so insertion order can't hide scheduling bugs
+    let out = device(&mut graph, "Output");
+    let mixer = device(&mut graph, "A/B Mixer");
+    let a = device(&mut graph, "Attenuator");
+    let b = device(&mut graph, "Attenuator");
+    let control = device(&mut graph, "Control");
+
+    connect(&mut graph, (control, 1), (a, 0));
+    connect(&mut graph, (control, 1), (b, 0));
+    connect(&mut graph, (a, 2), (mixer, 0));
+    connect(&mut graph, (b, 2), (mixer, 1));
+    connect(&mut graph, (mixer, 3), (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((control, 0), 1.0);
+    code.update_param((a, 1), 0.5);
+    code.update_param((b, 1), 0.25);
+    code.update_param((mixer, 2), 0.5);
+
+    // Every device must see this sample's inputs, not last sample's
+    assert_eq!(code.sample()[0], 0.375);
+}
+
+#[test]
+fn fanned_out_oscillator_is_sampled_once() {
+    let mut graph = Graph::new();
+    let osc = device(&mut graph, "SineOsc");
+    let mixer = device(&mut graph, "A/B Mixer");
+    let out = device(&mut graph, "Output");
+
+    connect(&mut graph, (osc, 2), (mixer, 0));
+    connect(&mut graph, (osc, 2), (mixer, 1));
+    connect(&mut graph, (mixer, 3), (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((osc, 0), 440.0);
+
+    let mut reference = Osc::<f32>::with_freq(SAMPLE_RATE, f32::sin, 440.0);
+    for _ in 0..128 {
+        assert!((code.sample()[0] - reference.sample()).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn long_chain() {
+    let mut graph = Graph::new();
+    let control = device(&mut graph, "Control");
+    let out = device(&mut graph, "Output");
+
+    let mut prev = (control, 1);
+    for _ in 0..32 {
+        let att = device(&mut graph, "Attenuator");
+        connect(&mut graph, prev, (att, 0));
+        prev = (att, 2);
+    }
+    connect(&mut graph, prev, (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((control, 0), 0.75);
+    assert_eq!(code.sample()[0], 0.75);
+}
+
+#[test]
+fn unconnected_output_is_silent() {
+    let mut graph = Graph::new();
+    device(&mut graph, "Output");
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    assert_eq!(code.sample()[0], 0.0);
+}
+
+#[test]
+fn self_feedback_is_delayed() {
+    let mut graph = Graph::new();
+    let control = device(&mut graph, "Control");
+    let mixer = device(&mut graph, "A/B Mixer");
+    let out = device(&mut graph, "Output");
+
+    connect(&mut graph, (control, 1), (mixer, 0));
+    connect(&mut graph, (mixer, 3), (mixer, 1));
+    connect(&mut graph, (mixer, 3), (out, 0));
+
+    let ctl = graph.walk();
+    let loop_input = graph.input_of(mixer, 1).unwrap();
+    assert_eq!(ctl.feedback.keys().collect::<Vec<_>>(), [loop_input]);
+
+    let mut code = compile(&ctl, SAMPLE_RATE);
+    code.update_param((control, 0), 1.0);
+    code.update_param((mixer, 2), 0.5);
+
+    // y[n] = 0.5 * 1.0 + 0.5 * y[n - 1]
+    for expected in [0.5, 0.75, 0.875, 0.9375] {
+        assert_eq!(code.sample()[0], expected);
+    }
+}
+
+#[test]
+fn loop_through_two_devices() {
+    let mut graph = Graph::new();
+    let control = device(&mut graph, "Control");
+    let mixer = device(&mut graph, "A/B Mixer");
+    let att = device(&mut graph, "Attenuator");
+    let out = device(&mut graph, "Output");
+
+    connect(&mut graph, (control, 1), (mixer, 0));
+    connect(&mut graph, (mixer, 3), (att, 0));
+    connect(&mut graph, (att, 2), (mixer, 1));
+    connect(&mut graph, (mixer, 3), (out, 0));
+
+    let ctl = graph.walk();
+    assert_eq!(ctl.feedback.len(), 1);
+
+    let mut code = compile(&ctl, SAMPLE_RATE);
+    code.update_param((control, 0), 1.0);
+    code.update_param((mixer, 2), 0.5);
+    code.update_param((att, 1), 0.5);
+
+    // y[n] = 0.5 * 1.0 + 0.25 * y[n - 1]
+    let mut y = 0.0;
+    for _ in 0..16 {
+        y = 0.5 + 0.25 * y;
+        assert_eq!(code.sample()[0], y);
+    }
+}
+
+#[test]
+fn recompiling_keeps_device_state() {
+    let mut graph = Graph::new();
+    let osc = device(&mut graph, "SineOsc");
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, (osc, 2), (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((osc, 0), 440.0);
+    let mut reference = Osc::<f32>::with_freq(SAMPLE_RATE, f32::sin, 440.0);
+    for _ in 0..100 {
+        code.sample();
+        reference.sample();
+    }
+
+    let mut recompiled = compile(&graph.walk(), SAMPLE_RATE);
+    recompiled.carry_state_from(&code);
+    for _ in 0..16 {
+        assert!((recompiled.sample()[0] - reference.sample()).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn block_matches_per_sample() {
+    let build = || {
+        let mut graph = Graph::new();
+        let osc = device(&mut graph, "SineOsc");
+        let adsr = device(&mut graph, "ADSR");
+        let gate = device(&mut graph, "Control");
+        let att = device(&mut graph, "Attenuator");
+        let out = device(&mut graph, "Output");
+        connect(&mut graph, (gate, 1), (adsr, 6));
+        connect(&mut graph, (osc, 2), (att, 0));
+        connect(&mut graph, (adsr, 7), (att, 1));
+        connect(&mut graph, (att, 2), (out, 0));
+
+        let mut code = compile(&graph.walk(), SAMPLE_RATE);
+        code.update_param((osc, 0), 440.0);
+        code.update_param((gate, 0), 1.0);
+        code
+    };
+
+    let mut per_sample = build();
+    let mut block = build();
+    // Not a multiple of the block size, to cover a partial last block
+    let mut buffer = vec![[0.0; CHANNELS]; 1000];
+    block.process_block(&mut buffer);
+    for frame in buffer {
+        let expected = per_sample.sample();
+        for (a, b) in frame.iter().zip(expected) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+}
+
+#[test]
+fn mono_output_feeds_both_channels() {
+    let mut graph = Graph::new();
+    let control = device(&mut graph, "Control");
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, (control, 1), (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((control, 0), 0.5);
+    assert_eq!(code.sample(), [0.5, 0.5]);
+}
+
+#[test]
+fn stereo_output_keeps_channels_apart() {
+    let mut graph = Graph::new();
+    let left = device(&mut graph, "Control");
+    let right = device(&mut graph, "Control");
+    let out = device(&mut graph, "StereoOutput");
+    connect(&mut graph, (left, 1), (out, 0));
+    connect(&mut graph, (right, 1), (out, 1));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((left, 0), 0.25);
+    code.update_param((right, 0), -0.5);
+    assert_eq!(code.sample(), [0.25, -0.5]);
+
+    let mut buffer = [[0.0; CHANNELS]; 4];
+    code.process_block(&mut buffer);
+    assert_eq!(buffer, [[0.25, -0.5]; 4]);
+}
+
+#[test]
+fn panner_is_equal_power() {
+    let mut graph = Graph::new();
+    let control = device(&mut graph, "Control");
+    let panner = device(&mut graph, "Panner");
+    let out = device(&mut graph, "StereoOutput");
+    connect(&mut graph, (control, 1), (panner, 0));
+    connect(&mut graph, (panner, 2), (out, 0));
+    connect(&mut graph, (panner, 3), (out, 1));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((control, 0), 1.0);
+    for pan in [-1.0, -0.5, 0.0, 0.3, 1.0] {
+        code.update_param((panner, 1), pan);
+        let [l, r] = code.sample();
+        assert!((l * l + r * r - 1.0).abs() < 1e-6);
+    }
+
+    code.update_param((panner, 1), -1.0);
+    let [l, r] = code.sample();
+    assert!((l - 1.0).abs() < 1e-6 && r.abs() < 1e-6);
+}
+
+#[test]
+fn outputs_are_summed() {
+    let mut graph = Graph::new();
+    let a = device(&mut graph, "Control");
+    let b = device(&mut graph, "Control");
+    let mono = device(&mut graph, "Output");
+    let stereo = device(&mut graph, "StereoOutput");
+    connect(&mut graph, (a, 1), (mono, 0));
+    connect(&mut graph, (b, 1), (stereo, 1));
+
+    let ctl = graph.walk();
+    assert_eq!(ctl.ends.len(), 2);
+
+    let mut code = compile(&ctl, SAMPLE_RATE);
+    code.update_param((a, 0), 0.25);
+    code.update_param((b, 0), 0.5);
+    assert_eq!(code.sample(), [0.25, 0.75]);
+
+    let mut buffer = [[0.0; CHANNELS]; 4];
+    code.process_block(&mut buffer);
+    assert_eq!(buffer, [[0.25, 0.75]; 4]);
+}
+
+#[test]
+fn device_shared_by_outputs_runs_once() {
+    let mut graph = Graph::new();
+    let osc = device(&mut graph, "SineOsc");
+    let first = device(&mut graph, "Output");
+    let second = device(&mut graph, "Output");
+    connect(&mut graph, (osc, 2), (first, 0));
+    connect(&mut graph, (osc, 2), (second, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((osc, 0), 440.0);
+
+    let mut reference = Osc::<f32>::with_freq(SAMPLE_RATE, f32::sin, 440.0);
+    for _ in 0..128 {
+        let expected = 2.0 * reference.sample();
+        assert!((code.sample()[0] - expected).abs() < 1e-5);
+    }
+}
+
+/// Merge fed by a `Control` per value, returned along with the controls
+fn merge(graph: &mut Graph, values: &[f32]) -> (DeviceId, Vec<(DeviceId, f32)>) {
+    let merge = device(graph, "Merge");
+    let controls = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let control = device(graph, "Control");
+            connect(graph, (control, 1), (merge, i as u8));
+            (control, *value)
+        })
+        .collect();
+    (merge, controls)
+}
+
+#[test]
+fn split_takes_merged_channels_apart() {
+    let mut graph = Graph::new();
+    let (merge, controls) = merge(&mut graph, &[0.1, 0.2, 0.3]);
+    let split = device(&mut graph, "Split");
+    let out = device(&mut graph, "StereoOutput");
+    connect(&mut graph, (merge, 16), (split, 0));
+    connect(&mut graph, (split, 3), (out, 0));
+    connect(&mut graph, (split, 4), (out, 1));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    for (control, value) in controls {
+        code.update_param((control, 0), value);
+    }
+    assert_eq!(code.sample(), [0.3, 0.0]);
+}
+
+#[test]
+fn poly_cable_runs_device_per_channel() {
+    let mut graph = Graph::new();
+    let (merge, controls) = merge(&mut graph, &[0.5, 0.25]);
+    let factor = device(&mut graph, "Control");
+    let att = device(&mut graph, "Attenuator");
+    let split = device(&mut graph, "Split");
+    let out = device(&mut graph, "StereoOutput");
+    connect(&mut graph, (merge, 16), (att, 0));
+    // Mono signals go to every channel
+    connect(&mut graph, (factor, 1), (att, 1));
+    connect(&mut graph, (att, 2), (split, 0));
+    connect(&mut graph, (split, 1), (out, 0));
+    connect(&mut graph, (split, 2), (out, 1));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    for (control, value) in controls {
+        code.update_param((control, 0), value);
+    }
+    code.update_param((factor, 0), 2.0);
+    assert_eq!(code.sample(), [1.0, 0.5]);
+
+    let mut block = [[0.0; CHANNELS]; 4];
+    code.process_block(&mut block);
+    assert_eq!(block, [[1.0, 0.5]; 4]);
+}
+
+#[test]
+fn poly_cable_into_mono_input_is_summed() {
+    let mut graph = Graph::new();
+    let (merge, controls) = merge(&mut graph, &[0.5, 0.25, 0.125]);
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, (merge, 16), (out, 0));
+
+    let ctl_graph = graph.walk();
+    assert_eq!(ctl_graph.poly_inputs().len(), 1);
+    let mut code = compile(&ctl_graph, SAMPLE_RATE);
+    for (control, value) in controls {
+        code.update_param((control, 0), value);
+    }
+    assert_eq!(code.sample(), [0.875; CHANNELS]);
+}
+
+#[test]
+fn missing_channels_are_silent() {
+    let mut graph = Graph::new();
+    let (inputs, mut controls) = merge(&mut graph, &[1.0, 1.0, 1.0]);
+    let (factors, more) = merge(&mut graph, &[0.5, 0.25]);
+    controls.extend(more);
+    let att = device(&mut graph, "Attenuator");
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, (inputs, 16), (att, 0));
+    connect(&mut graph, (factors, 16), (att, 1));
+    connect(&mut graph, (att, 2), (out, 0));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    for (control, value) in controls {
+        code.update_param((control, 0), value);
+    }
+    assert_eq!(code.sample()[0], 0.75);
+}
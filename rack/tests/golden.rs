@@ -0,0 +1,260 @@
+//! Drives every device through scripted inputs and compares what comes out
+//! against reference buffers in `tests/golden`.
+//!
+//! Run with `BLESS=1` to (re)write the references after an intended change.
+
+use std::{
+    env,
+    f32::consts::TAU,
+    fs,
+    path::PathBuf,
+};
+
+use rack::devices::{
+    description::DeviceKind,
+    Device,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+/// Samples compared against the stored references
+const GOLDEN_LEN: usize = 256;
+const TOLERANCE: f32 = 1e-4;
+/// Long enough for 10 Hz spectral resolution
+const SPECTRUM_LEN: usize = 4800;
+
+/// Parameter changes, each applied right before the sample it's tagged with
+type Script<'a> = &'a [(usize, u8, f32)];
+
+fn make(name: &str) -> Box<dyn Device + Send + Sync> {
+    let kind = DeviceKind::all()
+        .into_iter()
+        .find(|k| k.name() == name)
+        .unwrap();
+    let mut devices = Vec::new();
+    kind.make()(&mut devices, SAMPLE_RATE);
+    devices.pop().unwrap()
+}
+
+/// Runs `script` through device `name` for `len` samples, recording `output`
+fn render(name: &str, script: Script, output: u8, len: usize) -> Vec<f32> {
+    let mut device = make(name);
+    let mut script = script.iter().peekable();
+    (0..len)
+        .map(|i| {
+            while let Some((_, param, value)) = script.next_if(|(t, _, _)| *t <= i) {
+                device.set_param_indexed(*param, *value);
+            }
+            device.get_output_indexed(output)
+        })
+        .collect()
+}
+
+fn check_golden(name: &str, samples: &[f32]) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", name]
+        .iter()
+        .collect::<PathBuf>()
+        .with_extension("txt");
+
+    if env::var_os("BLESS").is_some() {
+        let text: String = samples.iter().map(|s| format!("{s:.8}\n")).collect();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, text).unwrap();
+        return;
+    }
+
+    let reference = fs::read_to_string(&path).unwrap_or_else(|_| {
+        panic!("No reference for {name}, run with BLESS=1 to create it");
+    });
+    let reference: Vec<f32> = reference.lines().map(|l| l.parse().unwrap()).collect();
+    assert_eq!(reference.len(), samples.len(), "{name} changed length");
+    for (i, (got, expected)) in samples.iter().zip(&reference).enumerate() {
+        assert!(
+            (got - expected).abs() <= TOLERANCE,
+            "{name} differs from its reference at sample {i}: got {got}, expected {expected}"
+        );
+    }
+}
+
+fn dc_offset(samples: &[f32]) -> f32 {
+    samples.iter().sum::<f32>() / samples.len() as f32
+}
+
+/// Frequency of the strongest partial below `max_freq`
+fn fundamental(samples: &[f32], max_freq: f32) -> f32 {
+    let n = samples.len();
+    let bin_width = SAMPLE_RATE / n as f32;
+    let magnitude = |bin: usize| {
+        let (re, im) = samples
+            .iter()
+            .enumerate()
+            .fold((0.0f32, 0.0f32), |(re, im), (i, s)| {
+                let angle = TAU * bin as f32 * i as f32 / n as f32;
+                (re + s * angle.cos(), im - s * angle.sin())
+            });
+        re.hypot(im)
+    };
+    let peak = (1..(max_freq / bin_width) as usize)
+        .max_by(|a, b| magnitude(*a).total_cmp(&magnitude(*b)))
+        .unwrap();
+    peak as f32 * bin_width
+}
+
+fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0f32, |m, s| m.max(s.abs()))
+}
+
+fn check_oscillator(name: &str, script: Script, output: u8) {
+    check_golden(name, &render(name, script, output, GOLDEN_LEN));
+
+    let long = render(name, script, output, SPECTRUM_LEN);
+    assert_eq!(fundamental(&long, 2000.0), 440.0, "{name} is out of tune");
+    assert!(dc_offset(&long).abs() < 0.01, "{name} has a DC offset");
+    assert!((peak(&long) - 1.0).abs() < 0.01, "{name} isn't normalised");
+}
+
+#[test]
+fn sine_osc() {
+    check_oscillator("SineOsc", &[(0, 0, 440.0)], 2);
+}
+
+#[test]
+fn saw_osc() {
+    check_oscillator("SawOsc", &[(0, 0, 440.0)], 2);
+
+    // A rising ramp only ever falls when it wraps around, once per period
+    let samples = render("SawOsc", &[(0, 0, 440.0)], 2, SPECTRUM_LEN);
+    let falls = samples.windows(2).filter(|w| w[1] < w[0]).count();
+    assert!((43..=44).contains(&falls), "fell {falls} times");
+}
+
+#[test]
+fn triangle_osc() {
+    check_oscillator("TriangleOsc", &[(0, 0, 440.0)], 2);
+
+    // Straight lines, so the slope only takes two values apart from at the corners
+    let samples = render("TriangleOsc", &[(0, 0, 440.0)], 2, SPECTRUM_LEN);
+    let slope = 4.0 * 440.0 / SAMPLE_RATE;
+    let bent = samples
+        .windows(2)
+        .filter(|w| ((w[1] - w[0]).abs() - slope).abs() > 1e-3)
+        .count();
+    assert!(bent <= 2 * 44, "{bent} samples off the triangle's slope");
+}
+
+#[test]
+fn square_osc() {
+    check_oscillator("SquareOsc", &[(0, 0, 440.0), (0, 1, 0.5)], 2);
+}
+
+#[test]
+fn detune_shifts_pitch() {
+    let samples = render("SineOsc", &[(0, 0, 400.0), (0, 1, 40.0)], 2, SPECTRUM_LEN);
+    assert_eq!(fundamental(&samples, 2000.0), 440.0);
+}
+
+#[test]
+fn moog_filter() {
+    let square: Vec<_> = (0..GOLDEN_LEN)
+        .map(|i| {
+            if i % 64 < 32 {
+                (i, 0, 1.0)
+            } else {
+                (i, 0, -1.0)
+            }
+        })
+        .collect();
+    let mut script = vec![(0, 1, 2000.0), (0, 2, 0.5)];
+    script.extend(square);
+    check_golden("MoogFilter", &render("MoogFilter", &script, 3, GOLDEN_LEN));
+
+    // Low pass: a partial well below the cutoff passes, one well above doesn't
+    let sine = |freq: f32| -> Vec<(usize, u8, f32)> {
+        let mut script = vec![(0, 1, 1000.0), (0, 2, 0.0)];
+        script.extend((0..SPECTRUM_LEN).map(|i| {
+            // Quiet, to stay clear of the clipper
+            let value = 0.1 * (TAU * freq * i as f32 / SAMPLE_RATE).sin();
+            (i, 0, value)
+        }));
+        script
+    };
+    // Skip the filter settling in
+    let low = peak(&render("MoogFilter", &sine(100.0), 3, SPECTRUM_LEN)[2400..]);
+    let high = peak(&render("MoogFilter", &sine(10000.0), 3, SPECTRUM_LEN)[2400..]);
+    assert!(low > 0.09, "pass band attenuated to {low}");
+    assert!(high < 0.001, "stop band only attenuated to {high}");
+}
+
+#[test]
+fn attenuator() {
+    let script = [(0, 0, 1.0), (0, 1, 0.5), (64, 0, -0.5), (128, 1, 2.0)];
+    let samples = render("Attenuator", &script, 2, GOLDEN_LEN);
+    check_golden("Attenuator", &samples);
+    assert_eq!((samples[0], samples[64], samples[128]), (0.5, -0.25, -1.0));
+}
+
+#[test]
+fn ab_mixer() {
+    let script = [
+        (0, 0, 1.0),
+        (0, 1, -1.0),
+        (0, 2, 0.0),
+        (64, 2, 0.25),
+        (128, 2, 1.0),
+    ];
+    let samples = render("A/B Mixer", &script, 3, GOLDEN_LEN);
+    check_golden("ABMixer", &samples);
+    assert_eq!((samples[0], samples[64], samples[128]), (1.0, 0.5, -1.0));
+}
+
+#[test]
+fn panner() {
+    let script = [(0, 0, 1.0), (0, 1, -1.0), (64, 1, 0.0), (128, 1, 1.0)];
+    let left = render("Panner", &script, 2, GOLDEN_LEN);
+    let right = render("Panner", &script, 3, GOLDEN_LEN);
+    check_golden("PannerLeft", &left);
+    check_golden("PannerRight", &right);
+
+    let centre = std::f32::consts::FRAC_1_SQRT_2;
+    assert!((left[0] - 1.0).abs() < 1e-6 && right[0].abs() < 1e-6);
+    assert!((left[64] - centre).abs() < 1e-6 && (right[64] - centre).abs() < 1e-6);
+    assert!(left[128].abs() < 1e-6 && (right[128] - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn adsr() {
+    // Times are in seconds, so keep them short enough to fit in the reference
+    let script = [
+        (0, 0, 0.001),
+        (0, 1, 0.001),
+        (0, 2, 0.5),
+        (0, 3, 0.001),
+        (0, 4, 0.3),
+        (0, 5, 0.0001),
+        (0, 6, 1.0),
+        (160, 6, 0.0),
+    ];
+    let samples = render("ADSR", &script, 7, GOLDEN_LEN);
+    check_golden("ADSR", &samples);
+
+    let top = samples
+        .iter()
+        .position(|s| *s >= 1.0)
+        .expect("attack never peaks");
+    assert!(top <= 48, "attack took {top} samples");
+    assert!(samples[..top].windows(2).all(|w| w[1] >= w[0]));
+    assert!((samples[150] - 0.5).abs() < 1e-3, "didn't sustain");
+    assert!(samples[160..].windows(2).all(|w| w[1] <= w[0]));
+    assert_eq!(*samples.last().unwrap(), 0.0, "didn't release");
+}
+
+#[test]
+fn sequencer() {
+    let mut script: Vec<_> = (0..8).map(|i| (0, i as u8, (i + 1) as f32)).collect();
+    // A step every 16 samples
+    script.push((0, 8, 60.0 * SAMPLE_RATE / 16.0));
+    let samples = render("Sequencer", &script, 9, GOLDEN_LEN);
+    check_golden("Sequencer", &samples);
+
+    let steps: Vec<f32> = samples.iter().step_by(16).copied().collect();
+    assert_eq!(&steps[..9], &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 1.0]);
+}
@@ -0,0 +1,132 @@
+use rack::{
+    container::{
+        Stack,
+        StackResponse,
+    },
+    graph::modules::Module,
+    midi::CcMapping,
+    module_description::ModuleDescription,
+    STQueue,
+};
+use wmidi::{
+    Channel,
+    ControlFunction,
+    MidiMessage,
+    U7,
+};
+
+/// Visual index of the pan knob in `panner.yml`
+const PAN: usize = 1;
+
+fn cc(channel: Channel, cc: u8, value: u8) -> MidiMessage<'static> {
+    MidiMessage::ControlChange(
+        channel,
+        ControlFunction(U7::from_u8_lossy(cc)),
+        U7::from_u8_lossy(value),
+    )
+}
+
+fn panner_stack() -> Stack {
+    let description: ModuleDescription =
+        serde_yaml::from_str(include_str!("../../prefab_modules/panner.yml")).unwrap();
+    let mut stack = Stack::new(STQueue::new());
+    let mid = Module::insert_from_description(&mut stack.graph, description);
+    assert!(stack.with_module(mid).is_none());
+    stack
+}
+
+/// Values sent to the engine since the last call
+fn control_changes(stack: &Stack) -> Vec<f32> {
+    let mut values = Vec::new();
+    while let Some(ev) = stack.events.get() {
+        if let StackResponse::ControlChange(_, value) = ev {
+            values.push(value);
+        }
+    }
+    values
+}
+
+#[test]
+fn coarse_controller_scales_to_range() {
+    let mut mapping = CcMapping::new(0, 7, -1.0, 1.0);
+    assert_eq!(mapping.handle(0, 7, 0), Some(-1.0));
+    assert_eq!(mapping.handle(0, 7, 127), Some(1.0));
+    assert_eq!(mapping.handle(1, 7, 127), None);
+    assert_eq!(mapping.handle(0, 8, 127), None);
+}
+
+#[test]
+fn fine_controller_adds_precision() {
+    let mut mapping = CcMapping::new(0, 1, 0.0, 1.0);
+    assert!(!mapping.fine);
+    mapping.handle(0, 1, 64);
+    let value = mapping.handle(0, 33, 64).unwrap();
+    assert!(mapping.fine);
+    assert_eq!(value, f32::from(64u16 << 7 | 64) / 16383.0);
+    assert_eq!(
+        mapping.handle(0, 1, 127),
+        Some(f32::from(127u16 << 7) / 16383.0)
+    );
+    assert_eq!(mapping.handle(0, 33, 127), Some(1.0));
+
+    // Learning from the fine half pairs up only once the coarse one arrives
+    let mut mapping = CcMapping::new(0, 33, 0.0, 1.0);
+    assert_eq!((mapping.cc, mapping.fine), (33, false));
+    assert_eq!(mapping.handle(0, 33, 127), Some(1.0));
+    assert_eq!(
+        mapping.handle(0, 1, 64),
+        Some(f32::from(64u16 << 7) / 16383.0)
+    );
+    assert_eq!((mapping.cc, mapping.fine), (1, true));
+    assert_eq!(
+        mapping.handle(0, 33, 127),
+        Some(f32::from(64u16 << 7 | 127) / 16383.0)
+    );
+}
+
+#[test]
+fn learned_controller_moves_knob() {
+    let mut stack = panner_stack();
+    let mid = stack.graph.modules.keys().next().unwrap();
+    let vid = stack.graph[mid].visual_ids[&PAN];
+    control_changes(&stack);
+
+    // Nothing is mapped yet
+    stack.handle_midi(&cc(Channel::Ch3, 74, 127));
+    assert!(control_changes(&stack).is_empty());
+
+    stack.learn(mid, vid);
+    assert!(stack.learning());
+    stack.handle_midi(&cc(Channel::Ch3, 74, 127));
+    assert!(!stack.learning());
+    assert_eq!(control_changes(&stack), [1.0]);
+
+    stack.handle_midi(&cc(Channel::Ch3, 74, 0));
+    assert_eq!(control_changes(&stack), [-1.0]);
+    assert_eq!(stack.graph[mid].visuals[vid].value(), -1.0);
+
+    // Other channels and controllers are left alone
+    stack.handle_midi(&cc(Channel::Ch1, 74, 127));
+    stack.handle_midi(&cc(Channel::Ch3, 75, 127));
+    assert!(control_changes(&stack).is_empty());
+}
+
+#[test]
+fn mappings_are_saved_in_patches() {
+    let mut stack = panner_stack();
+    let mid = stack.graph.modules.keys().next().unwrap();
+    let vid = stack.graph[mid].visual_ids[&PAN];
+    stack.learn(mid, vid);
+    stack.handle_midi(&cc(Channel::Ch2, 10, 64));
+
+    let patch = stack.to_patch();
+    let mapping = patch.modules[0].mappings[&PAN];
+    assert_eq!((mapping.channel, mapping.cc), (1, 10));
+    assert_eq!((mapping.min, mapping.max), (-1.0, 1.0));
+
+    let mut loaded = Stack::new(STQueue::new());
+    loaded.load_patch(patch);
+    control_changes(&loaded);
+    loaded.handle_midi(&cc(Channel::Ch2, 10, 127));
+    assert_eq!(control_changes(&loaded), [1.0]);
+}
@@ -0,0 +1,181 @@
+use rack::{
+    devices::description::{
+        DeviceKind,
+        Param,
+    },
+    graph::{
+        compiled::{
+            compile,
+            ByteCode,
+            CHANNELS,
+        },
+        DeviceId,
+        Graph,
+    },
+    midi::{
+        load_smf,
+        write_smf,
+    },
+    module_description::ModuleDescription,
+    tuning::{
+        Scale,
+        Tuning,
+    },
+};
+use wmidi::{
+    Channel,
+    ControlFunction,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+
+fn device(graph: &mut Graph, name: &str) -> DeviceId {
+    let kind = DeviceKind::all()
+        .into_iter()
+        .find(|k| k.name() == name)
+        .unwrap();
+    graph.insert_device(kind)
+}
+
+fn connect(graph: &mut Graph, (from, out): (DeviceId, u8), (to, inp): (DeviceId, u8)) {
+    let out = graph.output_of(from, out).unwrap();
+    let inp = graph.input_of(to, inp).unwrap();
+    graph.cables.insert(inp, out);
+}
+
+fn on(note: Note) -> MidiMessage<'static> {
+    MidiMessage::NoteOn(Channel::Ch1, note, U7::MAX)
+}
+
+fn off(note: Note) -> MidiMessage<'static> {
+    MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN)
+}
+
+/// MidiControl playing straight into a MidiOut, which is all the rack ends in
+fn midi_through() -> ByteCode {
+    let mut graph = Graph::new();
+    let midi = device(&mut graph, "MidiControl");
+    let out = device(&mut graph, "MidiOut");
+    connect(&mut graph, (midi, 0), (out, 0));
+    connect(&mut graph, (midi, 1), (out, 1));
+    compile(&graph.walk(), SAMPLE_RATE)
+}
+
+/// Runs `len` samples, returning what the MidiOuts sent and when
+fn run(code: &mut ByteCode, len: usize) -> Vec<(u64, MidiMessage<'static>)> {
+    let mut block = vec![[0.0; CHANNELS]; len];
+    code.process_block(&mut block);
+    let mut sent = Vec::new();
+    code.take_midi_out(|time, msg| sent.push((time, msg)));
+    sent
+}
+
+#[test]
+fn gate_edges_send_notes() {
+    let mut code = midi_through();
+    assert!(run(&mut code, 10).is_empty());
+
+    code.handle_midi(0, &on(Note::A4));
+    assert_eq!(run(&mut code, 10), [(0, on(Note::A4))]);
+    // Held notes aren't sent again
+    assert!(run(&mut code, 10).is_empty());
+
+    code.handle_midi(0, &off(Note::A4));
+    assert_eq!(run(&mut code, 10), [(0, off(Note::A4))]);
+}
+
+#[test]
+fn legato_sends_new_note_before_releasing_old() {
+    let mut code = midi_through();
+    code.handle_midi(0, &on(Note::C4));
+    run(&mut code, 10);
+
+    // Mono MidiControl glides over to the new note without dropping the gate
+    code.handle_midi(0, &on(Note::G4));
+    assert_eq!(run(&mut code, 10), [(0, on(Note::G4)), (0, off(Note::C4))]);
+}
+
+#[test]
+fn notes_follow_tuning() {
+    let mut graph = Graph::new();
+    let midi = device(&mut graph, "MidiControl");
+    let out = device(&mut graph, "MidiOut");
+    connect(&mut graph, (midi, 0), (out, 0));
+    connect(&mut graph, (midi, 1), (out, 1));
+    let mut ctl_graph = graph.walk();
+    // Quarter tones, which put C4 nine of them below A4
+    ctl_graph.tuning = Tuning {
+        scale: Scale::equal(24),
+        ..Default::default()
+    };
+    let mut code = compile(&ctl_graph, SAMPLE_RATE);
+
+    code.handle_midi(0, &on(Note::C4));
+    assert_eq!(run(&mut code, 10), [(0, on(Note::C4))]);
+    code.handle_midi(0, &off(Note::C4));
+    assert_eq!(run(&mut code, 10), [(0, off(Note::C4))]);
+}
+
+#[test]
+fn controller_is_sent_on_change() {
+    let mut graph = Graph::new();
+    let control = device(&mut graph, "Control");
+    let number = device(&mut graph, "Control");
+    let out = device(&mut graph, "MidiOut");
+    connect(&mut graph, (control, 1), (out, 3));
+    connect(&mut graph, (number, 1), (out, 4));
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+
+    code.update_param((number, 0), 74.0);
+    code.update_param((control, 0), 0.5);
+    let cc = |value| {
+        MidiMessage::ControlChange(
+            Channel::Ch1,
+            ControlFunction(U7::from_u8_lossy(74)),
+            U7::from_u8_lossy(value),
+        )
+    };
+    assert_eq!(run(&mut code, 10), [(0, cc(64))]);
+    assert!(run(&mut code, 10).is_empty());
+
+    code.update_param((control, 0), 1.0);
+    assert_eq!(run(&mut code, 10), [(0, cc(127))]);
+}
+
+#[test]
+fn exported_file_loads_back() {
+    let events = [
+        (0, on(Note::C4)),
+        (24000, off(Note::C4)),
+        (36000, on(Note::E4)),
+        (48000, off(Note::E4)),
+    ];
+    let bytes = write_smf(&events, SAMPLE_RATE);
+    assert_eq!(load_smf(&bytes, SAMPLE_RATE).unwrap(), events);
+}
+
+#[test]
+fn exported_events_get_sorted() {
+    let events = [(24000, off(Note::C4)), (0, on(Note::C4)), (0, on(Note::E4))];
+    let bytes = write_smf(&events, SAMPLE_RATE);
+    assert_eq!(
+        load_smf(&bytes, SAMPLE_RATE).unwrap(),
+        [events[1].clone(), events[2].clone(), events[0].clone()]
+    );
+}
+
+#[test]
+fn prefab_module_fits_device() {
+    let description: ModuleDescription =
+        serde_yaml::from_str(include_str!("../../prefab_modules/midiout.yml")).unwrap();
+    let params = DeviceKind::MidiOut.params();
+    assert_eq!(description.devices[&0], DeviceKind::MidiOut);
+    for (vi, (di, pi)) in &description.connections {
+        assert_eq!(*di, 0);
+        let (Param::In(name) | Param::Out(name)) = params[*pi];
+        assert_eq!(description.visuals[vi].name, name);
+    }
+}
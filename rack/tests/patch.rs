@@ -0,0 +1,71 @@
+use rack::{
+    container::Stack,
+    graph::modules::Module,
+    module_description::ModuleDescription,
+    patch::{
+        Patch,
+        PatchPort,
+    },
+    STQueue,
+};
+
+/// Visual index of the mix knob in `mrmixtastik.yml`
+const MIX: usize = 2;
+/// Parameters of the mixer device
+const A: u8 = 0;
+const OUT: u8 = 3;
+
+/// Rack of two mixers, the first feeding the second
+fn two_mixers() -> Stack {
+    let description: ModuleDescription =
+        serde_yaml::from_str(include_str!("../../prefab_modules/mrmixtastik.yml")).unwrap();
+    let mut stack = Stack::new(STQueue::new());
+    let mut mixers = Vec::new();
+    for _ in 0..2 {
+        let mid = Module::insert_from_description(&mut stack.graph, description.clone());
+        assert!(stack.with_module(mid).is_none());
+        mixers.push(mid);
+    }
+
+    let graph = &mut stack.graph;
+    let from = graph[mixers[0]].device(0).unwrap();
+    let to = graph[mixers[1]].device(0).unwrap();
+    let out = graph.output_of(from, OUT).unwrap();
+    let inp = graph.input_of(to, A).unwrap();
+    graph.cables.insert(inp, out);
+
+    let vid = graph[mixers[0]].visual_ids[&MIX];
+    graph[mixers[0]].visuals[vid].set_value(0.25);
+    stack
+}
+
+#[test]
+fn patch_round_trips() {
+    let stack = two_mixers();
+    let patch = stack.to_patch();
+    assert_eq!(patch.modules.len(), 2);
+    assert_eq!(patch.cables.len(), 1);
+    let cable = patch.cables[0];
+    assert_eq!(
+        (cable.output.device, cable.output.param),
+        (0, OUT),
+        "{cable:?}"
+    );
+    assert_eq!(
+        cable.input,
+        PatchPort {
+            module: 1 - cable.output.module,
+            device: 0,
+            param: A,
+        }
+    );
+    let mixes: Vec<_> = patch.modules.iter().map(|m| m.values[&MIX]).collect();
+    assert!(mixes.contains(&0.25), "{mixes:?}");
+
+    let yaml = serde_yaml::to_string(&patch).unwrap();
+    let mut loaded = Stack::new(STQueue::new());
+    loaded.load_patch(serde_yaml::from_str::<Patch>(&yaml).unwrap());
+    assert_eq!(loaded.graph.modules.len(), 2);
+    assert_eq!(loaded.graph.cables.len(), 1);
+    assert_eq!(serde_yaml::to_string(&loaded.to_patch()).unwrap(), yaml);
+}
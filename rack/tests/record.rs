@@ -0,0 +1,118 @@
+use std::{
+    path::PathBuf,
+    thread,
+    time::Duration,
+};
+
+use hound::WavReader;
+use rack::{
+    graph::compiled::{
+        Frame,
+        CHANNELS,
+    },
+    midi::load_smf,
+    record::Recorder,
+};
+use wmidi::{
+    Channel,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rack-{}-{name}.wav", std::process::id()))
+}
+
+fn ramp(len: usize) -> Vec<Frame> {
+    (0..len).map(|i| [i as f32, -(i as f32)]).collect()
+}
+
+fn read(path: &PathBuf) -> (hound::WavSpec, Vec<f32>) {
+    let mut reader = WavReader::open(path).unwrap();
+    let samples = reader.samples::<f32>().map(Result::unwrap).collect();
+    (reader.spec(), samples)
+}
+
+#[test]
+fn writes_everything_fed() {
+    let path = temp_path("fed");
+    let (recorder, mut tap) = Recorder::start(&path, SAMPLE_RATE).unwrap();
+    let frames = ramp(10000);
+    // In blocks, as the audio thread does
+    for block in frames.chunks(256) {
+        tap.write(block);
+    }
+    recorder.finish().unwrap();
+
+    let (spec, samples) = read(&path);
+    assert_eq!(spec.channels, CHANNELS as u16);
+    assert_eq!(spec.sample_rate, 48000);
+    assert_eq!(samples, frames.concat());
+    // Nothing was sent, so there's no MIDI file to go with it
+    assert!(!path.with_extension("mid").exists());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn writes_midi_next_to_audio() {
+    let path = temp_path("midi");
+    let (recorder, mut tap) = Recorder::start(&path, SAMPLE_RATE).unwrap();
+    let on = MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::MAX);
+    let off = MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN);
+    for (i, block) in ramp(24000).chunks(1000).enumerate() {
+        tap.write(block);
+        match i {
+            0 => tap.write_midi(0, on.clone()),
+            11 => tap.write_midi(1000, off.clone()),
+            _ => (),
+        }
+    }
+    recorder.finish().unwrap();
+
+    let midi_path = path.with_extension("mid");
+    let bytes = std::fs::read(&midi_path).unwrap();
+    // Offsets count from the start of the block they were sent in
+    assert_eq!(
+        load_smf(&bytes, SAMPLE_RATE).unwrap(),
+        [(0, on), (12000, off)]
+    );
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(midi_path).unwrap();
+}
+
+#[test]
+fn counts_time_written() {
+    let path = temp_path("time");
+    let (recorder, mut tap) = Recorder::start(&path, SAMPLE_RATE).unwrap();
+    assert_eq!(recorder.seconds(), 0.0);
+    tap.write(&ramp(24000));
+    while recorder.seconds() < 0.5 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(recorder.seconds(), 0.5);
+    assert_eq!(recorder.dropped(), 0);
+    drop(recorder);
+
+    // Dropping finishes the file as well
+    assert_eq!(read(&path).1.len(), 24000 * CHANNELS);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn drops_what_does_not_fit() {
+    let path = temp_path("dropped");
+    // A buffer of two seconds at 100 Hz is 200 frames
+    let (recorder, mut tap) = Recorder::start(&path, 100.0).unwrap();
+    tap.write(&ramp(1000));
+    assert!(recorder.dropped() >= 800);
+
+    recorder.finish().unwrap();
+    let (_, samples) = read(&path);
+    // What got through is the start, without gaps
+    let kept = samples.len() / CHANNELS;
+    assert_eq!(samples, ramp(kept).concat());
+    std::fs::remove_file(path).unwrap();
+}
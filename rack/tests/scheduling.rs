@@ -0,0 +1,212 @@
+use rack::{
+    devices::description::DeviceKind,
+    engine::{
+        Engine,
+        MidiScheduler,
+    },
+    graph::{
+        compiled::{
+            compile,
+            Frame,
+            CHANNELS,
+        },
+        Graph,
+    },
+    midi::{
+        MidiEvent,
+        Polyphony,
+        TRIGGER,
+    },
+};
+use wmidi::{
+    Channel,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+const BUFFER: usize = 256;
+
+/// Microseconds `samples` take to play
+fn micros(samples: u64) -> u64 {
+    samples * 1_000_000 / SAMPLE_RATE as u64
+}
+
+fn note_on(time: u64, note: Note) -> MidiEvent {
+    MidiEvent {
+        port: 0,
+        time,
+        msg: MidiMessage::NoteOn(Channel::Ch1, note, U7::MAX),
+    }
+}
+
+/// Engine playing the sum of the triggers of three voices, past the crossfade
+/// in from silence, along with a scheduler that has rendered as much
+fn triggers() -> (Engine, MidiScheduler) {
+    let mut graph = Graph::new();
+    let kind = |name| {
+        DeviceKind::all()
+            .into_iter()
+            .find(|k| k.name() == name)
+            .unwrap()
+    };
+    let midi = graph.insert_device(kind("MidiControl"));
+    let out = graph.insert_device(kind("Output"));
+    let output = graph.output_of(midi, TRIGGER).unwrap();
+    let input = graph.input_of(out, 0).unwrap();
+    graph.cables.insert(input, output);
+
+    let mut ctl_graph = graph.walk();
+    ctl_graph.polyphony = Polyphony {
+        voices: 3,
+        ..Default::default()
+    };
+    let mut engine = Engine::new(SAMPLE_RATE);
+    engine.swap(Box::new(compile(&ctl_graph, SAMPLE_RATE)));
+
+    let mut scheduler = MidiScheduler::new(SAMPLE_RATE, 1, 4);
+    let mut buffer = [[0.0; CHANNELS]; BUFFER];
+    for _ in 0..2 {
+        scheduler.process_block(&mut engine, &mut buffer);
+    }
+    (engine, scheduler)
+}
+
+/// Renders a buffer in chunks of `chunk`, after scheduling `events`
+fn render(
+    engine: &mut Engine,
+    scheduler: &mut MidiScheduler,
+    events: Vec<MidiEvent>,
+    chunk: usize,
+) -> Vec<Frame> {
+    for ev in events {
+        scheduler.schedule(ev, BUFFER);
+    }
+    let mut buffer = vec![[0.0; CHANNELS]; BUFFER];
+    for chunk in buffer.chunks_mut(chunk) {
+        scheduler.process_block(engine, chunk);
+    }
+    buffer
+}
+
+#[test]
+fn burst_lands_on_one_sample() {
+    for chunk in [BUFFER, 64, 7] {
+        let (mut engine, mut scheduler) = triggers();
+        // The first event lines the clocks up, and everything that arrived
+        // during the same buffer keeps its distance from it
+        let anchor = MidiEvent {
+            port: 0,
+            time: 1_000_000,
+            msg: MidiMessage::TimingClock,
+        };
+        let at = 1_000_000 + micros(100);
+        let burst = vec![
+            anchor,
+            note_on(at, Note::C4),
+            note_on(at, Note::E4),
+            note_on(at, Note::G4),
+        ];
+        let out = render(&mut engine, &mut scheduler, burst, chunk);
+
+        assert!(out[..100].iter().all(|f| f[0] == 0.0), "early in {chunk}");
+        assert!(out[100..].iter().all(|f| f[0] == 3.0), "smeared in {chunk}");
+    }
+}
+
+#[test]
+fn events_keep_their_distance_across_buffers() {
+    let (mut engine, mut scheduler) = triggers();
+    let start = 1_000_000;
+    let out = render(
+        &mut engine,
+        &mut scheduler,
+        vec![
+            note_on(start, Note::C4),
+            note_on(start + micros(10), Note::E4),
+        ],
+        BUFFER,
+    );
+    assert_eq!((out[9][0], out[10][0]), (1.0, 2.0));
+
+    // Arrived while the last buffer played, so lands in this one
+    let out = render(
+        &mut engine,
+        &mut scheduler,
+        vec![note_on(start + micros(BUFFER as u64 + 50), Note::G4)],
+        BUFFER,
+    );
+    assert_eq!((out[49][0], out[50][0]), (2.0, 3.0));
+}
+
+#[test]
+fn late_events_play_right_away() {
+    let (mut engine, mut scheduler) = triggers();
+    render(
+        &mut engine,
+        &mut scheduler,
+        vec![note_on(0, Note::C4)],
+        BUFFER,
+    );
+    // Stamped way back, but can't be played in the past
+    let out = render(
+        &mut engine,
+        &mut scheduler,
+        vec![note_on(0, Note::E4)],
+        BUFFER,
+    );
+    assert!(out.iter().all(|f| f[0] == 2.0));
+
+    // Stamped way ahead, from the clocks drifting apart
+    let ahead = note_on(micros(100 * BUFFER as u64), Note::G4);
+    let now = 4 * BUFFER as u64;
+    assert_eq!(scheduler.schedule(ahead, BUFFER), Some(now + BUFFER as u64));
+}
+
+#[test]
+fn highest_port_gets_scheduled() {
+    // Sixteen MIDI inputs, followed by the file player and the keyboard
+    let ports = 18;
+    let mut scheduler = MidiScheduler::new(SAMPLE_RATE, ports, 4);
+    let highest = MidiEvent {
+        port: ports - 1,
+        ..note_on(0, Note::C4)
+    };
+    assert_eq!(scheduler.schedule(highest, BUFFER), Some(0));
+
+    let past = MidiEvent {
+        port: ports,
+        ..note_on(0, Note::C4)
+    };
+    assert_eq!(scheduler.schedule(past, BUFFER), None);
+}
+
+#[test]
+fn full_queue_keeps_releases() {
+    let (mut engine, mut scheduler) = triggers();
+    let notes = [Note::C4, Note::D4, Note::E4, Note::F4];
+    for (i, note) in notes.into_iter().enumerate() {
+        assert!(scheduler
+            .schedule(note_on(i as u64, note), BUFFER)
+            .is_some());
+    }
+    assert_eq!(scheduler.schedule(note_on(10, Note::G4), BUFFER), None);
+
+    // Takes the place of the last note on
+    let note_off = MidiEvent {
+        port: 0,
+        time: 20,
+        msg: MidiMessage::NoteOff(Channel::Ch1, Note::C4, U7::MIN),
+    };
+    assert!(scheduler.schedule(note_off, BUFFER).is_some());
+    let out = render(&mut engine, &mut scheduler, Vec::new(), BUFFER);
+    assert_eq!(out[BUFFER - 1][0], 2.0);
+
+    // Ports past the ones it was made for are dropped
+    let other_port = MidiEvent {
+        port: 1,
+        ..note_on(0, Note::A4)
+    };
+    assert_eq!(scheduler.schedule(other_port, BUFFER), None);
+}
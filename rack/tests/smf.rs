@@ -0,0 +1,147 @@
+use midly::{
+    num::{
+        u15,
+        u24,
+        u28,
+        u4,
+        u7,
+    },
+    Format,
+    Header,
+    MetaMessage,
+    MidiMessage as SmfMessage,
+    Smf,
+    Timing,
+    TrackEvent,
+    TrackEventKind,
+};
+use rack::midi::{
+    load_smf,
+    SmfPlayer,
+};
+use wmidi::{
+    Channel,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+/// C4 for half a second, then at twice the tempo E4 for a quarter of one
+fn two_notes() -> Vec<u8> {
+    let event = |delta: u32, kind| TrackEvent {
+        delta: u28::new(delta),
+        kind,
+    };
+    let midi = |message| TrackEventKind::Midi {
+        channel: u4::new(0),
+        message,
+    };
+    let on = |key: u8| {
+        midi(SmfMessage::NoteOn {
+            key: u7::new(key),
+            vel: u7::new(100),
+        })
+    };
+    let off = |key: u8| {
+        midi(SmfMessage::NoteOff {
+            key: u7::new(key),
+            vel: u7::new(0),
+        })
+    };
+    let track = vec![
+        event(0, on(60)),
+        event(480, off(60)),
+        event(
+            0,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(250_000))),
+        ),
+        event(480, on(64)),
+        event(480, off(64)),
+        event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+    ];
+    let smf = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))),
+        tracks: vec![track],
+    };
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes).unwrap();
+    bytes
+}
+
+fn on(note: Note) -> MidiMessage<'static> {
+    MidiMessage::NoteOn(Channel::Ch1, note, U7::from_u8_lossy(100))
+}
+
+fn off(note: Note) -> MidiMessage<'static> {
+    MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN)
+}
+
+fn advance(player: &mut SmfPlayer, elapsed: u64) -> Vec<(u64, MidiMessage<'static>)> {
+    let mut sent = Vec::new();
+    player.advance(elapsed, |t, msg| sent.push((t, msg)));
+    sent
+}
+
+#[test]
+fn tempo_changes_are_followed() {
+    let events = load_smf(&two_notes(), 48000.0).unwrap();
+    let times: Vec<_> = events.iter().map(|(t, _)| *t).collect();
+    assert_eq!(times, [0, 24000, 36000, 48000]);
+}
+
+#[test]
+fn plays_in_real_time() {
+    let mut player = SmfPlayer::new(&two_notes()).unwrap();
+    assert_eq!(player.length(), 1_000_000);
+    assert!(
+        advance(&mut player, 100_000).is_empty(),
+        "played while stopped"
+    );
+
+    player.play();
+    assert_eq!(
+        advance(&mut player, 600_000),
+        [(0, on(Note::C4)), (500_000, off(Note::C4))]
+    );
+    assert_eq!(
+        advance(&mut player, 500_000),
+        [(150_000, on(Note::E4)), (400_000, off(Note::E4))]
+    );
+    assert!(!player.is_playing());
+    assert_eq!(player.position(), 1_000_000);
+
+    // Starts over once finished
+    player.play();
+    assert_eq!(advance(&mut player, 1), [(0, on(Note::C4))]);
+}
+
+#[test]
+fn loops_around() {
+    let mut player = SmfPlayer::new(&two_notes()).unwrap();
+    player.looping = true;
+    player.play();
+    advance(&mut player, 900_000);
+    assert_eq!(
+        advance(&mut player, 200_000),
+        [(100_000, off(Note::E4)), (100_000, on(Note::C4))]
+    );
+    assert!(player.is_playing());
+    assert_eq!(player.position(), 100_000);
+}
+
+#[test]
+fn jumps_release_notes() {
+    let mut player = SmfPlayer::new(&two_notes()).unwrap();
+    player.play();
+    advance(&mut player, 100_000);
+
+    let mut sent = Vec::new();
+    player.seek(700_000, |t, msg| sent.push((t, msg)));
+    assert_eq!(sent, [(0, off(Note::C4))]);
+    assert_eq!(advance(&mut player, 100_000), [(50_000, on(Note::E4))]);
+
+    let mut sent = Vec::new();
+    player.stop(|t, msg| sent.push((t, msg)));
+    assert_eq!(sent, [(0, off(Note::E4))]);
+    assert!(advance(&mut player, 500_000).is_empty());
+}
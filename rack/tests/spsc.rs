@@ -0,0 +1,78 @@
+use std::{
+    sync::{
+        atomic::{
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    thread,
+};
+
+use rack::spsc::channel;
+
+#[test]
+fn full_channel_hands_value_back() {
+    let (mut tx, mut rx) = channel(2);
+    assert_eq!(tx.push(1), Ok(()));
+    assert_eq!(tx.push(2), Ok(()));
+    assert!(tx.is_full());
+    assert_eq!(tx.push(3), Err(3));
+
+    assert_eq!(rx.pop(), Some(1));
+    assert_eq!(tx.push(3), Ok(()));
+    assert_eq!(rx.pop(), Some(2));
+    assert_eq!(rx.pop(), Some(3));
+    assert_eq!(rx.pop(), None);
+    assert!(rx.is_empty());
+}
+
+#[test]
+fn keeps_order_across_threads() {
+    const COUNT: usize = 100_000;
+    let (mut tx, mut rx) = channel(64);
+
+    let producer = thread::spawn(move || {
+        for i in 0..COUNT {
+            let mut value = i;
+            while let Err(v) = tx.push(value) {
+                value = v;
+                thread::yield_now();
+            }
+        }
+    });
+
+    let mut expected = 0;
+    while expected < COUNT {
+        match rx.pop() {
+            Some(i) => {
+                assert_eq!(i, expected);
+                expected += 1;
+            }
+            None => thread::yield_now(),
+        }
+    }
+    producer.join().unwrap();
+}
+
+#[test]
+fn drops_queued_values() {
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let (mut tx, mut rx) = channel(4);
+    for _ in 0..3 {
+        assert!(tx.push(Counted(Arc::clone(&drops))).is_ok());
+    }
+    drop(rx.pop());
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+
+    drop((tx, rx));
+    assert_eq!(drops.load(Ordering::Relaxed), 3);
+}
@@ -0,0 +1,242 @@
+use rack::{
+    container::Stack,
+    devices::description::{
+        DeviceKind,
+        Param,
+    },
+    graph::{
+        compiled::{
+            compile,
+            ByteCode,
+            CHANNELS,
+        },
+        DeviceId,
+        Graph,
+    },
+    module_description::ModuleDescription,
+    patch::Patch,
+    transport::{
+        TransportCommand,
+        TransportSettings,
+    },
+    STQueue,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+/// At the default 120 BPM
+const SAMPLES_PER_BEAT: usize = 24000;
+
+fn device(graph: &mut Graph, name: &str) -> DeviceId {
+    let kind = DeviceKind::all()
+        .into_iter()
+        .find(|k| k.name() == name)
+        .unwrap();
+    graph.insert_device(kind)
+}
+
+fn connect(graph: &mut Graph, (from, out): (DeviceId, u8), (to, inp): (DeviceId, u8)) {
+    let out = graph.output_of(from, out).unwrap();
+    let inp = graph.input_of(to, inp).unwrap();
+    graph.cables.insert(inp, out);
+}
+
+/// Clock with output `out` going straight to the speakers
+fn clock_output(out: u8) -> ByteCode {
+    let mut graph = Graph::new();
+    let clock = device(&mut graph, "Clock");
+    let output = device(&mut graph, "Output");
+    connect(&mut graph, (clock, out), (output, 0));
+    compile(&graph.walk(), SAMPLE_RATE)
+}
+
+fn run(code: &mut ByteCode, len: usize) -> Vec<f32> {
+    let mut block = vec![[0.0; CHANNELS]; len];
+    code.process_block(&mut block);
+    block.iter().map(|frame| frame[0]).collect()
+}
+
+/// Samples on which `signal` goes high
+fn rising_edges(signal: &[f32]) -> Vec<usize> {
+    let mut last = 0.0;
+    let mut edges = Vec::new();
+    for (i, &value) in signal.iter().enumerate() {
+        if value >= 0.5 && last < 0.5 {
+            edges.push(i);
+        }
+        last = value;
+    }
+    edges
+}
+
+#[test]
+fn clock_pulses_every_tick() {
+    let mut code = clock_output(0);
+    let edges = rising_edges(&run(&mut code, SAMPLES_PER_BEAT * 2));
+    assert_eq!(edges.len(), 48);
+    assert_eq!(edges[0], 0);
+    assert_eq!(edges[1], 1000);
+    assert_eq!(edges[24], SAMPLES_PER_BEAT);
+}
+
+#[test]
+fn beats_and_bars_follow_meter() {
+    let mut code = clock_output(1);
+    let beats = rising_edges(&run(&mut code, SAMPLES_PER_BEAT * 8));
+    assert_eq!(
+        beats,
+        (0..8).map(|b| b * SAMPLES_PER_BEAT).collect::<Vec<_>>()
+    );
+
+    let mut code = clock_output(2);
+    code.set_transport(TransportSettings {
+        beats_per_bar: 3,
+        ..Default::default()
+    });
+    let bars = rising_edges(&run(&mut code, SAMPLES_PER_BEAT * 8));
+    assert_eq!(bars, [0, 3 * SAMPLES_PER_BEAT, 6 * SAMPLES_PER_BEAT]);
+    assert_eq!(code.transport().bar_beat(), (2, 2));
+}
+
+#[test]
+fn stop_holds_position_and_reset_rewinds() {
+    let mut code = clock_output(4);
+    run(&mut code, 1000);
+    code.transport_command(TransportCommand::Stop);
+    assert!(run(&mut code, 1000).iter().all(|v| *v == 0.0));
+    assert!((code.transport().position() - 1000.0 / SAMPLES_PER_BEAT as f64).abs() < 1e-9);
+
+    code.transport_command(TransportCommand::Play);
+    assert!(run(&mut code, 10).iter().all(|v| *v == 1.0));
+
+    let mut code = clock_output(3);
+    let reset = run(&mut code, SAMPLES_PER_BEAT * 2);
+    assert_eq!(rising_edges(&reset), [0]);
+    code.transport_command(TransportCommand::Reset);
+    assert_eq!(code.transport().position(), 0.0);
+    assert_eq!(rising_edges(&run(&mut code, 10)), [0]);
+}
+
+#[test]
+fn swing_delays_every_other_sixteenth() {
+    let mut code = clock_output(0);
+    code.set_transport(TransportSettings {
+        swing: 0.625,
+        ..Default::default()
+    });
+    let edges = rising_edges(&run(&mut code, SAMPLES_PER_BEAT));
+    // The sixteenth halfway through the first eighth comes an eighth of an eighth late
+    assert_eq!(edges[0], 0);
+    assert!(!edges.contains(&6000));
+    assert!(edges.contains(&7500));
+    assert!(edges.contains(&12000));
+    assert_eq!(edges.len(), 24);
+}
+
+#[test]
+fn block_matches_sample_by_sample() {
+    let mut block = clock_output(5);
+    let mut single = clock_output(5);
+    let settings = TransportSettings {
+        tempo: 97.0,
+        swing: 0.55,
+        ..Default::default()
+    };
+    block.set_transport(settings);
+    single.set_transport(settings);
+
+    let expected: Vec<_> = (0..10000).map(|_| single.sample()[0]).collect();
+    assert_eq!(run(&mut block, 10000), expected);
+    assert_eq!(block.transport().position(), single.transport().position());
+}
+
+#[test]
+fn sequencer_steps_on_clock() {
+    let mut graph = Graph::new();
+    let clock = device(&mut graph, "Clock");
+    let sequencer = device(&mut graph, "Sequencer");
+    let output = device(&mut graph, "Output");
+    connect(&mut graph, (clock, 1), (sequencer, 10));
+    connect(&mut graph, (clock, 3), (sequencer, 11));
+    connect(&mut graph, (sequencer, 9), (output, 0));
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    for step in 0..8 {
+        code.update_param((sequencer, step), f32::from(step) + 1.0);
+    }
+
+    let played = run(&mut code, SAMPLES_PER_BEAT * 3);
+    assert_eq!(played[0], 1.0);
+    assert_eq!(played[SAMPLES_PER_BEAT], 2.0);
+    assert_eq!(played[SAMPLES_PER_BEAT * 2], 3.0);
+    // Steps are short blips, not held until the next one
+    assert_eq!(played[SAMPLES_PER_BEAT / 2], 0.0);
+
+    code.transport_command(TransportCommand::Reset);
+    assert_eq!(run(&mut code, 1)[0], 1.0);
+}
+
+#[test]
+fn sequencer_keeps_tempo_once_unplugged() {
+    let mut graph = Graph::new();
+    let clock = device(&mut graph, "Clock");
+    let sequencer = device(&mut graph, "Sequencer");
+    let output = device(&mut graph, "Output");
+    connect(&mut graph, (clock, 1), (sequencer, 10));
+    connect(&mut graph, (sequencer, 9), (output, 0));
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    for step in 0..8 {
+        code.update_param((sequencer, step), f32::from(step) + 1.0);
+    }
+    let played = run(&mut code, SAMPLES_PER_BEAT + 1);
+    assert_eq!(played[SAMPLES_PER_BEAT], 2.0);
+
+    let clock_input = graph.input_of(sequencer, 10).unwrap();
+    graph.cables.remove(clock_input);
+    let mut unplugged = compile(&graph.walk(), SAMPLE_RATE);
+    unplugged.carry_state_from(&code);
+    unplugged.update_param((sequencer, 8), 120.0);
+
+    let played = run(&mut unplugged, SAMPLES_PER_BEAT * 2 + 1);
+    assert_eq!(played[SAMPLES_PER_BEAT], 3.0);
+    assert_eq!(played[SAMPLES_PER_BEAT * 2], 4.0);
+}
+
+#[test]
+fn transport_is_saved_in_patches() {
+    let mut stack = Stack::new(STQueue::new());
+    let settings = TransportSettings {
+        tempo: 93.5,
+        beats_per_bar: 7,
+        swing: 0.62,
+    };
+    stack.set_transport(settings);
+
+    let yaml = serde_yaml::to_string(&stack.to_patch()).unwrap();
+    let mut loaded = Stack::new(STQueue::new());
+    loaded.load_patch(serde_yaml::from_str(&yaml).unwrap());
+    assert_eq!(loaded.transport(), settings);
+
+    let old: Patch = serde_yaml::from_str("modules: []\ncables: []\n").unwrap();
+    assert_eq!(old.transport, TransportSettings::default());
+}
+
+#[test]
+fn prefab_modules_fit_devices() {
+    let clock: ModuleDescription =
+        serde_yaml::from_str(include_str!("../../prefab_modules/clock.yml")).unwrap();
+    let params = DeviceKind::Clock.params();
+    assert_eq!(clock.devices[&0], DeviceKind::Clock);
+    assert_eq!(clock.connections.len(), params.len());
+    for (vi, (di, pi)) in &clock.connections {
+        assert_eq!(*di, 0);
+        let (Param::In(name) | Param::Out(name)) = params[*pi];
+        assert_eq!(clock.visuals[vi].name, name);
+    }
+
+    let sequencer: ModuleDescription =
+        serde_yaml::from_str(include_str!("../../prefab_modules/sequencer.yml")).unwrap();
+    for (vi, name) in [(10, "Clock"), (11, "Reset")] {
+        let (_, pi) = sequencer.connections[&vi];
+        assert_eq!(sequencer.devices[&0].params()[pi], Param::In(name));
+        assert_eq!(sequencer.visuals[&vi].name, name);
+    }
+}
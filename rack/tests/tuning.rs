@@ -0,0 +1,170 @@
+use rack::{
+    container::Stack,
+    midi::{
+        Polyphony,
+        VoiceAllocator,
+        NOTE,
+    },
+    patch::Patch,
+    tuning::{
+        KeyboardMap,
+        Scale,
+        Tuning,
+        TuningError,
+    },
+    STQueue,
+};
+use wmidi::{
+    Channel,
+    MidiMessage,
+    Note,
+    U7,
+};
+
+/// Just intonation major scale, with some of everything the format allows
+const JUST_MAJOR: &str = "! just.scl
+!
+Just major
+ 7
+!
+ 9/8
+ 5/4
+ 4/3 fourth
+ 3/2
+ 5/3
+ 15/8
+ 2
+";
+
+/// Plays the white keys only, starting the scale on C4 with A4 at 432Hz
+const WHITE_KEYS: &str = "! white.kbm
+12
+0
+127
+60
+69
+432.0
+7
+! mapping
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+fn close(a: f32, b: f32) -> bool {
+    (a / b - 1.0).abs() < 1e-5
+}
+
+#[test]
+fn default_is_equal_temperament() {
+    let tuning = Tuning::default();
+    for note in [Note::C4, Note::A4, Note::A5, Note::CMinus1, Note::G9] {
+        let freq = tuning.freq(note as u8).unwrap();
+        assert!(close(freq, note.to_freq_f32()), "{note:?} at {freq}");
+    }
+}
+
+#[test]
+fn scale_reads_cents_and_ratios() {
+    let scale = Scale::parse_scl(JUST_MAJOR).unwrap();
+    assert_eq!(scale.description, "Just major");
+    assert_eq!(scale.cents.len(), 7);
+    assert!((scale.cents[2] - 1200.0 * (4.0f64 / 3.0).log2()).abs() < 1e-9);
+    assert_eq!(scale.cents[6], 1200.0);
+
+    let cents = Scale::parse_scl("Quarter tones\n2\n50.0\n1200.0\n").unwrap();
+    assert_eq!(cents.cents, [50.0, 1200.0]);
+
+    assert_eq!(
+        Scale::parse_scl("Broken\n2\n3/2\n"),
+        Err(TuningError::MissingLine("pitch"))
+    );
+    assert!(Scale::parse_scl("Broken\n1\nfifth\n").is_err());
+}
+
+#[test]
+fn consecutive_keys_play_consecutive_degrees() {
+    let tuning = Tuning {
+        name: "just.scl".into(),
+        scale: Scale::parse_scl(JUST_MAJOR).unwrap(),
+        keyboard: KeyboardMap::default(),
+    };
+    // A4 is 9 keys above C4, which is the third degree of the next octave
+    let a4 = tuning.freq(69).unwrap();
+    assert!(close(a4, 440.0));
+    let c4 = tuning.freq(60).unwrap();
+    let c5 = tuning.freq(67).unwrap();
+    assert!(close(c5, c4 * 2.0));
+    assert!(close(tuning.freq(62).unwrap(), c4 * 5.0 / 4.0));
+}
+
+#[test]
+fn keyboard_map_skips_unmapped_keys() {
+    let tuning = Tuning {
+        name: "just.scl".into(),
+        scale: Scale::parse_scl(JUST_MAJOR).unwrap(),
+        keyboard: KeyboardMap::parse_kbm(WHITE_KEYS).unwrap(),
+    };
+    tuning.validate().unwrap();
+
+    assert!(close(tuning.freq(69).unwrap(), 432.0));
+    let c4 = tuning.freq(60).unwrap();
+    assert!(close(c4, 432.0 * 3.0 / 5.0));
+    assert!(close(tuning.freq(64).unwrap(), c4 * 5.0 / 4.0));
+    assert!(close(tuning.freq(72).unwrap(), c4 * 2.0));
+    assert!(close(tuning.freq(48).unwrap(), c4 / 2.0));
+    assert_eq!(tuning.freq(61), None);
+    assert_eq!(tuning.freq(73), None);
+
+    let mut unmapped = tuning.clone();
+    unmapped.keyboard.reference_note = 70;
+    assert_eq!(unmapped.validate(), Err(TuningError::UnmappedReference));
+}
+
+#[test]
+fn voices_play_tuned_frequencies() {
+    let tuning = Tuning {
+        name: "just.scl".into(),
+        scale: Scale::parse_scl(JUST_MAJOR).unwrap(),
+        keyboard: KeyboardMap::parse_kbm(WHITE_KEYS).unwrap(),
+    };
+    let mut allocator = VoiceAllocator::new(Polyphony::default()).with_tuning(&tuning);
+    let mut play = |note: Note| {
+        let mut freq = None;
+        let msg = MidiMessage::NoteOn(Channel::Ch1, note, U7::MAX);
+        allocator.handle(&msg, |_, param, value| {
+            if param == NOTE {
+                freq = Some(value);
+            }
+        });
+        freq
+    };
+    assert_eq!(play(Note::A4), Some(432.0));
+    assert_eq!(play(Note::ASharp4), None);
+}
+
+#[test]
+fn tuning_is_saved_in_patches() {
+    let mut stack = Stack::new(STQueue::new());
+    let mut tuning = Tuning::default();
+    tuning.keyboard.reference_freq = 415.0;
+    stack.set_tuning(tuning.clone());
+
+    let yaml = serde_yaml::to_string(&stack.to_patch()).unwrap();
+    let mut loaded = Stack::new(STQueue::new());
+    loaded.load_patch(serde_yaml::from_str(&yaml).unwrap());
+    assert_eq!(loaded.tuning(), &tuning);
+
+    // Patches from before tunings play in 12-TET
+    let old: Patch = serde_yaml::from_str("modules: []\ncables: []\n").unwrap();
+    assert_eq!(old.tuning, Tuning::default());
+}
@@ -0,0 +1,316 @@
+use rack::{
+    devices::description::DeviceKind,
+    graph::{
+        compiled::{
+            compile,
+            CHANNELS,
+        },
+        DeviceId,
+        Graph,
+    },
+    midi::{
+        MidiFilter,
+        Polyphony,
+        VoiceAllocator,
+        AFTERTOUCH,
+        BEND,
+        CHANNEL,
+        MOD_WHEEL,
+        NOTE,
+        PORT,
+        PRESSURE,
+        TRIGGER,
+        VELOCITY,
+    },
+};
+use wmidi::{
+    Channel,
+    ControlFunction,
+    MidiMessage,
+    Note,
+    U14,
+    U7,
+};
+
+const SAMPLE_RATE: f32 = 48000.0;
+
+fn device(graph: &mut Graph, name: &str) -> DeviceId {
+    let kind = DeviceKind::all()
+        .into_iter()
+        .find(|k| k.name() == name)
+        .unwrap();
+    graph.insert_device(kind)
+}
+
+fn connect(graph: &mut Graph, (from, out): (DeviceId, u8), (to, inp): (DeviceId, u8)) {
+    let out = graph.output_of(from, out).unwrap();
+    let inp = graph.input_of(to, inp).unwrap();
+    graph.cables.insert(inp, out);
+}
+
+fn on(note: Note) -> MidiMessage<'static> {
+    MidiMessage::NoteOn(Channel::Ch1, note, U7::MAX)
+}
+
+fn off(note: Note) -> MidiMessage<'static> {
+    MidiMessage::NoteOff(Channel::Ch1, note, U7::MIN)
+}
+
+fn polyphony(voices: usize, unison: usize) -> Polyphony {
+    Polyphony {
+        voices,
+        unison,
+        ..Default::default()
+    }
+}
+
+/// Parameters changed by `msg`, as voice, parameter and value
+fn play(allocator: &mut VoiceAllocator, msg: MidiMessage) -> Vec<(usize, u8, f32)> {
+    let mut changed = Vec::new();
+    allocator.handle(&msg, |voice, param, value| {
+        changed.push((voice, param, value))
+    });
+    changed
+}
+
+/// Voices whose trigger changed, along with its new value
+fn triggers(changed: &[(usize, u8, f32)]) -> Vec<(usize, f32)> {
+    changed
+        .iter()
+        .filter(|(_, param, _)| *param == TRIGGER)
+        .map(|(voice, _, value)| (*voice, *value))
+        .collect()
+}
+
+#[test]
+fn notes_get_their_own_voices() {
+    let mut voices = VoiceAllocator::new(polyphony(2, 1));
+    assert_eq!(
+        play(&mut voices, on(Note::A4)),
+        [(0, NOTE, 440.0), (0, VELOCITY, 1.0), (0, TRIGGER, 1.0)]
+    );
+    assert_eq!(triggers(&play(&mut voices, on(Note::A5))), [(1, 1.0)]);
+}
+
+#[test]
+fn note_off_releases_voice() {
+    let mut voices = VoiceAllocator::new(polyphony(2, 1));
+    play(&mut voices, on(Note::A4));
+    assert_eq!(play(&mut voices, off(Note::A4)), [(0, TRIGGER, 0.0)]);
+    // Note on with no velocity is a note off
+    play(&mut voices, on(Note::A5));
+    let silent = MidiMessage::NoteOn(Channel::Ch1, Note::A5, U7::MIN);
+    assert_eq!(play(&mut voices, silent), [(1, TRIGGER, 0.0)]);
+}
+
+#[test]
+fn oldest_note_is_stolen() {
+    let mut voices = VoiceAllocator::new(polyphony(2, 1));
+    play(&mut voices, on(Note::C4));
+    play(&mut voices, on(Note::E4));
+    assert_eq!(triggers(&play(&mut voices, on(Note::G4))), [(0, 1.0)]);
+}
+
+#[test]
+fn released_voices_are_reused_before_stealing() {
+    let mut voices = VoiceAllocator::new(polyphony(3, 1));
+    play(&mut voices, on(Note::C4));
+    play(&mut voices, on(Note::E4));
+    play(&mut voices, on(Note::G4));
+    play(&mut voices, off(Note::E4));
+    play(&mut voices, off(Note::C4));
+
+    // E4's release tail has been ringing the longest
+    assert_eq!(triggers(&play(&mut voices, on(Note::B4))), [(1, 1.0)]);
+    assert_eq!(triggers(&play(&mut voices, on(Note::D5))), [(0, 1.0)]);
+}
+
+#[test]
+fn unison_spreads_voices() {
+    let mut voices = VoiceAllocator::new(Polyphony {
+        voices: 4,
+        unison: 2,
+        detune: 10.0,
+    });
+    let freqs: Vec<_> = play(&mut voices, on(Note::A4))
+        .into_iter()
+        .filter_map(|(_, param, value)| (param == NOTE).then_some(value))
+        .collect();
+    assert_eq!(freqs.len(), 2);
+    assert!((freqs[0] - 440.0 * (-5.0f32 / 1200.0).exp2()).abs() < 1e-3);
+    assert!((freqs[1] - 440.0 * (5.0f32 / 1200.0).exp2()).abs() < 1e-3);
+
+    assert_eq!(triggers(&play(&mut voices, off(Note::A4))).len(), 2);
+}
+
+#[test]
+fn expression_reaches_voices() {
+    let mut voices = VoiceAllocator::new(polyphony(2, 1));
+    let soft = MidiMessage::NoteOn(Channel::Ch1, Note::C4, U7::from_u8_lossy(127 / 2 + 1));
+    assert!(play(&mut voices, soft).contains(&(0, VELOCITY, 64.0 / 127.0)));
+    play(&mut voices, on(Note::E4));
+
+    // Channel wide
+    let bend = MidiMessage::PitchBendChange(Channel::Ch1, U14::MAX);
+    assert_eq!(play(&mut voices, bend), [(0, BEND, 1.0), (1, BEND, 1.0)]);
+    let bend = MidiMessage::PitchBendChange(Channel::Ch1, U14::MIN);
+    assert_eq!(play(&mut voices, bend), [(0, BEND, -1.0), (1, BEND, -1.0)]);
+    let pressure = MidiMessage::ChannelPressure(Channel::Ch1, U7::MAX);
+    assert_eq!(
+        play(&mut voices, pressure),
+        [(0, PRESSURE, 1.0), (1, PRESSURE, 1.0)]
+    );
+    let wheel =
+        MidiMessage::ControlChange(Channel::Ch1, ControlFunction::MODULATION_WHEEL, U7::MAX);
+    assert_eq!(
+        play(&mut voices, wheel),
+        [(0, MOD_WHEEL, 1.0), (1, MOD_WHEEL, 1.0)]
+    );
+
+    // Only the voice playing the note
+    let aftertouch = MidiMessage::PolyphonicKeyPressure(Channel::Ch1, Note::E4, U7::MAX);
+    assert_eq!(play(&mut voices, aftertouch), [(1, AFTERTOUCH, 1.0)]);
+}
+
+#[test]
+fn pitch_bend_moves_note_by_range() {
+    let mut graph = Graph::new();
+    let midi = device(&mut graph, "MidiControl");
+    let out = device(&mut graph, "StereoOutput");
+    connect(&mut graph, (midi, NOTE), (out, 0));
+    connect(&mut graph, (midi, BEND), (out, 1));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((midi, 7), 12.0);
+    code.handle_midi(0, &on(Note::A4));
+    code.handle_midi(0, &MidiMessage::PitchBendChange(Channel::Ch1, U14::MAX));
+    assert_eq!(code.sample(), [880.0, 12.0]);
+}
+
+#[test]
+fn voices_are_summed_before_output() {
+    let mut graph = Graph::new();
+    let midi = device(&mut graph, "MidiControl");
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, (midi, 0), (out, 0));
+
+    let mut ctl_graph = graph.walk();
+    ctl_graph.polyphony = polyphony(4, 1);
+    let mut code = compile(&ctl_graph, SAMPLE_RATE);
+    code.handle_midi(0, &on(Note::A4));
+    code.handle_midi(0, &on(Note::A5));
+    assert_eq!(code.sample(), [1320.0; CHANNELS]);
+
+    // Released voices keep their pitch for the release tail
+    code.handle_midi(0, &off(Note::A4));
+    code.handle_midi(0, &on(Note::A3));
+    assert!((code.sample()[0] - 1540.0).abs() < 1e-3);
+}
+
+#[test]
+fn shared_controls_reach_every_voice() {
+    let mut graph = Graph::new();
+    let midi = device(&mut graph, "MidiControl");
+    let control = device(&mut graph, "Control");
+    let att = device(&mut graph, "Attenuator");
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, (midi, 1), (att, 0));
+    connect(&mut graph, (control, 1), (att, 1));
+    connect(&mut graph, (att, 2), (out, 0));
+
+    let mut ctl_graph = graph.walk();
+    ctl_graph.polyphony = polyphony(3, 1);
+    let mut code = compile(&ctl_graph, SAMPLE_RATE);
+    code.update_param((control, 0), 0.25);
+    for note in [Note::C4, Note::E4, Note::G4] {
+        code.handle_midi(0, &on(note));
+    }
+    assert_eq!(code.sample()[0], 0.75);
+}
+
+#[test]
+fn polyphonic_block_matches_per_sample() {
+    let mut graph = Graph::new();
+    let midi = device(&mut graph, "MidiControl");
+    let osc = device(&mut graph, "SawOsc");
+    let adsr = device(&mut graph, "ADSR");
+    let vca = device(&mut graph, "Attenuator");
+    let out = device(&mut graph, "Output");
+    connect(&mut graph, (midi, 0), (osc, 0));
+    connect(&mut graph, (midi, 1), (adsr, 6));
+    connect(&mut graph, (osc, 2), (vca, 0));
+    connect(&mut graph, (adsr, 7), (vca, 1));
+    connect(&mut graph, (vca, 2), (out, 0));
+
+    let mut ctl_graph = graph.walk();
+    ctl_graph.polyphony = polyphony(4, 2);
+    let mut per_sample = compile(&ctl_graph, SAMPLE_RATE);
+    let mut block = compile(&ctl_graph, SAMPLE_RATE);
+    for code in [&mut per_sample, &mut block] {
+        code.handle_midi(0, &on(Note::C4));
+        code.handle_midi(0, &on(Note::G4));
+    }
+
+    let expected: Vec<_> = (0..300).map(|_| per_sample.sample()).collect();
+    let mut got = vec![[0.0; CHANNELS]; 300];
+    block.process_block(&mut got);
+    for (e, g) in expected.iter().zip(&got) {
+        for (e, g) in e.iter().zip(g) {
+            assert!((e - g).abs() < 1e-5, "{e} != {g}");
+        }
+    }
+    assert!(expected.iter().any(|f| f[0] != 0.0));
+}
+
+#[test]
+fn filter_picks_channel_and_port() {
+    let mut filter = MidiFilter::default();
+    let on_ch2 = MidiMessage::NoteOn(Channel::Ch2, Note::A4, U7::MAX);
+    assert!(filter.accepts(0, &on(Note::A4)) && filter.accepts(3, &on_ch2));
+
+    filter.set_param(CHANNEL, 2.0);
+    assert!(!filter.accepts(0, &on(Note::A4)));
+    assert!(filter.accepts(0, &on_ch2));
+    // Not tied to a channel, so everyone hears it
+    assert!(filter.accepts(0, &MidiMessage::TimingClock));
+
+    filter.set_param(PORT, 2.0);
+    assert!(!filter.accepts(0, &on_ch2));
+    assert!(filter.accepts(1, &on_ch2));
+
+    filter.set_param(CHANNEL, 0.0);
+    filter.set_param(PORT, 0.0);
+    assert_eq!(filter, MidiFilter::default());
+}
+
+#[test]
+fn split_keyboard_drives_separate_voices() {
+    let mut graph = Graph::new();
+    let lower = device(&mut graph, "MidiControl");
+    let upper = device(&mut graph, "MidiControl");
+    let out = device(&mut graph, "StereoOutput");
+    connect(&mut graph, (lower, NOTE), (out, 0));
+    connect(&mut graph, (upper, NOTE), (out, 1));
+
+    let mut code = compile(&graph.walk(), SAMPLE_RATE);
+    code.update_param((lower, CHANNEL), 1.0);
+    code.update_param((upper, CHANNEL), 2.0);
+    code.handle_midi(0, &on(Note::A4));
+    code.handle_midi(0, &MidiMessage::NoteOn(Channel::Ch2, Note::A5, U7::MAX));
+    assert_eq!(code.sample(), [440.0, 880.0]);
+
+    // A second controller on its own port
+    code.update_param((lower, PORT), 1.0);
+    code.update_param((upper, CHANNEL), 0.0);
+    code.update_param((upper, PORT), 2.0);
+    code.handle_midi(0, &on(Note::A5));
+    assert_eq!(code.sample(), [880.0, 880.0]);
+    code.handle_midi(1, &on(Note::A4));
+    assert_eq!(code.sample(), [880.0, 440.0]);
+
+    // Filters survive recompiling
+    let mut next = compile(&graph.walk(), SAMPLE_RATE);
+    next.carry_state_from(&code);
+    next.handle_midi(0, &on(Note::A5));
+    assert_eq!(next.sample()[1], 440.0);
+}